@@ -0,0 +1,4 @@
+// The C interface of tcp-client as a shared library, for projects linking
+// against include/tcp_client.h. The functions live in tcp_client::ffi; the
+// re-export keeps them in the library's exported symbols.
+pub use tcp_client::ffi::*;
@@ -0,0 +1,35 @@
+#ifndef TCP_CLIENT_H
+#define TCP_CLIENT_H
+
+/* C interface to the tcp-client crate. The shared library is built by
+ * `cargo build -p tcp-client-ffi`; Rust builds linking the crate directly
+ * enable `--features ffi` instead. Keep in sync with src/ffi.rs. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct TcpClient TcpClient;
+
+/* Called from the receive thread for every complete frame. */
+typedef void (*TcpClientMessageCallback)(const uint8_t *data, size_t length, void *user_data);
+
+/* Returns NULL if the connection could not be established. */
+TcpClient *tcpclient_connect(const char *address);
+
+/* The functions below return 0 on success and -1 on failure. */
+int tcpclient_send(const TcpClient *client, const uint8_t *data, size_t length);
+int tcpclient_set_callback(TcpClient *client, TcpClientMessageCallback callback, void *user_data);
+int tcpclient_receive(const TcpClient *client);
+int tcpclient_disconnect(const TcpClient *client);
+
+void tcpclient_free(TcpClient *client);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TCP_CLIENT_H */
@@ -0,0 +1,346 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::queue::Lane;
+use crate::{Clock, Error, SendTicket, SystemClock, TcpClient, TcpClientData};
+
+// Every frame starts with a kind byte. Auth frames carry the token as their
+// payload. The server sends an auth-expired frame, with no payload, when a
+// token needs replacing; the connection stays open meanwhile.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const AUTH: u8 = 1;
+pub(crate) const AUTH_EXPIRED: u8 = 2;
+
+type OnMessageReceivedCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+pub trait AuthProvider: Send + Sync {
+    fn token(&self) -> Result<Vec<u8>, String>;
+
+    // Called when the server reports the current token as expired.
+    fn refresh(&self) -> Result<Vec<u8>, String> {
+        self.token()
+    }
+
+    // AuthClient passes in the client's clock, for providers that track
+    // when a token expires.
+    fn use_clock(&self, _clock: Arc<dyn Clock>) {}
+}
+
+pub struct StaticToken {
+    token: Vec<u8>,
+}
+
+pub struct CallbackProvider<F> {
+    callback: F,
+}
+
+// Caches a token until it expires, like an OAuth access token. The fetch
+// function returns the token and how long it stays valid.
+pub struct RefreshingProvider<F> {
+    fetch: F,
+    cached: Mutex<Option<(Vec<u8>, Instant)>>,
+    clock: Mutex<Arc<dyn Clock>>,
+}
+
+pub struct AuthClient {
+    client: TcpClient,
+    provider: Arc<dyn AuthProvider>,
+    on_message_received: Arc<Mutex<OnMessageReceivedCallback>>,
+}
+
+impl StaticToken {
+    pub fn new<T>(token: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl AuthProvider for StaticToken {
+    fn token(&self) -> Result<Vec<u8>, String> {
+        Ok(self.token.clone())
+    }
+}
+
+impl<F> CallbackProvider<F>
+where
+    F: Fn() -> Result<Vec<u8>, String> + Send + Sync,
+{
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> AuthProvider for CallbackProvider<F>
+where
+    F: Fn() -> Result<Vec<u8>, String> + Send + Sync,
+{
+    fn token(&self) -> Result<Vec<u8>, String> {
+        (self.callback)()
+    }
+}
+
+impl<F> RefreshingProvider<F>
+where
+    F: Fn() -> Result<(Vec<u8>, Duration), String> + Send + Sync,
+{
+    pub fn new(fetch: F) -> Self {
+        Self {
+            fetch,
+            cached: Mutex::new(None),
+            clock: Mutex::new(Arc::new(SystemClock)),
+        }
+    }
+
+    fn now(&self) -> Instant {
+        match self.clock.lock() {
+            Ok(clock) => clock.now(),
+            Err(e) => e.into_inner().now(),
+        }
+    }
+
+    fn fetch(&self) -> Result<Vec<u8>, String> {
+        let (token, valid_for) = (self.fetch)()?;
+        let expires = self.now() + valid_for;
+
+        match self.cached.lock() {
+            Ok(mut cached) => *cached = Some((token.clone(), expires)),
+            Err(e) => *e.into_inner() = Some((token.clone(), expires)),
+        }
+
+        Ok(token)
+    }
+}
+
+impl<F> AuthProvider for RefreshingProvider<F>
+where
+    F: Fn() -> Result<(Vec<u8>, Duration), String> + Send + Sync,
+{
+    fn token(&self) -> Result<Vec<u8>, String> {
+        let cached = match self.cached.lock() {
+            Ok(cached) => cached.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+        match cached {
+            Some((token, expires)) if self.now() < expires => Ok(token),
+            _ => self.fetch(),
+        }
+    }
+
+    fn refresh(&self) -> Result<Vec<u8>, String> {
+        self.fetch()
+    }
+
+    fn use_clock(&self, clock: Arc<dyn Clock>) {
+        match self.clock.lock() {
+            Ok(mut current) => *current = clock,
+            Err(e) => *e.into_inner() = clock,
+        }
+    }
+}
+
+impl AuthClient {
+    // Sends the provider's token before returning, and again after every
+    // reconnect.
+    pub fn new<P>(mut client: TcpClient, provider: P) -> Result<Self, Error>
+    where
+        P: AuthProvider + 'static,
+    {
+        let provider: Arc<dyn AuthProvider> = Arc::new(provider);
+
+        if let Some(data) = client.downgrade().upgrade() {
+            provider.use_clock(data.clock());
+        }
+
+        let on_message_received: Arc<Mutex<OnMessageReceivedCallback>> =
+            Arc::new(Mutex::new(Arc::new(|_| {})));
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let provider_ref = provider.clone();
+        let on_message_received_ref = on_message_received.clone();
+
+        client.set_on_message_received(move |frame| {
+            let data = match data.upgrade() {
+                Some(data) => data,
+                None => return,
+            };
+
+            match frame.first() {
+                Some(&DATA) => {
+                    let callback = match on_message_received_ref.lock() {
+                        Ok(callback) => callback.clone(),
+                        Err(e) => e.into_inner().clone(),
+                    };
+
+                    callback(&frame[1..]);
+                }
+                Some(&AUTH_EXPIRED) => {
+                    if let Err(e) = authenticate(&data, provider_ref.refresh()) {
+                        data.report_error(&e);
+                    }
+                }
+                Some(kind) => {
+                    data.report_error(&Error::Protocol(format!("Unknown auth frame kind {kind}")))
+                }
+                None => data.report_error(&Error::Protocol("Empty auth frame".to_string())),
+            }
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let provider_ref = provider.clone();
+
+        // A failed send is left to the next connection, which sends the
+        // token again.
+        client.set_on_reconnected(move || {
+            if let Some(data) = data.upgrade() {
+                let sent = auth_frame(provider_ref.token())
+                    .and_then(|frame| data.send_on_reconnect(&frame, Lane::Control));
+
+                if let Err(e) = sent {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        match client.downgrade().upgrade() {
+            Some(data) => authenticate(&data, provider.token())?,
+            None => return Err(Error::Closed),
+        }
+
+        Ok(Self {
+            client,
+            provider,
+            on_message_received,
+        })
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // Sends a fresh token without waiting for the server to ask.
+    pub fn reauthenticate(&self) -> Result<(), Error> {
+        match self.client.downgrade().upgrade() {
+            Some(data) => authenticate(&data, self.provider.refresh()),
+            None => Err(Error::Closed),
+        }
+    }
+
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let payload = payload.as_ref();
+
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(DATA);
+        frame.extend_from_slice(payload);
+
+        self.client.send(frame)
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        match self.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+fn authenticate(data: &TcpClientData, token: Result<Vec<u8>, String>) -> Result<(), Error> {
+    data.send(&auth_frame(token)?, Lane::Control).map(|_| ())
+}
+
+fn auth_frame(token: Result<Vec<u8>, String>) -> Result<Vec<u8>, Error> {
+    let token = token.map_err(Error::Auth)?;
+
+    let mut frame = Vec::with_capacity(1 + token.len());
+    frame.push(AUTH);
+    frame.extend_from_slice(&token);
+
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::testing::{client_over, instant_reconnects, read_frame, within};
+    use crate::{ErrorAction, ErrorPolicy, MemoryTransport, MockClock, Transport};
+
+    fn counting_provider(
+        fetches: Arc<AtomicU32>,
+    ) -> RefreshingProvider<impl Fn() -> Result<(Vec<u8>, Duration), String> + Send + Sync> {
+        RefreshingProvider::new(move || {
+            let count = fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok((
+                format!("token-{count}").into_bytes(),
+                Duration::from_secs(60),
+            ))
+        })
+    }
+
+    #[test]
+    fn refreshing_provider_expires_tokens_by_its_clock() {
+        let fetches = Arc::new(AtomicU32::new(0));
+        let provider = counting_provider(fetches.clone());
+        let clock = MockClock::new();
+        provider.use_clock(Arc::new(clock.clone()));
+
+        assert_eq!(provider.token().unwrap(), b"token-1");
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(provider.token().unwrap(), b"token-1");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(provider.token().unwrap(), b"token-2");
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reconnect_sends_a_token_fetched_by_the_client_clock() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let (third, third_server) = MemoryTransport::pair();
+
+        // The token send fails on the second connection and is redone on
+        // the third.
+        second_server.shutdown(Shutdown::Both).unwrap();
+
+        let clock = MockClock::new();
+        let mut client = client_over(vec![first, second, third]);
+        client.set_clock(clock.clone());
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let fetches = Arc::new(AtomicU32::new(0));
+        let auth = AuthClient::new(client, counting_provider(fetches.clone())).unwrap();
+        assert_eq!(read_frame(&first_server).unwrap(), b"\x01token-1");
+
+        // Only expired by the client's clock, not by the system's.
+        clock.advance(Duration::from_secs(61));
+        first_server.shutdown(Shutdown::Both).unwrap();
+
+        // The send is retried once on the second connection, which is dead
+        // too; the next one reconnects again.
+        let auth = within(move || {
+            assert!(auth.send("lost").is_err());
+            auth.send("hello").unwrap();
+            auth
+        });
+
+        assert_eq!(read_frame(&third_server).unwrap(), b"\x01token-2");
+        assert_eq!(read_frame(&third_server).unwrap(), b"\x00hello");
+        drop(auth);
+    }
+}
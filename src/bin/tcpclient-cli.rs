@@ -0,0 +1,193 @@
+// An interactive client for servers that speak this crate's framing. Every
+// line typed is sent as a message, except for these commands:
+//
+//   /hex 48656c6c6f   sends the bytes given in hex
+//   /file path        sends the contents of a file as one message
+//   /quit             disconnects and exits
+//
+// Incoming messages are printed as they arrive, as text or in hex.
+
+use std::io::{self, BufRead};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use tcp_client::{Framing, TcpClient};
+
+const USAGE: &str = "\
+Usage: tcpclient-cli [options] <address>
+
+Options:
+  --framing <length|lines|mqtt>  How messages are framed (default: length)
+  --delimiter <text>             Line delimiter for --framing lines (default: \\n)
+  --hex                          Print incoming messages in hex
+  --timeout <seconds>            Give up connecting after this long
+  --help                         Show this help";
+
+struct Options {
+    address: String,
+    framing: Framing,
+    hex: bool,
+    timeout: Option<Duration>,
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut address = None;
+    let mut framing = "length".to_string();
+    let mut delimiter = "\n".to_string();
+    let mut hex = false;
+    let mut timeout = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+
+        match arg.as_str() {
+            "--framing" => framing = value("--framing")?,
+            "--delimiter" => delimiter = value("--delimiter")?,
+            "--hex" => hex = true,
+            "--timeout" => {
+                let seconds = value("--timeout")?;
+                let seconds: f64 = seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid timeout \"{seconds}\""))?;
+
+                timeout = Some(
+                    Duration::try_from_secs_f64(seconds)
+                        .map_err(|_| format!("Invalid timeout \"{seconds}\""))?,
+                );
+            }
+            "--help" => return Err(USAGE.to_string()),
+            option if option.starts_with("--") => {
+                return Err(format!("Unknown option {option}\n\n{USAGE}"));
+            }
+            _ if address.is_none() => address = Some(arg),
+            _ => return Err(format!("Unexpected argument \"{arg}\"\n\n{USAGE}")),
+        }
+    }
+
+    let framing = match framing.as_str() {
+        "length" => Framing::LengthPrefixed,
+        "lines" => Framing::Delimited {
+            delimiter: delimiter.into_bytes(),
+            max_length: 1024 * 1024,
+        },
+        "mqtt" => Framing::Mqtt,
+        other => return Err(format!("Unknown framing \"{other}\"")),
+    };
+
+    Ok(Options {
+        address: address.ok_or_else(|| USAGE.to_string())?,
+        framing,
+        hex,
+        timeout,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex needs an even number of digits".to_string());
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("Invalid hex \"{pair}\""))
+        })
+        .collect()
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let connected = match options.timeout {
+        Some(timeout) => TcpClient::connect_timeout(&options.address, timeout),
+        None => TcpClient::connect(&options.address),
+    };
+
+    let mut client = match connected {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let hex = options.hex;
+
+    client.set_framing(options.framing);
+    client.set_on_message_received(move |message| {
+        if hex {
+            println!("< {}", to_hex(message));
+        } else {
+            println!("< {}", String::from_utf8_lossy(message));
+        }
+    });
+    client.set_on_error(|e| eprintln!("! {e}"));
+    client.set_on_peer_closed_write(|| eprintln!("! Server closed the connection"));
+
+    if let Err(e) = client.receive() {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+
+    eprintln!("Connected to {}", options.address);
+
+    // Input piped from a file may end before the queue has been written.
+    let mut last_sent = None;
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{e}");
+                break;
+            }
+        };
+
+        let message = if line == "/quit" {
+            break;
+        } else if let Some(digits) = line.strip_prefix("/hex ") {
+            from_hex(digits)
+        } else if let Some(path) = line.strip_prefix("/file ") {
+            std::fs::read(path.trim()).map_err(|e| format!("{path}: {e}"))
+        } else {
+            Ok(line.into_bytes())
+        };
+
+        let sent = match message {
+            Ok(message) => match client.send(message) {
+                Ok(ticket) => {
+                    last_sent = Some(ticket);
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            Err(message) => Err(message),
+        };
+
+        if let Err(message) = sent {
+            eprintln!("! {message}");
+        }
+    }
+
+    if let Some(ticket) = last_sent {
+        if let Err(e) = ticket.wait() {
+            eprintln!("! {e}");
+        }
+    }
+
+    client.disconnect();
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,250 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+// Binds the client's socket to a fixed local address before connecting,
+// e.g. to the port of a listener for TCP simultaneous open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalBind {
+    pub address: SocketAddr,
+    pub reuse_address: bool,
+    // Needed to share the port with a listener that is still open.
+    pub reuse_port: bool,
+}
+
+impl LocalBind {
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            reuse_address: true,
+            reuse_port: true,
+        }
+    }
+
+    pub(crate) fn connect(
+        &self,
+        remote: &SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        if self.address.is_ipv4() != remote.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Local and remote addresses are of different families",
+            ));
+        }
+
+        platform::connect_bound(self, remote, timeout)
+    }
+}
+
+// The socket constants, flag values and timeval layout below are those of
+// the generic Linux ABI on 64-bit x86 and Arm; architectures like MIPS,
+// SPARC or 32-bit Arm number them differently and use the unsupported
+// fallback.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod platform {
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use super::LocalBind;
+
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+    const SOCK_STREAM: i32 = 1;
+    const SOCK_CLOEXEC: i32 = 0o2000000;
+    const SOL_SOCKET: i32 = 1;
+    const SO_REUSEADDR: i32 = 2;
+    const SO_REUSEPORT: i32 = 15;
+    const SO_SNDTIMEO: i32 = 21;
+    const EINPROGRESS: i32 = 115;
+    // Large enough for sockaddr_in6.
+    const SOCKADDR_SIZE: usize = 28;
+
+    extern "C" {
+        fn socket(domain: i32, kind: i32, protocol: i32) -> i32;
+        fn setsockopt(fd: i32, level: i32, name: i32, value: *const u8, length: u32) -> i32;
+        fn bind(fd: i32, address: *const u8, length: u32) -> i32;
+        fn connect(fd: i32, address: *const u8, length: u32) -> i32;
+    }
+
+    pub(super) fn connect_bound(
+        local: &LocalBind,
+        remote: &SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let family = match remote {
+            SocketAddr::V4(_) => AF_INET,
+            SocketAddr::V6(_) => AF_INET6,
+        };
+
+        let fd = unsafe { socket(family as i32, SOCK_STREAM | SOCK_CLOEXEC, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Owned from here on so that every error path closes it.
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        if local.reuse_address {
+            set_option(&socket, SO_REUSEADDR, &1i32.to_ne_bytes())?;
+        }
+
+        if local.reuse_port {
+            set_option(&socket, SO_REUSEPORT, &1i32.to_ne_bytes())?;
+        }
+
+        // Linux bounds a blocking connect by the send timeout.
+        if let Some(timeout) = timeout {
+            set_option(&socket, SO_SNDTIMEO, &timeval(timeout))?;
+        }
+
+        let (address, length) = sockaddr(&local.address);
+
+        if unsafe { bind(fd, address.as_ptr(), length) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (address, length) = sockaddr(remote);
+
+        if unsafe { connect(fd, address.as_ptr(), length) } != 0 {
+            let error = io::Error::last_os_error();
+
+            // A connect cut short by the send timeout fails with EINPROGRESS.
+            return Err(match error.raw_os_error() {
+                Some(EINPROGRESS) => io::ErrorKind::TimedOut.into(),
+                _ => error,
+            });
+        }
+
+        let stream = TcpStream::from(socket);
+
+        if timeout.is_some() {
+            stream.set_write_timeout(None)?;
+        }
+
+        Ok(stream)
+    }
+
+    fn set_option(socket: &OwnedFd, name: i32, value: &[u8]) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+
+        match unsafe { setsockopt(fd, SOL_SOCKET, name, value.as_ptr(), value.len() as u32) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn timeval(timeout: Duration) -> [u8; 16] {
+        let mut value = [0u8; 16];
+        value[..8].copy_from_slice(&(timeout.as_secs() as i64).to_ne_bytes());
+        value[8..].copy_from_slice(&(timeout.subsec_micros() as i64).to_ne_bytes());
+        value
+    }
+
+    // Family is in host order; port, flow info and address in network order.
+    fn sockaddr(address: &SocketAddr) -> ([u8; SOCKADDR_SIZE], u32) {
+        let mut bytes = [0u8; SOCKADDR_SIZE];
+        bytes[2..4].copy_from_slice(&address.port().to_be_bytes());
+
+        match address {
+            SocketAddr::V4(address) => {
+                bytes[..2].copy_from_slice(&AF_INET.to_ne_bytes());
+                bytes[4..8].copy_from_slice(&address.ip().octets());
+                (bytes, 16)
+            }
+            SocketAddr::V6(address) => {
+                bytes[..2].copy_from_slice(&AF_INET6.to_ne_bytes());
+                bytes[4..8].copy_from_slice(&address.flowinfo().to_be_bytes());
+                bytes[8..24].copy_from_slice(&address.ip().octets());
+                bytes[24..28].copy_from_slice(&address.scope_id().to_ne_bytes());
+                (bytes, SOCKADDR_SIZE as u32)
+            }
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+mod platform {
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    use super::LocalBind;
+
+    pub(super) fn connect_bound(
+        _local: &LocalBind,
+        _remote: &SocketAddr,
+        _timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn mixed_address_families_are_rejected() {
+        let local = LocalBind::new("127.0.0.1:0".parse().unwrap());
+        let error = local
+            .connect(&"[::1]:80".parse().unwrap(), None)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn connects_from_the_bound_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+
+        // Reserves a free port, then frees it for the bound connect.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let local = LocalBind::new(port);
+
+        let stream = local
+            .connect(&remote, Some(Duration::from_secs(5)))
+            .unwrap();
+        let (accepted, peer) = listener.accept().unwrap();
+
+        assert_eq!(stream.local_addr().unwrap(), port);
+        assert_eq!(peer, port);
+        assert_eq!(stream.write_timeout().unwrap(), None);
+        drop(accepted);
+    }
+
+    #[cfg(not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )))]
+    #[test]
+    fn bound_connects_are_unsupported_elsewhere() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = LocalBind::new("127.0.0.1:0".parse().unwrap());
+        let error = local
+            .connect(&listener.local_addr().unwrap(), None)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
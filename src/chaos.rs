@@ -0,0 +1,157 @@
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::Transport;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChaosConfig {
+    // Added before every write, plus a random extra of up to jitter.
+    pub latency: Duration,
+    pub jitter: Duration,
+    // Writes accept a random number of bytes up to this limit, so frames
+    // arrive split across reads. Zero writes everything at once.
+    pub max_write_size: usize,
+    // Each read or write disconnects the transport with a one in this many
+    // chance. Zero never disconnects.
+    pub disconnect_one_in: u32,
+    // The same seed replays the same sequence of faults.
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            max_write_size: 0,
+            disconnect_one_in: 0,
+            seed: 1,
+        }
+    }
+}
+
+// Wraps a transport and injects faults between the client and the real
+// stream, for exercising reconnect and framing under adverse conditions.
+// Once disconnected, every read and write fails with ConnectionReset.
+pub struct ChaosTransport<T: Transport> {
+    inner: T,
+    config: ChaosConfig,
+    rng: Mutex<u64>,
+    disconnected: AtomicBool,
+}
+
+impl<T: Transport> ChaosTransport<T> {
+    pub fn new(inner: T, config: ChaosConfig) -> Self {
+        Self {
+            inner,
+            config,
+            // Xorshift never leaves zero.
+            rng: Mutex::new(config.seed.max(1)),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+
+    fn next_random(&self) -> u64 {
+        let mut state = match self.rng.lock() {
+            Ok(state) => state,
+            Err(e) => e.into_inner(),
+        };
+
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn check_disconnect(&self) -> io::Result<()> {
+        if !self.is_disconnected()
+            && self.config.disconnect_one_in > 0
+            && self
+                .next_random()
+                .is_multiple_of(self.config.disconnect_one_in as u64)
+        {
+            self.disconnected.store(true, Ordering::SeqCst);
+            let _ = self.inner.shutdown(Shutdown::Both);
+        }
+
+        if self.is_disconnected() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Disconnected by ChaosTransport",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = self.config.jitter.as_nanos() as u64;
+
+        match jitter {
+            0 => self.config.latency,
+            _ => self.config.latency + Duration::from_nanos(self.next_random() % (jitter + 1)),
+        }
+    }
+}
+
+impl<T: Transport> Transport for ChaosTransport<T> {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.check_disconnect()?;
+        self.inner.read(buffer)
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        self.check_disconnect()?;
+
+        let delay = self.delay();
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+
+        let size = match self.config.max_write_size {
+            max if max > 0 && !data.is_empty() => {
+                (self.next_random() % max.min(data.len()) as u64) as usize + 1
+            }
+            _ => data.len(),
+        };
+
+        self.inner.write(&data[..size])
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read_retry_delay(&self) -> Option<Duration> {
+        self.inner.read_retry_delay()
+    }
+
+    fn write_retry_delay(&self) -> Option<Duration> {
+        self.inner.write_retry_delay()
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        self.inner.as_tcp()
+    }
+}
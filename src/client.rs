@@ -1,190 +1,3787 @@
 use std::net::Shutdown;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, ThreadId};
 use std::{
-    io::{self, Read, Write},
-    net::TcpStream,
-    sync::{Arc, Mutex},
-    time::Duration,
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex, TryLockError, Weak},
+    time::{Duration, Instant},
 };
 
-type OnMessageReceivedCallback = Arc<Mutex<Box<dyn Fn(&[u8]) + Send>>>;
+use crate::coalesce::spawn_flusher;
+use crate::diagnostics::ConnectionLog;
+use crate::error::{connect_failed, handshake_timed_out};
+use crate::fragment::{fragment_header, Reassembler};
+use crate::framing::StreamDecoder;
+use crate::intercept::{unwrap, wrap};
+use crate::journal::Journal;
+use crate::lifecycle::{panic_message, supervise};
+use crate::memory::MemoryAccount;
+use crate::protocol::{FRAGMENT_HEADER_SIZE, LENGTH_HEADER_SIZE};
+use crate::pump::{PeriodicTask, Timers};
+use crate::queue::{spawn_writer, Lane, QueueState, WriteQueue};
+use crate::transport::suppress_sigpipe;
+use crate::{
+    AddressAttempt, ClientConfig, ClientIdentity, ClientState, Clock, CongestionSignal,
+    CongestionTracker, Diagnostics, Direction, DisconnectReason, DispatchMode, Dispatcher, Error,
+    ErrorAction, ErrorPolicy, ErrorReason, FailureThreshold, FrameSizeHistogram, Framing,
+    HandshakeStage, Interceptor, IoThread, JournalConfig, LatencyReport, LocalBind, MemoryBudget,
+    MemoryUsage, MessageSink, Messages, ObfuscatedTransport, Obfuscation, QueueSnapshot,
+    ReconnectPolicy, Resolver, SendTicket, SocketError, SystemClock, SystemResolver, ThreadEvent,
+    ThreadHints, Transport, WriteCoalescing,
+};
+
+type MessageHandler = Arc<dyn Fn(&[u8]) + Send + Sync>;
+type OnMessageReceivedCallback = Arc<Mutex<MessageHandler>>;
+type OnBatchReceivedCallback = Arc<Mutex<Arc<dyn Fn(&[Vec<u8>]) + Send + Sync>>>;
+type OnErrorCallback = Arc<Mutex<Arc<dyn Fn(&Error) + Send + Sync>>>;
+type OnPeerClosedWriteCallback = Arc<Mutex<Arc<dyn Fn() + Send + Sync>>>;
+type OnConnectionClosedCallback =
+    Arc<Mutex<Arc<dyn Fn(&[u8], bool, DisconnectReason) + Send + Sync>>>;
+type OnConnectionFailedCallback = Arc<Mutex<Arc<dyn Fn(&Error) + Send + Sync>>>;
+type ReconnectFilter = Arc<Mutex<Arc<dyn Fn(DisconnectReason) -> bool + Send + Sync>>>;
+type OnReconnectedCallback = Arc<Mutex<Arc<dyn Fn() + Send + Sync>>>;
+type OnSlowConsumerCallback = Arc<Mutex<Arc<dyn Fn(Duration) + Send + Sync>>>;
+type OnThreadEventCallback = Arc<Mutex<Arc<dyn Fn(&ThreadEvent) + Send + Sync>>>;
+type WireTapCallback = Arc<Mutex<Arc<dyn Fn(Direction, &[u8]) + Send + Sync>>>;
+type ConnectionWatcher = Arc<dyn Fn(ConnectionChange) + Send + Sync>;
+
+// What a connection watcher is told, right after on_connection_closed or
+// on_reconnected runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnectionChange {
+    Closed(DisconnectReason),
+    Reconnected,
+}
+
+// The socket is swapped out on reconnect; the generation tells readers and
+// writers whether the socket they hold is still the current one.
+#[derive(Clone)]
+struct Connection {
+    socket: Arc<dyn Transport>,
+    generation: u64,
+}
+
+type Connector = Box<dyn Fn() -> io::Result<Box<dyn Transport>> + Send + Sync>;
+type ResolvedAddresses = Arc<Mutex<Vec<SocketAddr>>>;
+
+// How a TCP endpoint finds its socket addresses: the resolver, and the
+// addresses prewarm looked up ahead of time.
+#[derive(Clone)]
+struct Lookup {
+    resolver: Arc<dyn Resolver>,
+    resolved: ResolvedAddresses,
+}
+
+struct Endpoint {
+    address: String,
+    connect: Connector,
+    // None for custom transports.
+    lookup: Option<Lookup>,
+}
+
+impl Endpoint {
+    fn tcp(
+        address: String,
+        connect_timeout: Option<Duration>,
+        handshake_timeout: Option<Duration>,
+        local: Option<LocalBind>,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        let target = address.clone();
+        let lookup = Lookup {
+            resolver,
+            resolved: Arc::default(),
+        };
+        let lookup_ref = lookup.clone();
+
+        Self {
+            address,
+            connect: Box::new(move || {
+                let deadline = handshake_timeout.map(|timeout| Instant::now() + timeout);
+                let socket = open_socket(&target, connect_timeout, deadline, local, &lookup_ref)?;
+                Ok(Box::new(socket) as Box<dyn Transport>)
+            }),
+            lookup: Some(lookup),
+        }
+    }
+}
+
+// The deadline covers the whole of opening the connection, lookups and
+// every address tried included.
+fn open_socket(
+    address: &str,
+    connect_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    local: Option<LocalBind>,
+    lookup: &Lookup,
+) -> io::Result<TcpStream> {
+    let resolved = &lookup.resolved;
+    let cached = match resolved.lock() {
+        Ok(resolved) => resolved.clone(),
+        Err(e) => e.into_inner().clone(),
+    };
+
+    // Cached addresses may have gone stale, so a failure falls back to a
+    // fresh lookup.
+    if !cached.is_empty() {
+        if let Ok(socket) = connect_any(&cached, connect_timeout, deadline, local) {
+            return Ok(socket);
+        }
+
+        let addresses = resolve(lookup, address, deadline)?;
+        let socket = connect_any(&addresses, connect_timeout, deadline, local);
+
+        match resolved.lock() {
+            Ok(mut resolved) => *resolved = addresses,
+            Err(e) => *e.into_inner() = addresses,
+        }
+
+        return socket;
+    }
+
+    connect_any(
+        &resolve(lookup, address, deadline)?,
+        connect_timeout,
+        deadline,
+        local,
+    )
+}
+
+// Resolvers can't be interrupted, so with a deadline the lookup runs on a
+// thread of its own, which is left to finish by itself if it's too late.
+fn resolve(
+    lookup: &Lookup,
+    address: &str,
+    deadline: Option<Instant>,
+) -> io::Result<Vec<SocketAddr>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return lookup.resolver.resolve(address),
+    };
+
+    let (sender, receiver) = channel();
+    let resolver = lookup.resolver.clone();
+    let target = address.to_string();
+
+    thread::spawn(move || {
+        let _ = sender.send(resolver.resolve(&target));
+    });
+
+    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(handshake_timed_out(HandshakeStage::Resolve)),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("Resolver panicked")),
+    }
+}
+
+fn connect_any(
+    addresses: &[SocketAddr],
+    connect_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    local: Option<LocalBind>,
+) -> io::Result<TcpStream> {
+    let mut attempts = Vec::new();
+
+    for socket_address in addresses {
+        let started = Instant::now();
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(started));
+
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            return Err(handshake_timed_out(HandshakeStage::Connect));
+        }
+
+        let connect_timeout = match (connect_timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+
+        let result = match (local, connect_timeout) {
+            (Some(local), _) => local.connect(socket_address, connect_timeout),
+            (None, Some(timeout)) => TcpStream::connect_timeout(socket_address, timeout),
+            (None, None) => TcpStream::connect(socket_address),
+        };
+
+        match result {
+            Ok(socket) => return Ok(socket),
+            Err(e) => attempts.push(AddressAttempt {
+                address: *socket_address,
+                duration: started.elapsed(),
+                error: e.into(),
+            }),
+        }
+    }
+
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(handshake_timed_out(HandshakeStage::Connect));
+    }
+
+    Err(connect_failed(attempts))
+}
 
 pub struct TcpClientData {
-    socket: TcpStream,
+    // The primary address followed by its fallbacks, in priority order.
+    endpoints: Vec<Endpoint>,
+    active_endpoint: AtomicUsize,
+    // Set for a lazy client until its first connection is open.
+    connect_pending: AtomicBool,
+    diagnostics: ConnectionLog,
+    endpoint_failures: Mutex<Vec<u32>>,
+    connection: Mutex<Connection>,
+    reconnect_lock: Mutex<()>,
+    // When the server asked for the next reconnect attempt to be made.
+    retry_after: Mutex<Option<Instant>>,
+    // When the reconnect in progress makes its next attempt.
+    next_reconnect_at: Mutex<Option<Instant>>,
+    write_lock: Mutex<()>,
+    coalescing: Mutex<Option<WriteCoalescing>>,
+    coalescing_generation: AtomicU64,
+    // Only touched while holding the write lock, so frames stay in order.
+    write_buffer: Mutex<Vec<u8>>,
+    // The encoded identity frame, sent first on every new connection.
+    identity: Mutex<Option<Vec<u8>>>,
     on_message_received: OnMessageReceivedCallback,
-    sender: Sender<bool>,
-    receiver: Mutex<Receiver<bool>>,
+    // Set once on_batch_received has been given; batches replace
+    // on_message_received from then on.
+    batching: AtomicBool,
+    on_batch_received: OnBatchReceivedCallback,
+    on_error: OnErrorCallback,
+    on_peer_closed_write: OnPeerClosedWriteCallback,
+    on_connection_closed: OnConnectionClosedCallback,
+    on_connection_failed: OnConnectionFailedCallback,
+    reconnect_filter: ReconnectFilter,
+    on_reconnected: OnReconnectedCallback,
+    // For owners like ClientManager that follow the connection without
+    // taking the callbacks from the application.
+    connection_watchers: Mutex<Vec<(u64, ConnectionWatcher)>>,
+    next_watcher_id: AtomicU64,
+    on_slow_consumer: OnSlowConsumerCallback,
+    wire_tap: WireTapCallback,
+    on_thread_event: OnThreadEventCallback,
+    // Kinds of IO thread started so far, to tell restarts apart.
+    started_threads: Mutex<Vec<IoThread>>,
+    slow_consumer_threshold: Mutex<Duration>,
+    frame_sizes: Mutex<FrameSizeHistogram>,
+    congestion: Mutex<CongestionTracker>,
+    dispatch_mode: Mutex<DispatchMode>,
+    framing: Mutex<Framing>,
+    thread_hints: Mutex<ThreadHints>,
+    journal: Mutex<Option<Journal>>,
+    error_policy: Mutex<ErrorPolicy>,
+    reconnect_policy: Mutex<ReconnectPolicy>,
+    failure_threshold: Mutex<Option<FailureThreshold>>,
+    peer_closed_write: AtomicBool,
+    // Set when a write failed partway through a frame.
+    stream_torn: AtomicBool,
+    // Why the last connection ended; set once per connection.
+    disconnect_reason: Mutex<Option<DisconnectReason>>,
+    connection_ended: AtomicBool,
+    // Why the server said it was about to close, from a retry-after hint or
+    // a kick notice, until the next connection opens.
+    announced_reason: Mutex<Option<DisconnectReason>>,
+    stop_on_callback_panic: AtomicBool,
+    max_fragment_size: AtomicUsize,
+    max_message_size: AtomicUsize,
+    next_fragment_id: AtomicU32,
+    write_queue: Mutex<Option<WriteQueue>>,
+    memory: MemoryAccount,
+    interceptors: Mutex<Arc<Vec<Arc<dyn Interceptor>>>>,
+    receiving: AtomicBool,
+    clock: Mutex<Arc<dyn Clock>>,
+    // Each with an id, so wait_for_message can take its own back out.
+    message_senders: Mutex<Vec<(u64, Sender<Vec<u8>>)>>,
+    next_stream_id: AtomicU64,
+    closed: AtomicBool,
+    stop_receiving: AtomicBool,
+    suspended: AtomicBool,
+    socket_released: AtomicBool,
+    manual_pump: AtomicBool,
+    timers: Timers,
+    // Zero unless bulk reads are on.
+    bulk_read_size: AtomicUsize,
 }
 
 pub struct TcpClient {
     data: Arc<TcpClientData>,
     nonblocking: bool,
+    // Signalled when the receive loop exits, so drop can wait for it.
+    receive_thread: Mutex<Option<(ThreadId, Receiver<()>)>>,
+    pump: Mutex<Pump>,
+}
+
+// Replaces on_message_received from anywhere, the running handler
+// included, e.g. a login handler that hands over to the main one. The
+// message being handled finishes with the old handler and the next one goes
+// to the new. Does nothing once the client is gone.
+#[derive(Clone)]
+pub struct MessageHandlerSlot {
+    slot: Weak<Mutex<MessageHandler>>,
+}
+
+impl MessageHandlerSlot {
+    pub fn set<F>(&self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        if let Some(slot) = self.slot.upgrade() {
+            store_callback(&slot, Arc::new(callback));
+        }
+    }
+}
+
+// The receive loop as driven by poll, created on the first call.
+enum Pump {
+    NotStarted,
+    Running(Box<ReceiveLoop>),
+    Finished,
 }
 
+// Reads and frames handled by one poll at most.
+const MAX_POLL_STEPS: usize = 1024;
+
+// How long dropping a client waits for its receive loop to finish, e.g. for
+// a callback that is still running.
+const DROP_JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How often wait_for_message polls in manual pump mode.
+const MANUAL_WAIT_INTERVAL: Duration = Duration::from_millis(5);
+
 impl TcpClientData {
-    fn new(address: &str) -> Result<Self, String> {
-        let socket_result = TcpStream::connect(address);
+    fn new(endpoints: Vec<Endpoint>) -> Result<Self, Error> {
+        let mut socket_result = Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No address to connect to",
+        ));
+        let mut active_endpoint: usize = 0;
+        let diagnostics = ConnectionLog::default();
+
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            let started = Instant::now();
+            socket_result = (endpoint.connect)();
+
+            diagnostics.record_attempt(
+                &endpoint.address,
+                started.elapsed(),
+                socket_result.as_ref().err().map(|e| e.to_string()),
+            );
+
+            if socket_result.is_ok() {
+                active_endpoint = index;
+                break;
+            }
+        }
 
         match socket_result {
             Ok(socket) => {
-                if socket.set_nonblocking(true).is_err() {
-                    return Err("Couldn't set socket to nonblocking mode".to_string());
+                if let Err(e) = socket.set_nonblocking(true) {
+                    return Err(Error::Socket(e.into()));
                 };
 
-                let (sender, receiver) = channel::<bool>();
+                diagnostics.start_session(&endpoints[active_endpoint].address);
 
-                Ok(Self {
+                Ok(Self::with_socket(
+                    endpoints,
                     socket,
-                    on_message_received: Arc::new(Mutex::new(Box::new(|_| {}))),
-                    sender,
-                    receiver: Mutex::new(receiver),
-                })
+                    active_endpoint,
+                    diagnostics,
+                    false,
+                ))
             }
-            Err(e) => Err(format!("Error on connection: {e}")),
+            Err(e) => Err(Error::Connect(e.into())),
         }
     }
-}
 
-impl TcpClient {
-    pub fn connect(address: &str) -> Result<Self, String> {
-        let data = TcpClientData::new(address);
+    // Starts out without a connection; the first send or ensure_connected
+    // opens it.
+    fn new_lazy(endpoints: Vec<Endpoint>) -> Self {
+        Self::with_socket(
+            endpoints,
+            Box::new(Unconnected),
+            0,
+            ConnectionLog::default(),
+            true,
+        )
+    }
 
-        match data {
-            Ok(data) => Ok(Self {
-                data: Arc::new(data),
-                nonblocking: true,
+    fn with_socket(
+        endpoints: Vec<Endpoint>,
+        socket: Box<dyn Transport>,
+        active_endpoint: usize,
+        diagnostics: ConnectionLog,
+        connect_pending: bool,
+    ) -> Self {
+        suppress_sigpipe(socket.as_ref());
+
+        Self {
+            diagnostics,
+            endpoint_failures: Mutex::new(vec![0; endpoints.len()]),
+            endpoints,
+            active_endpoint: AtomicUsize::new(active_endpoint),
+            connect_pending: AtomicBool::new(connect_pending),
+            connection: Mutex::new(Connection {
+                socket: Arc::from(socket),
+                generation: 0,
             }),
-            Err(e) => Err(e),
+            reconnect_lock: Mutex::new(()),
+            retry_after: Mutex::new(None),
+            next_reconnect_at: Mutex::new(None),
+            write_lock: Mutex::new(()),
+            coalescing: Mutex::new(None),
+            coalescing_generation: AtomicU64::new(0),
+            write_buffer: Mutex::new(Vec::new()),
+            identity: Mutex::new(None),
+            on_message_received: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            batching: AtomicBool::new(false),
+            on_batch_received: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            on_error: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            on_peer_closed_write: Arc::new(Mutex::new(Arc::new(|| {}))),
+            on_connection_closed: Arc::new(Mutex::new(Arc::new(|_, _, _| {}))),
+            on_connection_failed: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            reconnect_filter: Arc::new(Mutex::new(Arc::new(|_| true))),
+            on_reconnected: Arc::new(Mutex::new(Arc::new(|| {}))),
+            connection_watchers: Mutex::new(Vec::new()),
+            next_watcher_id: AtomicU64::new(0),
+            on_slow_consumer: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            wire_tap: Arc::new(Mutex::new(Arc::new(|_, _| {}))),
+            on_thread_event: Arc::new(Mutex::new(Arc::new(|_| {}))),
+            started_threads: Mutex::new(Vec::new()),
+            slow_consumer_threshold: Mutex::new(Duration::from_millis(100)),
+            frame_sizes: Mutex::new(FrameSizeHistogram::new()),
+            congestion: Mutex::new(CongestionTracker::new()),
+            dispatch_mode: Mutex::new(DispatchMode::Inline),
+            framing: Mutex::new(Framing::LengthPrefixed),
+            thread_hints: Mutex::new(ThreadHints::default()),
+            journal: Mutex::new(None),
+            error_policy: Mutex::new(ErrorPolicy::default()),
+            reconnect_policy: Mutex::new(ReconnectPolicy::default()),
+            failure_threshold: Mutex::new(None),
+            peer_closed_write: AtomicBool::new(false),
+            stream_torn: AtomicBool::new(false),
+            disconnect_reason: Mutex::new(None),
+            connection_ended: AtomicBool::new(false),
+            announced_reason: Mutex::new(None),
+            stop_on_callback_panic: AtomicBool::new(false),
+            max_fragment_size: AtomicUsize::new(0),
+            max_message_size: AtomicUsize::new(0),
+            next_fragment_id: AtomicU32::new(0),
+            write_queue: Mutex::new(None),
+            memory: MemoryAccount::default(),
+            interceptors: Mutex::new(Arc::new(Vec::new())),
+            receiving: AtomicBool::new(false),
+            clock: Mutex::new(Arc::new(SystemClock)),
+            message_senders: Mutex::new(Vec::new()),
+            next_stream_id: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            stop_receiving: AtomicBool::new(false),
+            suspended: AtomicBool::new(false),
+            socket_released: AtomicBool::new(false),
+            manual_pump: AtomicBool::new(false),
+            timers: Timers::default(),
+            bulk_read_size: AtomicUsize::new(0),
         }
     }
 
-    pub fn disconnect(&self) -> bool {
-        let result = self.data.socket.shutdown(Shutdown::Both);
-        let data_ref = self.data.clone();
+    fn connection(&self) -> Connection {
+        match self.connection.lock() {
+            Ok(connection) => connection.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
 
-        if result.is_ok() {
-            let _ = data_ref.sender.send(true);
-            true
-        } else {
-            false
+    pub(crate) fn socket(&self) -> Arc<dyn Transport> {
+        self.connection().socket
+    }
+
+    fn framing(&self) -> Framing {
+        match self.framing.lock() {
+            Ok(framing) => framing.clone(),
+            Err(e) => e.into_inner().clone(),
         }
     }
 
-    pub fn send(&self, data: &[u8]) -> Result<(), String> {
-        let data_ref = self.data.clone();
-        let mut socket = &data_ref.socket;
+    pub(crate) fn apply_thread_hints(&self) {
+        let thread_hints = match self.thread_hints.lock() {
+            Ok(thread_hints) => *thread_hints,
+            Err(e) => *e.into_inner(),
+        };
 
-        let header = (data.len() as u64).to_le_bytes();
-        let mut header_written: usize = 0;
-        let mut body_written: usize = 0;
+        if let Err(e) = thread_hints.apply() {
+            self.report_error(&Error::ThreadHints(e.to_string()));
+        }
+    }
 
-        while header_written < 8 {
-            match socket.write(&header[header_written..]) {
-                Ok(size) => {
-                    if size > 0 {
-                        header_written += size;
-                    }
+    pub(crate) fn journal(&self, direction: Direction, message: &[u8]) {
+        let result = match self.journal.lock() {
+            Ok(mut journal) => match journal.as_mut() {
+                Some(journal) => journal.record(direction, message),
+                None => return,
+            },
+            Err(_) => return,
+        };
+
+        if let Err(e) = result {
+            self.report_error(&Error::Journal(e.to_string()));
+        }
+    }
+
+    // Counts and taps the bytes of every successful socket read and write.
+    fn record_io(&self, direction: Direction, bytes: &[u8]) {
+        self.diagnostics.record_io(direction, bytes.len());
+
+        let wire_tap = load_callback(&self.wire_tap);
+        self.run_callback(|| wire_tap(direction, bytes));
+    }
+
+    fn error_policy(&self) -> ErrorPolicy {
+        match self.error_policy.lock() {
+            Ok(error_policy) => *error_policy,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn close(&self) -> bool {
+        self.closed.store(true, Ordering::SeqCst);
+        self.stop_receiving.store(true, Ordering::SeqCst);
+        self.record_disconnect(DisconnectReason::LocalRequest);
+
+        self.socket().shutdown(Shutdown::Both).is_ok()
+    }
+
+    // Replaces the socket with a fresh connection, trying the endpoints in
+    // priority order. Each endpoint backs off according to its own failure
+    // count. Does nothing if the given generation was already replaced by
+    // someone else.
+    pub(crate) fn reconnect(&self, generation: u64) -> Result<(), Error> {
+        let guard = match self.reconnect_lock.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        if self.connection().generation != generation {
+            return Ok(());
+        }
+
+        let policy = match self.reconnect_policy.lock() {
+            Ok(policy) => *policy,
+            Err(e) => *e.into_inner(),
+        };
+        let clock = self.clock();
+        let mut last_error = Error::Closed;
+
+        for _ in 0..policy.max_attempts {
+            for (index, endpoint) in self.endpoints.iter().enumerate() {
+                clock.sleep(self.next_attempt_delay(&policy, index, clock.now()));
+
+                if self.is_closed() {
+                    self.set_next_reconnect_at(None);
+                    return Err(Error::Closed);
                 }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        thread::sleep(Duration::from_millis(50));
-                    } else {
-                        return Err(e.kind().to_string());
+
+                let started = clock.now();
+                let result = (endpoint.connect)();
+
+                self.diagnostics.record_attempt(
+                    &endpoint.address,
+                    clock.now().saturating_duration_since(started),
+                    result.as_ref().err().map(|e| e.to_string()),
+                );
+
+                let socket = match result {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        last_error = Error::Connect(e.into());
+                        self.record_endpoint_failure(index, true);
+                        continue;
                     }
+                };
+
+                if let Err(e) = socket.set_nonblocking(true) {
+                    last_error = Error::Socket(e.into());
+                    self.record_endpoint_failure(index, true);
+                    continue;
                 }
+
+                self.record_endpoint_failure(index, false);
+                self.active_endpoint.store(index, Ordering::SeqCst);
+                self.diagnostics.end_session("Replaced by reconnect");
+                self.diagnostics.start_session(&endpoint.address);
+                self.install_socket(socket);
+                self.set_next_reconnect_at(None);
+
+                // Released first: a send from the callback that fails under a
+                // Reconnect policy reconnects again on this thread.
+                drop(guard);
+
+                let on_reconnected = load_callback(&self.on_reconnected);
+                self.run_callback(|| on_reconnected());
+                self.notify_watchers(ConnectionChange::Reconnected);
+
+                return Ok(());
             }
         }
 
-        while body_written < data.len() {
-            match socket.write(&data[body_written..]) {
-                Ok(size) => {
-                    if size > 0 {
-                        body_written += size;
-                    }
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        thread::sleep(Duration::from_millis(50));
-                    } else {
-                        return Err(e.kind().to_string());
-                    }
+        self.set_next_reconnect_at(None);
+        Err(last_error)
+    }
+
+    // Moves the connection to an address the server redirected the client
+    // to. The old socket stays until the new one is open, so a target that
+    // can't be reached leaves the client where it was. Callbacks and queued
+    // writes carry over and on_reconnected runs as after a reconnect, but
+    // later reconnects still go to the configured endpoints.
+    pub(crate) fn redirect<F>(&self, address: &str, connect: F) -> Result<(), Error>
+    where
+        F: FnOnce() -> io::Result<Box<dyn Transport>>,
+    {
+        let guard = match self.reconnect_lock.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+
+        let clock = self.clock();
+        let started = clock.now();
+        let result = connect().and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+
+        self.diagnostics.record_attempt(
+            address,
+            clock.now().saturating_duration_since(started),
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+
+        let socket = result.map_err(|e| Error::Connect(e.into()))?;
+        self.diagnostics.end_session("Redirected by the server");
+        self.diagnostics.start_session(address);
+        self.install_socket(socket);
+        drop(guard);
+
+        let on_reconnected = load_callback(&self.on_reconnected);
+        self.run_callback(|| on_reconnected());
+        self.notify_watchers(ConnectionChange::Reconnected);
+
+        Ok(())
+    }
+
+    // A retry-after hint from the server replaces the policy's delay, once.
+    fn next_attempt_delay(&self, policy: &ReconnectPolicy, index: usize, now: Instant) -> Duration {
+        let hint = match self.retry_after.lock() {
+            Ok(mut retry_after) => retry_after.take(),
+            Err(e) => e.into_inner().take(),
+        };
+
+        let delay = match hint {
+            Some(at) => at.saturating_duration_since(now),
+            None => policy.delay(self.endpoint_failures(index)),
+        };
+
+        self.set_next_reconnect_at(Some(now + delay));
+        delay
+    }
+
+    fn set_next_reconnect_at(&self, at: Option<Instant>) {
+        match self.next_reconnect_at.lock() {
+            Ok(mut next_reconnect_at) => *next_reconnect_at = at,
+            Err(e) => *e.into_inner() = at,
+        }
+    }
+
+    // Opens a lazy client's connection, trying each endpoint once in order
+    // and without the reconnect policy's backoff.
+    pub(crate) fn ensure_connected(&self) -> Result<(), Error> {
+        if !self.connect_pending.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let _guard = match self.reconnect_lock.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        if !self.connect_pending.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+
+        let clock = self.clock();
+        let mut last_error =
+            io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            let started = clock.now();
+            let result = (endpoint.connect)().and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            });
+
+            self.diagnostics.record_attempt(
+                &endpoint.address,
+                clock.now().saturating_duration_since(started),
+                result.as_ref().err().map(|e| e.to_string()),
+            );
+
+            match result {
+                Ok(socket) => {
+                    self.active_endpoint.store(index, Ordering::SeqCst);
+                    self.diagnostics.start_session(&endpoint.address);
+                    self.install_socket(socket);
+
+                    return Ok(());
                 }
+                Err(e) => last_error = e,
             }
         }
 
-        Ok(())
+        Err(Error::Connect(last_error.into()))
     }
 
-    pub fn receive(&self) {
-        let data_ref = self.data.clone();
+    fn endpoint_failures(&self, index: usize) -> u32 {
+        match self.endpoint_failures.lock() {
+            Ok(failures) => failures[index],
+            Err(e) => e.into_inner()[index],
+        }
+    }
 
-        thread::spawn(move || {
-            let mut socket = &data_ref.socket;
-            let mut buffer: Vec<u8> = vec![0; 8];
-            let mut read_bytes: usize = 0;
-            let mut amount_to_read: usize = 0;
-            let header_size = std::mem::size_of::<u64>();
-
-            loop {
-                if let Ok(receiver) = data_ref.receiver.lock() {
-                    if let Ok(data) = receiver.try_recv() {
-                        if data {
-                            break;
-                        }
+    fn record_endpoint_failure(&self, index: usize, failed: bool) {
+        let mut failures = match self.endpoint_failures.lock() {
+            Ok(failures) => failures,
+            Err(e) => e.into_inner(),
+        };
+
+        failures[index] = if failed {
+            failures[index].saturating_add(1)
+        } else {
+            0
+        };
+    }
+
+    fn install_socket(&self, socket: Box<dyn Transport>) {
+        suppress_sigpipe(socket.as_ref());
+
+        let previous = match self.connection.lock() {
+            Ok(mut connection) => Self::replace_socket(&mut connection, socket),
+            Err(e) => Self::replace_socket(&mut e.into_inner(), socket),
+        };
+
+        let _ = previous.shutdown(Shutdown::Both);
+        self.connect_pending.store(false, Ordering::SeqCst);
+        self.peer_closed_write.store(false, Ordering::SeqCst);
+        self.stream_torn.store(false, Ordering::SeqCst);
+        self.connection_ended.store(false, Ordering::SeqCst);
+        self.set_announced_reason(None);
+
+        // Written straight to the new socket rather than through the write
+        // error policy, which could reconnect again from in here.
+        let identity = match self.identity.lock() {
+            Ok(identity) => identity.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+        if let Some(identity) = identity {
+            let result = self.write_message_to(
+                self.socket().as_ref(),
+                &identity,
+                ErrorAction::Continue,
+                None,
+            );
+
+            if let Err(e) = result {
+                self.report_error(&e);
+            }
+        }
+    }
+
+    fn replace_socket(
+        connection: &mut Connection,
+        socket: Box<dyn Transport>,
+    ) -> Arc<dyn Transport> {
+        connection.generation += 1;
+        std::mem::replace(&mut connection.socket, Arc::from(socket))
+    }
+
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        match self.clock.lock() {
+            Ok(clock) => clock.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn check_message_size(&self, size: usize) -> Result<(), Error> {
+        match self.max_message_size.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            limit if size > limit => Err(Error::FrameTooLarge(size)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn send(&self, data: &[u8], lane: Lane) -> Result<SendTicket, Error> {
+        let interceptors = load_callback(&self.interceptors);
+
+        if interceptors.is_empty() {
+            self.enqueue(data, lane)
+        } else {
+            self.enqueue(&wrap(&interceptors, data)?, lane)
+        }
+    }
+
+    // For frames a layer sends from on_reconnected to restore its session.
+    // A failed write is returned rather than handled by the write error
+    // policy: reconnecting from in here would restore the newer connection
+    // and then finish this restore on it as well. The dead connection is
+    // replaced by the receive loop or the next send, and on_reconnected runs
+    // again for the one replacing it. Queued frames are written by the
+    // writer thread, which reconnects from outside the callback.
+    pub(crate) fn send_on_reconnect(&self, data: &[u8], lane: Lane) -> Result<SendTicket, Error> {
+        if self.write_queue_state().is_some() {
+            return self.send(data, lane);
+        }
+
+        let interceptors = load_callback(&self.interceptors);
+        let wrapped;
+
+        let data = if interceptors.is_empty() {
+            data
+        } else {
+            wrapped = wrap(&interceptors, data)?;
+            &wrapped[..]
+        };
+
+        self.check_message_size(data.len())?;
+        self.journal(Direction::Outbound, data);
+
+        self.write_message_to(self.socket().as_ref(), data, ErrorAction::Continue, None)
+            .map(|_| SendTicket::completed(Ok(())))
+    }
+
+    // Sends a message that has already been through the interceptors.
+    fn enqueue(&self, data: &[u8], lane: Lane) -> Result<SendTicket, Error> {
+        self.check_message_size(data.len())?;
+
+        match self.write_queue_state() {
+            Some(write_queue) => {
+                let (ticket, message) = SendTicket::queued(data.to_vec());
+
+                // Control frames are small and must get out, so only data
+                // counts against the budget.
+                let budget = match lane {
+                    Lane::Control => None,
+                    Lane::Data => self.memory.budget().map(|budget| {
+                        let inbound = self.memory.usage(0).total();
+                        (budget, budget.limit.saturating_sub(inbound))
+                    }),
+                };
+
+                if let Err(e) = write_queue.push(lane, message, budget, &*self.clock()) {
+                    if matches!(e, Error::MemoryBudgetExceeded(_)) {
+                        self.memory.record_exceeded();
                     }
+
+                    return Err(e);
                 }
 
-                if read_bytes >= 8 {
-                    let arr: [u8; 8] = buffer[0..header_size].try_into().unwrap();
-                    amount_to_read = usize::from_le_bytes(arr);
+                Ok(ticket)
+            }
+            None if self.is_suspended() => Err(Error::Suspended),
+            None => self
+                .write_message(data)
+                .map(|_| SendTicket::completed(Ok(()))),
+        }
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        let queued = match self.write_queue_state() {
+            Some(write_queue) => write_queue.queued_bytes(),
+            None => 0,
+        };
 
-                    if buffer.len() != header_size + amount_to_read {
-                        buffer.resize(header_size + amount_to_read, 0);
-                    }
+        self.memory.usage(queued)
+    }
+
+    // Fails, and counts it, if the usage would be over the budget.
+    fn check_memory(&self, usage: MemoryUsage) -> Result<(), Error> {
+        match self.memory.budget() {
+            Some(budget) if usage.total() > budget.limit => {
+                self.memory.record_exceeded();
+                Err(Error::MemoryBudgetExceeded(budget.limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_queue_state(&self) -> Option<Arc<QueueState>> {
+        match self.write_queue.lock() {
+            Ok(write_queue) => write_queue.as_ref().map(WriteQueue::state),
+            Err(e) => e.into_inner().as_ref().map(WriteQueue::state),
+        }
+    }
+
+    pub(crate) fn write_message(&self, data: &[u8]) -> Result<(), Error> {
+        self.write_message_with_progress(data, None)
+    }
+
+    pub(crate) fn write_message_with_progress(
+        &self,
+        data: &[u8],
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<(), Error> {
+        self.ensure_connected()?;
+        self.journal(Direction::Outbound, data);
+
+        let connection = self.connection();
+        let policy = self.error_policy();
+        let result = self.write_message_to(
+            connection.socket.as_ref(),
+            data,
+            policy.write_error,
+            progress,
+        );
+        let action = self.write_failure_action(&result, &policy);
+
+        match (result, action) {
+            (Err(e), ErrorAction::Reconnect) if !self.is_closed() => {
+                if let Err(reconnect_error) = self.reconnect(connection.generation) {
+                    self.report_error(&e);
+                    self.close();
+                    return Err(reconnect_error);
                 }
 
-                match socket.read(&mut buffer[read_bytes..]) {
-                    Ok(size) => {
-                        if size == 0 {
-                            if let Ok(on_message_received) = data_ref.on_message_received.lock() {
-                                on_message_received(&[]);
-                                // exit(0);
-                            }
-                        } else {
-                            read_bytes += size;
-
-                            if amount_to_read > 0 && read_bytes == header_size + amount_to_read {
-                                if let Ok(on_message_received) = data_ref.on_message_received.lock()
-                                {
-                                    on_message_received(&buffer[header_size..]);
-                                    buffer.resize(8, 0);
-                                    read_bytes = 0;
-                                    amount_to_read = 0;
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            thread::sleep(Duration::from_millis(100));
-                        }
-                    }
+                self.write_message_to(
+                    self.socket().as_ref(),
+                    data,
+                    ErrorAction::Continue,
+                    progress,
+                )
+            }
+            (Err(e), ErrorAction::Retry(_) | ErrorAction::Close) => {
+                self.close();
+                Err(e)
+            }
+            (result, _) => result,
+        }
+    }
+
+    // A write that finds the peer gone is handled like the receive loop
+    // seeing end of stream, under the peer_closed policy. A connection left
+    // partway through a frame is reconnected or closed, since carrying on
+    // would garble the peer's stream.
+    fn write_failure_action(
+        &self,
+        result: &Result<(), Error>,
+        policy: &ErrorPolicy,
+    ) -> ErrorAction {
+        let action = match result {
+            Ok(()) => return policy.write_error,
+            Err(Error::Disconnected(_)) => {
+                if !self.peer_closed_write.swap(true, Ordering::SeqCst) {
+                    let on_peer_closed_write = load_callback(&self.on_peer_closed_write);
+                    self.run_callback(|| on_peer_closed_write());
                 }
+
+                policy.peer_closed
             }
-        });
+            Err(_) => policy.write_error,
+        };
+
+        let action = match action {
+            ErrorAction::Continue | ErrorAction::Retry(_)
+                if self.stream_torn.load(Ordering::SeqCst) =>
+            {
+                ErrorAction::Close
+            }
+            action => action,
+        };
+
+        match (result, action) {
+            (Err(e), ErrorAction::Reconnect | ErrorAction::Close | ErrorAction::Retry(_)) => {
+                self.record_disconnect(self.disconnect_reason_for(e));
+                self.filter_reconnect(action)
+            }
+            _ => action,
+        }
     }
 
-    pub fn set_nonblocking(&mut self, nonblocking: bool) {
-        self.nonblocking = nonblocking;
+    // The first reason recorded for a connection sticks, so the close that
+    // follows a failed read is still reported as that failure.
+    pub(crate) fn record_disconnect(&self, reason: DisconnectReason) {
+        if self.connection_ended.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        match self.disconnect_reason.lock() {
+            Ok(mut current) => *current = Some(reason),
+            Err(e) => *e.into_inner() = Some(reason),
+        }
+
+        self.diagnostics.end_session(&reason.to_string());
     }
 
-    pub fn set_on_message_received<F>(&mut self, callback: F)
-    where
-        F: Fn(&[u8]) + Send + 'static,
-    {
-        if let Ok(mut cb) = self.data.on_message_received.lock() {
-            *cb = Box::new(callback);
+    fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self.disconnect_reason.lock() {
+            Ok(reason) => *reason,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    fn announced_reason(&self) -> Option<DisconnectReason> {
+        match self.announced_reason.lock() {
+            Ok(reason) => *reason,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    fn set_announced_reason(&self, reason: Option<DisconnectReason>) {
+        match self.announced_reason.lock() {
+            Ok(mut current) => *current = reason,
+            Err(e) => *e.into_inner() = reason,
         }
     }
+
+    // Records that the server is about to close the connection, and why, so
+    // that its end is reported with that reason rather than as the peer
+    // closing. A retry-after delay replaces the reconnect policy's delay
+    // for the next attempt.
+    pub(crate) fn announce_disconnect(
+        &self,
+        reason: DisconnectReason,
+        retry_after: Option<Duration>,
+    ) {
+        self.set_announced_reason(Some(reason));
+
+        if let Some(delay) = retry_after {
+            let at = self.clock().now() + delay;
+
+            match self.retry_after.lock() {
+                Ok(mut retry_after) => *retry_after = Some(at),
+                Err(e) => *e.into_inner() = Some(at),
+            }
+        }
+    }
+
+    fn peer_closed_reason(&self) -> DisconnectReason {
+        self.announced_reason()
+            .unwrap_or(DisconnectReason::PeerClosed)
+    }
+
+    fn disconnect_reason_for(&self, error: &Error) -> DisconnectReason {
+        match error {
+            Error::Disconnected(_) => self.peer_closed_reason(),
+            Error::Connect(e) | Error::Socket(e) => match (e.kind(), self.announced_reason()) {
+                (
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted,
+                    Some(reason),
+                ) => reason,
+                (kind, _) => DisconnectReason::from_kind(kind),
+            },
+            Error::Closed | Error::QueueClosed => DisconnectReason::LocalRequest,
+            _ => DisconnectReason::ProtocolError,
+        }
+    }
+
+    // Turns a reconnect into a close when the filter turns down the reason
+    // the connection ended for.
+    fn filter_reconnect(&self, action: ErrorAction) -> ErrorAction {
+        let reason = match (action, self.last_disconnect_reason()) {
+            (ErrorAction::Reconnect, Some(reason)) => reason,
+            _ => return action,
+        };
+
+        let reconnect_filter = load_callback(&self.reconnect_filter);
+
+        if reconnect_filter(reason) {
+            action
+        } else {
+            ErrorAction::Close
+        }
+    }
+
+    fn write_message_to(
+        &self,
+        socket: &dyn Transport,
+        data: &[u8],
+        action: ErrorAction,
+        progress: Option<&dyn Fn(usize, usize)>,
+    ) -> Result<(), Error> {
+        // Held for the whole message so that concurrent senders can't
+        // interleave their frames, or the fragments of one message.
+        let _guard = match self.write_lock.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+
+        let mut progress = progress.map(|callback| Progress {
+            callback,
+            sent: 0,
+            total: 0,
+        });
+
+        match self.framing() {
+            Framing::LengthPrefixed => {}
+            Framing::Delimited { delimiter, .. } => {
+                if let Some(progress) = &mut progress {
+                    progress.total = data.len() + delimiter.len();
+                }
+
+                return self.write_frame(socket, &[data, &delimiter], action, progress.as_mut());
+            }
+            Framing::Mqtt => {
+                if let Some(progress) = &mut progress {
+                    progress.total = data.len();
+                }
+
+                return self.write_frame(socket, &[data], action, progress.as_mut());
+            }
+        }
+
+        let max_fragment_size = self.max_fragment_size.load(Ordering::SeqCst);
+
+        if max_fragment_size == 0 {
+            if let Some(progress) = &mut progress {
+                progress.total = LENGTH_HEADER_SIZE + data.len();
+            }
+
+            let length = (data.len() as u64).to_le_bytes();
+            return self.write_frame(socket, &[&length, data], action, progress.as_mut());
+        }
+
+        let id = self.next_fragment_id.fetch_add(1, Ordering::SeqCst);
+        let count = data.len().div_ceil(max_fragment_size).max(1);
+
+        if let Some(progress) = &mut progress {
+            progress.total = count * (LENGTH_HEADER_SIZE + FRAGMENT_HEADER_SIZE) + data.len();
+        }
+
+        for index in 0..count {
+            let start = index * max_fragment_size;
+            let end = (start + max_fragment_size).min(data.len());
+            let header = fragment_header(id, index as u32, index + 1 == count);
+            let length = ((FRAGMENT_HEADER_SIZE + end - start) as u64).to_le_bytes();
+
+            self.write_frame(
+                socket,
+                &[&length, &header, &data[start..end]],
+                action,
+                progress.as_mut(),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn write_frame(
+        &self,
+        socket: &dyn Transport,
+        parts: &[&[u8]],
+        action: ErrorAction,
+        progress: Option<&mut Progress>,
+    ) -> Result<(), Error> {
+        let coalescing = match self.coalescing.lock() {
+            Ok(coalescing) => *coalescing,
+            Err(e) => *e.into_inner(),
+        };
+
+        let coalescing = match coalescing {
+            Some(coalescing) => coalescing,
+            None => return self.write_parts(socket, parts, action, progress),
+        };
+
+        // Progress is only meaningful for bytes actually written, so these
+        // frames skip the buffer, after writing out what is already in it.
+        if progress.is_some() {
+            let pending = std::mem::take(&mut *self.write_buffer());
+
+            if !pending.is_empty() {
+                self.write_parts(socket, &[&pending], action, None)?;
+            }
+
+            return self.write_parts(socket, parts, action, progress);
+        }
+
+        let pending = {
+            let mut buffer = self.write_buffer();
+
+            for part in parts {
+                buffer.extend_from_slice(part);
+            }
+
+            if buffer.len() < coalescing.capacity {
+                return Ok(());
+            }
+
+            std::mem::take(&mut *buffer)
+        };
+
+        self.write_parts(socket, &[&pending], action, None)
+    }
+
+    fn write_buffer(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        match self.write_buffer.lock() {
+            Ok(buffer) => buffer,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    pub(crate) fn coalescing_generation(&self) -> u64 {
+        self.coalescing_generation.load(Ordering::SeqCst)
+    }
+
+    // Writes out whatever write coalescing has buffered, following the write
+    // error policy like a regular send.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        if self.is_suspended() {
+            return Ok(());
+        }
+
+        let connection = self.connection();
+        let policy = self.error_policy();
+
+        let (pending, result) = {
+            let _guard = match self.write_lock.lock() {
+                Ok(guard) => guard,
+                Err(e) => e.into_inner(),
+            };
+
+            let pending = std::mem::take(&mut *self.write_buffer());
+
+            if pending.is_empty() {
+                return Ok(());
+            }
+
+            let result = self.write_parts(
+                connection.socket.as_ref(),
+                &[&pending],
+                policy.write_error,
+                None,
+            );
+            (pending, result)
+        };
+
+        // The write lock is released first: reconnect callbacks may send.
+        let action = self.write_failure_action(&result, &policy);
+
+        match (result, action) {
+            (Err(e), ErrorAction::Reconnect) if !self.is_closed() => {
+                if let Err(reconnect_error) = self.reconnect(connection.generation) {
+                    self.report_error(&e);
+                    self.close();
+                    return Err(reconnect_error);
+                }
+
+                let _guard = match self.write_lock.lock() {
+                    Ok(guard) => guard,
+                    Err(e) => e.into_inner(),
+                };
+
+                self.write_parts(
+                    self.socket().as_ref(),
+                    &[&pending],
+                    ErrorAction::Continue,
+                    None,
+                )
+            }
+            (Err(e), ErrorAction::Retry(_) | ErrorAction::Close) => {
+                self.close();
+                Err(e)
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn write_parts(
+        &self,
+        socket: &dyn Transport,
+        parts: &[&[u8]],
+        action: ErrorAction,
+        progress: Option<&mut Progress>,
+    ) -> Result<(), Error> {
+        if self.stream_torn.load(Ordering::SeqCst) {
+            return Err(Error::Socket(
+                io::Error::other("A frame was only partly written on this connection").into(),
+            ));
+        }
+
+        let clock = self.clock();
+
+        let pacing_delay = match self.congestion.lock() {
+            Ok(congestion) => congestion.pacing_delay(),
+            Err(_) => None,
+        };
+
+        if let Some(delay) = pacing_delay {
+            clock.sleep(delay);
+        }
+
+        let mut writer = FrameWriter {
+            client: self,
+            socket,
+            clock: clock.as_ref(),
+            retries: match action {
+                ErrorAction::Retry(retries) => retries,
+                _ => 0,
+            },
+            stalled: Duration::ZERO,
+            written: 0,
+            progress,
+        };
+
+        for part in parts {
+            if let Err(e) = writer.write_all(part) {
+                // The peer is now partway through a frame, so nothing else
+                // can be sent on this connection.
+                if writer.written > 0 {
+                    self.stream_torn.store(true, Ordering::SeqCst);
+                }
+
+                return Err(e);
+            }
+        }
+
+        // A failed flush may have lost any of the frame's bytes.
+        if let Err(e) = writer.flush() {
+            self.stream_torn.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        if let Ok(mut congestion) = self.congestion.lock() {
+            congestion.record(writer.stalled);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn report_error(&self, error: &Error) {
+        let on_error = load_callback(&self.on_error);
+
+        // A panicking error handler has nowhere left to report to.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| on_error(error)));
+    }
+
+    pub(crate) fn thread_event(&self, event: &ThreadEvent) {
+        let on_thread_event = load_callback(&self.on_thread_event);
+        self.run_callback(|| on_thread_event(event));
+    }
+
+    // Returns whether a thread of this kind was started before.
+    pub(crate) fn record_thread_start(&self, thread: IoThread) -> bool {
+        let mut started_threads = match self.started_threads.lock() {
+            Ok(started_threads) => started_threads,
+            Err(e) => e.into_inner(),
+        };
+
+        if started_threads.contains(&thread) {
+            return true;
+        }
+
+        started_threads.push(thread);
+        false
+    }
+
+    // Lets receive start a new loop after the previous one panicked.
+    pub(crate) fn recover_thread(&self, thread: IoThread) {
+        if thread == IoThread::Receive {
+            self.receiving.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn run_callback<F>(&self, callback: F)
+    where
+        F: FnOnce(),
+    {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(callback)) {
+            let message = panic_message(payload.as_ref())
+                .unwrap_or_else(|| "Callback panicked with a non-string payload".to_string());
+
+            self.report_error(&Error::CallbackPanicked(message));
+
+            if self.stop_on_callback_panic.load(Ordering::SeqCst) {
+                self.stop_receiving.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn run_receive_loop(self: Arc<Self>) {
+        self.apply_thread_hints();
+
+        let mut receive_loop = ReceiveLoop::new(self);
+
+        // Idle steps wait for the socket, not for time to pass, so they
+        // sleep in real time even under a MockClock, which would otherwise
+        // hold the loop until a test advances it.
+        loop {
+            match receive_loop.step() {
+                Step::Progressed => {}
+                Step::Idle(delay) => thread::sleep(delay),
+                Step::Stopped => break,
+            }
+        }
+
+        receive_loop.finish();
+    }
+
+    fn notify_watchers(&self, change: ConnectionChange) {
+        let watchers: Vec<ConnectionWatcher> = match self.connection_watchers.lock() {
+            Ok(watchers) => watchers
+                .iter()
+                .map(|(_, watcher)| watcher.clone())
+                .collect(),
+            Err(e) => e
+                .into_inner()
+                .iter()
+                .map(|(_, watcher)| watcher.clone())
+                .collect(),
+        };
+
+        for watcher in watchers {
+            self.run_callback(|| watcher(change));
+        }
+    }
+
+    pub(crate) fn is_manual_pump(&self) -> bool {
+        self.manual_pump.load(Ordering::SeqCst)
+    }
+
+    // Runs task every interval from poll; only for manual pump mode.
+    pub(crate) fn schedule(&self, interval: Duration, task: PeriodicTask) {
+        self.timers.add(self.clock().now(), interval, task);
+    }
+
+    fn open_message_stream(&self) -> (u64, Receiver<Vec<u8>>) {
+        let (sender, receiver) = channel::<Vec<u8>>();
+        let id = self.next_stream_id.fetch_add(1, Ordering::SeqCst);
+
+        if let Ok(mut message_senders) = self.message_senders.lock() {
+            message_senders.push((id, sender));
+        }
+
+        (id, receiver)
+    }
+
+    fn close_message_stream(&self, id: u64) {
+        if let Ok(mut message_senders) = self.message_senders.lock() {
+            message_senders.retain(|(stream, _)| *stream != id);
+        }
+    }
+
+    fn end_message_streams(&self) {
+        if let Ok(mut message_senders) = self.message_senders.lock() {
+            message_senders.clear();
+        }
+    }
+
+    pub(crate) fn dispatch(&self, message: &[u8]) {
+        let interceptors = load_callback(&self.interceptors);
+        let unwrapped;
+
+        let message = if interceptors.is_empty() {
+            message
+        } else {
+            match unwrap(&interceptors, message) {
+                Ok(message) => {
+                    unwrapped = message;
+                    &unwrapped[..]
+                }
+                Err(e) => {
+                    self.report_error(&e);
+                    return;
+                }
+            }
+        };
+
+        self.record_received(message);
+
+        // The handler is cloned out of the mutex so that pool workers can run
+        // it concurrently.
+        let on_message_received = load_callback(&self.on_message_received);
+        self.run_timed_callback(|| on_message_received(message));
+    }
+
+    pub(crate) fn dispatch_batch(&self, messages: &[Vec<u8>]) {
+        let interceptors = load_callback(&self.interceptors);
+        let unwrapped: Vec<Vec<u8>>;
+
+        let messages = if interceptors.is_empty() {
+            messages
+        } else {
+            unwrapped = messages
+                .iter()
+                .filter_map(|message| match unwrap(&interceptors, message) {
+                    Ok(message) => Some(message),
+                    Err(e) => {
+                        self.report_error(&e);
+                        None
+                    }
+                })
+                .collect();
+            &unwrapped[..]
+        };
+
+        for message in messages {
+            self.record_received(message);
+        }
+
+        let on_batch_received = load_callback(&self.on_batch_received);
+        self.run_timed_callback(|| on_batch_received(messages));
+    }
+
+    fn record_received(&self, message: &[u8]) {
+        if let Ok(mut frame_sizes) = self.frame_sizes.lock() {
+            frame_sizes.record(message.len());
+        }
+
+        if let Ok(mut message_senders) = self.message_senders.lock() {
+            message_senders.retain(|(_, sender)| sender.send(message.to_vec()).is_ok());
+        }
+    }
+
+    // Runs a receive callback, reporting it as a slow consumer if it takes
+    // longer than the threshold.
+    fn run_timed_callback<F>(&self, callback: F)
+    where
+        F: FnOnce(),
+    {
+        let clock = self.clock();
+        let started = clock.now();
+
+        self.run_callback(callback);
+
+        let elapsed = clock.now().saturating_duration_since(started);
+        let threshold = match self.slow_consumer_threshold.lock() {
+            Ok(threshold) => *threshold,
+            Err(_) => return,
+        };
+
+        if elapsed > threshold {
+            let on_slow_consumer = load_callback(&self.on_slow_consumer);
+            self.run_callback(|| on_slow_consumer(elapsed));
+        }
+    }
+}
+
+impl TcpClient {
+    pub fn connect(address: &str) -> Result<Self, Error> {
+        Self::connect_with(
+            vec![address.to_string()],
+            None,
+            None,
+            None,
+            Arc::new(SystemResolver),
+        )
+    }
+
+    // Connects with a custom resolver, e.g. a StaticResolver in tests or a
+    // CachingResolver to spare DNS during reconnect storms. Reconnects and
+    // prewarm use the same resolver.
+    pub fn connect_with_resolver(
+        address: &str,
+        resolver: Arc<dyn Resolver>,
+    ) -> Result<Self, Error> {
+        Self::connect_with(vec![address.to_string()], None, None, None, resolver)
+    }
+
+    pub fn connect_timeout(address: &str, timeout: Duration) -> Result<Self, Error> {
+        Self::connect_with(
+            vec![address.to_string()],
+            Some(timeout),
+            None,
+            None,
+            Arc::new(SystemResolver),
+        )
+    }
+
+    // Connects from a fixed local address, shared with a listener on the
+    // same port when reuse_port is set, as TCP hole punching needs.
+    // Reconnects bind to the same address, so reconnecting to the same peer
+    // fails until the old connection's TIME_WAIT has passed.
+    pub fn connect_from(address: &str, local: LocalBind) -> Result<Self, Error> {
+        Self::connect_with(
+            vec![address.to_string()],
+            None,
+            None,
+            Some(local),
+            Arc::new(SystemResolver),
+        )
+    }
+
+    pub fn from_config(config: &ClientConfig) -> Result<Self, Error> {
+        let mut addresses = vec![config.address.clone()];
+        addresses.extend(config.fallback_addresses.iter().cloned());
+
+        let resolver: Arc<dyn Resolver> = Arc::new(SystemResolver);
+        let mut client = if config.lazy {
+            Self::wrap(TcpClientData::new_lazy(
+                addresses
+                    .into_iter()
+                    .map(|address| {
+                        Endpoint::tcp(
+                            address,
+                            config.connect_timeout,
+                            config.handshake_timeout,
+                            config.local_bind,
+                            resolver.clone(),
+                        )
+                    })
+                    .collect(),
+            ))
+        } else {
+            Self::connect_with(
+                addresses,
+                config.connect_timeout,
+                config.handshake_timeout,
+                config.local_bind,
+                resolver,
+            )?
+        };
+
+        client.set_dispatch_mode(config.dispatch_mode);
+        client.set_slow_consumer_threshold(config.slow_consumer_threshold);
+        client.set_fragmentation(config.max_fragment_size);
+        client.set_max_message_size(config.max_message_size);
+        client.set_framing(config.framing.clone());
+        client.set_error_policy(config.error_policy);
+        client.set_reconnect_policy(config.reconnect_policy);
+        client.set_failure_threshold(config.failure_threshold);
+        client.set_memory_budget(config.memory_budget);
+
+        if let Some(journal) = &config.journal {
+            client.set_journal(Some(journal.clone()))?;
+        }
+
+        if let Some(identity) = &config.identity {
+            client.set_identity(Some(identity.clone()))?;
+        }
+
+        Ok(client)
+    }
+
+    // Connects over a custom transport instead of TCP, e.g. a
+    // MemoryTransport in tests. The connector is called again on reconnect
+    // and the address is only reported by active_address.
+    pub fn from_transport<F, T>(address: &str, connect: F) -> Result<Self, Error>
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+        T: Transport + 'static,
+    {
+        Self::open(vec![Endpoint {
+            address: address.to_string(),
+            connect: Box::new(move || Ok(Box::new(connect()?) as Box<dyn Transport>)),
+            lookup: None,
+        }])
+    }
+
+    // Connects over TCP with the whole stream obfuscated, headers included,
+    // and a fresh salt for every connection. It only keeps the framing from
+    // being recognized and is no substitute for encryption.
+    pub fn connect_obfuscated(
+        address: &str,
+        obfuscation: Arc<dyn Obfuscation>,
+    ) -> Result<Self, Error> {
+        let target = address.to_string();
+
+        Self::from_transport(address, move || {
+            ObfuscatedTransport::new(TcpStream::connect(&target)?, obfuscation.clone())
+        })
+    }
+
+    // Connects to a local named pipe such as \\.\pipe\name, with the
+    // same framing and callbacks as TCP. Reconnects reopen the pipe.
+    #[cfg(windows)]
+    pub fn connect_pipe(path: &str) -> Result<Self, Error> {
+        let pipe_path = path.to_string();
+
+        Self::from_transport(path, move || crate::PipeTransport::connect(&pipe_path))
+    }
+
+    // Returns at once, before connecting, so callbacks and settings can be
+    // set up while the server may still be starting. The connection opens
+    // on the first send or ensure_connected; until then the receive loop
+    // idles.
+    pub fn connect_lazy(address: &str) -> Self {
+        Self::wrap(TcpClientData::new_lazy(vec![Endpoint::tcp(
+            address.to_string(),
+            None,
+            None,
+            None,
+            Arc::new(SystemResolver),
+        )]))
+    }
+
+    // Opens the connection of a lazy client if it isn't open yet, trying
+    // each address once. Fails with the connect error, and can be called
+    // again later.
+    pub fn ensure_connected(&self) -> Result<(), Error> {
+        self.data.ensure_connected()
+    }
+
+    fn connect_with(
+        addresses: Vec<String>,
+        connect_timeout: Option<Duration>,
+        handshake_timeout: Option<Duration>,
+        local: Option<LocalBind>,
+        resolver: Arc<dyn Resolver>,
+    ) -> Result<Self, Error> {
+        Self::open(
+            addresses
+                .into_iter()
+                .map(|address| {
+                    Endpoint::tcp(
+                        address,
+                        connect_timeout,
+                        handshake_timeout,
+                        local,
+                        resolver.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn open(endpoints: Vec<Endpoint>) -> Result<Self, Error> {
+        let data = TcpClientData::new(endpoints);
+
+        match data {
+            Ok(data) => Ok(Self::wrap(data)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn wrap(data: TcpClientData) -> Self {
+        Self {
+            data: Arc::new(data),
+            nonblocking: true,
+            receive_thread: Mutex::new(None),
+            pump: Mutex::new(Pump::NotStarted),
+        }
+    }
+
+    // Resolves every TCP endpoint ahead of time so that reconnecting skips
+    // the DNS lookup. The connection itself is already open once the client
+    // exists. Fails with the first lookup error, after trying them all.
+    pub fn prewarm(&self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for endpoint in &self.data.endpoints {
+            let lookup = match &endpoint.lookup {
+                Some(lookup) => lookup,
+                None => continue,
+            };
+
+            match lookup.resolver.resolve(&endpoint.address) {
+                Ok(addresses) => match lookup.resolved.lock() {
+                    Ok(mut resolved) => *resolved = addresses,
+                    Err(e) => *e.into_inner() = addresses,
+                },
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(Error::Connect(e.into()));
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    pub(crate) fn downgrade(&self) -> Weak<TcpClientData> {
+        Arc::downgrade(&self.data)
+    }
+
+    // Returns an id for unwatch_connection.
+    pub(crate) fn watch_connection<F>(&self, watcher: F) -> u64
+    where
+        F: Fn(ConnectionChange) + Send + Sync + 'static,
+    {
+        let id = self.data.next_watcher_id.fetch_add(1, Ordering::SeqCst);
+
+        match self.data.connection_watchers.lock() {
+            Ok(mut watchers) => watchers.push((id, Arc::new(watcher))),
+            Err(e) => e.into_inner().push((id, Arc::new(watcher))),
+        }
+
+        id
+    }
+
+    pub(crate) fn unwatch_connection(&self, id: u64) {
+        match self.data.connection_watchers.lock() {
+            Ok(mut watchers) => watchers.retain(|(watcher, _)| *watcher != id),
+            Err(e) => e.into_inner().retain(|(watcher, _)| *watcher != id),
+        }
+    }
+
+    pub fn disconnect(&self) -> bool {
+        self.data.close()
+    }
+
+    // Stops accepting sends, gives the write queue up to timeout to drain,
+    // control frames first, and disconnects. Returns what was still queued;
+    // it is empty when the write queue is off.
+    pub fn disconnect_graceful(&self, timeout: Duration) -> QueueSnapshot {
+        let snapshot = match self.data.write_queue_state() {
+            Some(write_queue) => {
+                write_queue.close();
+                write_queue.wait_drained(timeout, &*self.data.clock());
+                write_queue.take_unsent()
+            }
+            None => QueueSnapshot::default(),
+        };
+
+        if let Err(e) = self.data.flush() {
+            self.data.report_error(&e);
+        }
+
+        self.data.close();
+        snapshot
+    }
+
+    // Takes the messages still waiting in the write queue, so that each is
+    // written once, by whichever client imports the state. One the writer
+    // has already taken is still written by this client.
+    pub fn export_state(&self) -> ClientState {
+        let pending = match self.data.write_queue_state() {
+            Some(write_queue) => write_queue.take_unsent(),
+            None => QueueSnapshot::default(),
+        };
+
+        // Layers fill in what they track, like sequence counters and
+        // subscriptions.
+        ClientState {
+            pending,
+            ..ClientState::default()
+        }
+    }
+
+    // Sends the pending messages of an exported state, control frames
+    // first, and returns their tickets in that order. They were wrapped by
+    // the old client's interceptors already, so they aren't wrapped again.
+    pub fn import_state(&self, state: &ClientState) -> Result<Vec<SendTicket>, Error> {
+        let control = state
+            .pending
+            .control
+            .iter()
+            .map(|message| (message, Lane::Control));
+        let data = state
+            .pending
+            .data
+            .iter()
+            .map(|message| (message, Lane::Data));
+
+        control
+            .chain(data)
+            .map(|(message, lane)| self.data.enqueue(message, lane))
+            .collect()
+    }
+
+    // Tells the client how long the server wants it to wait before
+    // reconnecting, as from a RetryAfter frame sent before a kick. The next
+    // reconnect attempt waits that long instead of following the reconnect
+    // policy; later attempts follow the policy again. An attempt that is
+    // already waiting keeps its delay. Until then the connection ending is
+    // reported as DisconnectReason::Kicked. KickClient does this for the
+    // servers that send kick notices.
+    pub fn retry_after(&self, delay: Duration) {
+        self.data
+            .announce_disconnect(DisconnectReason::Kicked, Some(delay));
+    }
+
+    // When the reconnect in progress makes its next attempt or, between
+    // reconnects, when a retry-after hint allows the next one. None when
+    // neither applies.
+    pub fn next_reconnect_at(&self) -> Option<Instant> {
+        let next_reconnect_at = match self.data.next_reconnect_at.lock() {
+            Ok(next_reconnect_at) => *next_reconnect_at,
+            Err(e) => *e.into_inner(),
+        };
+
+        next_reconnect_at.or_else(|| match self.data.retry_after.lock() {
+            Ok(retry_after) => *retry_after,
+            Err(e) => *e.into_inner(),
+        })
+    }
+
+    pub fn reconnect(&self) -> Result<(), Error> {
+        if self.data.is_closed() {
+            return Err(Error::Closed);
+        }
+
+        self.data.reconnect(self.data.connection().generation)
+    }
+
+    pub fn send<T>(&self, data: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.data.send(data.as_ref(), Lane::Data)
+    }
+
+    // Sends a control frame such as an ack, auth or goodbye. With the write
+    // queue enabled it is written ahead of all queued data, so it still
+    // gets out first after a reconnect or during disconnect_graceful.
+    pub fn send_control<T>(&self, data: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.data.send(data.as_ref(), Lane::Control)
+    }
+
+    // Writes the message on the calling thread, ahead of anything waiting
+    // in the write queue, and reports (bytes written, total bytes) after
+    // every socket write. Both count frame headers as well as the payload.
+    // After a reconnect the message is rewritten and progress starts over.
+    pub fn send_with_progress<T, F>(&self, data: T, progress: F) -> Result<(), Error>
+    where
+        T: AsRef<[u8]>,
+        F: Fn(usize, usize),
+    {
+        let interceptors = load_callback(&self.data.interceptors);
+        let wrapped;
+
+        let data = if interceptors.is_empty() {
+            data.as_ref()
+        } else {
+            wrapped = wrap(&interceptors, data.as_ref())?;
+            &wrapped[..]
+        };
+
+        self.data.check_message_size(data.len())?;
+
+        if self.data.is_suspended() {
+            return Err(Error::Suspended);
+        }
+
+        self.data.write_message_with_progress(data, Some(&progress))
+    }
+
+    pub fn send_str(&self, text: &str) -> Result<SendTicket, Error> {
+        self.send(text)
+    }
+
+    pub fn receive(&self) -> Result<(), Error> {
+        if self
+            .data
+            .receiving
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(Error::ReceiveLoopRunning);
+        }
+
+        // A loop stopped by a callback panic left the stop flag set; only a
+        // close keeps new loops from running.
+        if !self.data.is_closed() {
+            self.data.stop_receiving.store(false, Ordering::SeqCst);
+        }
+
+        let data_ref = self.data.clone();
+        let (done_sender, done_receiver) = channel::<()>();
+        let handle = thread::spawn(move || {
+            let data = Arc::downgrade(&data_ref);
+
+            supervise(&data, IoThread::Receive, || {
+                data_ref.clone().run_receive_loop();
+
+                if data_ref.is_closed() {
+                    "Connection closed".to_string()
+                } else {
+                    "Receive loop stopped".to_string()
+                }
+            });
+
+            let _ = done_sender.send(());
+        });
+
+        if let Ok(mut receive_thread) = self.receive_thread.lock() {
+            *receive_thread = Some((handle.thread().id(), done_receiver));
+        }
+
+        Ok(())
+    }
+
+    // Switches the client to manual pump mode for game loops and embedded
+    // event loops: instead of spawning threads, write coalescing and the
+    // MQTT keep-alive run their periodic work from poll. Enable it before
+    // either is set up; threads that are already running keep running. The
+    // write queue and the threaded dispatch modes still use threads.
+    pub fn enable_manual_pump(&mut self) {
+        self.data.manual_pump.store(true, Ordering::SeqCst);
+    }
+
+    // Does one tick of work on the calling thread, in place of the receive
+    // thread: runs the periodic work that is due, then reads and dispatches
+    // until the socket has nothing more, or up to a bound so a busy
+    // connection can't stall the caller. Reconnects happen inline and can
+    // block for the reconnect policy's backoff. Fails with
+    // ReceiveLoopRunning once receive has been called, and receive fails
+    // after the first poll. Calls from inside a callback do nothing.
+    pub fn poll(&self) -> Result<(), Error> {
+        if self.data.is_closed() {
+            return Err(Error::Closed);
+        }
+
+        let mut pump = match self.pump.try_lock() {
+            Ok(pump) => pump,
+            Err(TryLockError::WouldBlock) => return Ok(()),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+
+        if let Pump::NotStarted = *pump {
+            if self
+                .data
+                .receiving
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                return Err(Error::ReceiveLoopRunning);
+            }
+
+            *pump = Pump::Running(Box::new(ReceiveLoop::new(self.data.clone())));
+        }
+
+        self.data
+            .timers
+            .run_due(&self.data, self.data.clock().now());
+
+        let receive_loop = match &mut *pump {
+            Pump::Running(receive_loop) => receive_loop,
+            _ => return Ok(()),
+        };
+
+        for _ in 0..MAX_POLL_STEPS {
+            match receive_loop.step() {
+                Step::Progressed => {}
+                Step::Idle(_) => break,
+                Step::Stopped => {
+                    if let Pump::Running(receive_loop) =
+                        std::mem::replace(&mut *pump, Pump::Finished)
+                    {
+                        receive_loop.finish();
+                    }
+
+                    return Ok(());
+                }
+            }
+        }
+
+        receive_loop.flush_batch();
+        Ok(())
+    }
+
+    // For an existing epoll or kqueue loop in manual pump mode: register
+    // as_raw_fd for readiness, call on_readable and on_writable when the
+    // loop reports them, and wake up after next_timeout at the latest. On
+    // reconnect the descriptor changes, so register it again from
+    // on_reconnected.
+    pub fn on_readable(&self) -> Result<(), Error> {
+        self.poll()
+    }
+
+    // Writes out frames held back by write coalescing. Direct sends write
+    // as they are made, so there is nothing else waiting for the socket.
+    pub fn on_writable(&self) -> Result<(), Error> {
+        self.data.flush()
+    }
+
+    // How long until poll has periodic work due, such as a coalescing
+    // flush or an MQTT keep-alive; None when there is none.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let now = self.data.clock().now();
+
+        self.data
+            .timers
+            .next_due()
+            .map(|due| due.saturating_duration_since(now))
+    }
+
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> Result<std::os::unix::io::RawFd, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        self.with_tcp(|socket| Ok(socket.as_raw_fd()))
+    }
+
+    #[cfg(windows)]
+    pub fn as_raw_socket(&self) -> Result<std::os::windows::io::RawSocket, Error> {
+        use std::os::windows::io::AsRawSocket;
+
+        self.with_tcp(|socket| Ok(socket.as_raw_socket()))
+    }
+
+    // Pauses IO while keeping every setting, callback and queued write. The
+    // receive loop idles and, with release_socket, the connection is shut
+    // down too. Direct sends fail with Error::Suspended until resume; queued
+    // sends wait for it.
+    pub fn suspend(&self, release_socket: bool) {
+        if self.data.is_closed() || self.data.suspended.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if release_socket {
+            self.data.socket_released.store(true, Ordering::SeqCst);
+            let _ = self.data.socket().shutdown(Shutdown::Both);
+        }
+    }
+
+    // Reconnects according to the reconnect policy if the socket was
+    // released.
+    pub fn resume(&self) -> Result<(), Error> {
+        if self.data.is_closed() {
+            return Err(Error::Closed);
+        }
+
+        if !self.data.is_suspended() {
+            return Ok(());
+        }
+
+        if self.data.socket_released.load(Ordering::SeqCst) {
+            self.data.reconnect(self.data.connection().generation)?;
+            self.data.socket_released.store(false, Ordering::SeqCst);
+        }
+
+        self.data.suspended.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.data.is_suspended()
+    }
+
+    // The configured address the current connection was made to.
+    pub fn active_address(&self) -> &str {
+        &self.data.endpoints[self.data.active_endpoint.load(Ordering::SeqCst)].address
+    }
+
+    // Socket options are only available when the transport is TCP.
+    fn with_tcp<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&TcpStream) -> io::Result<R>,
+    {
+        match self.data.socket().as_tcp() {
+            Some(socket) => Ok(f(socket)?),
+            None => Err(Error::Socket(
+                io::Error::new(io::ErrorKind::Unsupported, "Transport is not TCP").into(),
+            )),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.with_tcp(TcpStream::peer_addr)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.with_tcp(TcpStream::local_addr)
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>, Error> {
+        self.with_tcp(TcpStream::read_timeout)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.with_tcp(|socket| socket.set_read_timeout(timeout))
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>, Error> {
+        self.with_tcp(TcpStream::write_timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.with_tcp(|socket| socket.set_write_timeout(timeout))
+    }
+
+    pub fn nodelay(&self) -> Result<bool, Error> {
+        self.with_tcp(TcpStream::nodelay)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        self.with_tcp(|socket| socket.set_nodelay(nodelay))
+    }
+
+    pub fn ttl(&self) -> Result<u32, Error> {
+        self.with_tcp(TcpStream::ttl)
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        self.with_tcp(|socket| socket.set_ttl(ttl))
+    }
+
+    #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+    pub fn tcp_info(&self) -> Result<crate::TcpInfo, Error> {
+        self.with_tcp(crate::tcp_info::tcp_info)
+    }
+
+    pub fn take_error(&self) -> Result<Option<SocketError>, Error> {
+        Ok(self.with_tcp(TcpStream::take_error)?.map(SocketError::from))
+    }
+
+    // The recent connection attempts and sessions, for bug reports.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.data.diagnostics.snapshot()
+    }
+
+    pub fn messages(&self) -> Messages {
+        Messages::new(self.data.open_message_stream().1)
+    }
+
+    // Blocks until the next message arrives, starting the receive loop if
+    // needed, and returns None on timeout. Messages that arrive between two
+    // calls only reach the callback; use messages() to see every one. In
+    // manual pump mode it calls poll until then instead, so it must not be
+    // called while another thread is polling.
+    pub fn wait_for_message(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        let (id, messages) = self.data.open_message_stream();
+
+        let result = if self.data.is_manual_pump() {
+            self.poll_for_message(&messages, timeout)
+        } else {
+            // Fails only when the receive loop is already running, which is
+            // fine since the stream above is fed by it.
+            let _ = self.receive();
+
+            match messages.recv_timeout(timeout) {
+                Ok(message) => Ok(Some(message)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Err(Error::Closed),
+            }
+        };
+
+        self.data.close_message_stream(id);
+        result
+    }
+
+    fn poll_for_message(
+        &self,
+        messages: &Receiver<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            self.poll()?;
+
+            match messages.try_recv() {
+                Ok(message) => return Ok(Some(message)),
+                Err(TryRecvError::Disconnected) => return Err(Error::Closed),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            thread::sleep(remaining.min(MANUAL_WAIT_INTERVAL));
+        }
+    }
+
+    // Expects the server to echo messages back unchanged. Probes that take
+    // longer than a second count as lost.
+    pub fn run_latency_test(
+        &self,
+        count: usize,
+        payload_size: usize,
+    ) -> Result<LatencyReport, Error> {
+        self.run_latency_test_timeout(count, payload_size, Duration::from_secs(1))
+    }
+
+    pub fn run_latency_test_timeout(
+        &self,
+        count: usize,
+        payload_size: usize,
+        timeout: Duration,
+    ) -> Result<LatencyReport, Error> {
+        crate::latency::run_latency_test(self, count, payload_size, timeout)
+    }
+
+    pub fn sink(&self) -> MessageSink {
+        MessageSink::new(self.data.clone())
+    }
+
+    pub fn frame_size_histogram(&self) -> FrameSizeHistogram {
+        match self.data.frame_sizes.lock() {
+            Ok(frame_sizes) => frame_sizes.clone(),
+            Err(_) => FrameSizeHistogram::new(),
+        }
+    }
+
+    pub fn is_peer_write_closed(&self) -> bool {
+        self.data.peer_closed_write.load(Ordering::SeqCst)
+    }
+
+    pub fn congestion(&self) -> CongestionSignal {
+        match self.data.congestion.lock() {
+            Ok(congestion) => congestion.signal(),
+            Err(e) => e.into_inner().signal(),
+        }
+    }
+
+    pub fn set_congestion_threshold(&mut self, threshold: Duration) {
+        if let Ok(mut congestion) = self.data.congestion.lock() {
+            congestion.set_threshold(threshold);
+        }
+    }
+
+    pub fn set_send_pacing(&mut self, pacing: bool) {
+        if let Ok(mut congestion) = self.data.congestion.lock() {
+            congestion.set_pacing(pacing);
+        }
+    }
+
+    pub fn set_clock<C>(&mut self, clock: C)
+    where
+        C: Clock + 'static,
+    {
+        if let Ok(mut current) = self.data.clock.lock() {
+            *current = Arc::new(clock);
+        }
+    }
+
+    pub fn set_dispatch_mode(&mut self, mode: DispatchMode) {
+        if let Ok(mut dispatch_mode) = self.data.dispatch_mode.lock() {
+            *dispatch_mode = mode;
+        }
+    }
+
+    // Takes effect for the receive loop when it starts. Fragmentation only
+    // applies to length-prefixed framing.
+    pub fn set_framing(&mut self, framing: Framing) {
+        let framing = match framing {
+            Framing::Delimited {
+                delimiter,
+                max_length,
+            } if delimiter.is_empty() => Framing::Delimited {
+                delimiter: b"\n".to_vec(),
+                max_length,
+            },
+            framing => framing,
+        };
+
+        match self.data.framing.lock() {
+            Ok(mut current) => *current = framing,
+            Err(e) => *e.into_inner() = framing,
+        }
+    }
+
+    // Both peers must agree on this setting: with it enabled, every frame
+    // carries a fragment header and payloads longer than the given size are
+    // split across several frames.
+    pub fn set_fragmentation(&mut self, max_fragment_size: Option<usize>) {
+        let max_fragment_size = match max_fragment_size {
+            Some(size) => size.max(1),
+            None => 0,
+        };
+
+        self.data
+            .max_fragment_size
+            .store(max_fragment_size, Ordering::SeqCst);
+    }
+
+    // Applies in both directions: send rejects larger messages up front, and
+    // a larger incoming frame is reported and closes the connection.
+    pub fn set_max_message_size(&mut self, max_message_size: Option<usize>) {
+        self.data
+            .max_message_size
+            .store(max_message_size.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    // With the write queue enabled, send only enqueues the message and a
+    // dedicated writer thread performs the socket writes; the returned ticket
+    // reports when the write actually completed.
+    pub fn set_write_queue(&mut self, enabled: bool) {
+        let write_queue = if enabled {
+            Some(spawn_writer(Arc::downgrade(&self.data)))
+        } else {
+            None
+        };
+
+        match self.data.write_queue.lock() {
+            Ok(mut current) => *current = write_queue,
+            Err(e) => *e.into_inner() = write_queue,
+        }
+    }
+
+    // Buffers outgoing frames and writes them in batches. Turning coalescing
+    // off, or changing it, flushes what is buffered first. Write errors of a
+    // batch surface on the send that filled it, or through the error
+    // callback when the timer flushes.
+    pub fn set_write_coalescing(
+        &mut self,
+        coalescing: Option<WriteCoalescing>,
+    ) -> Result<(), Error> {
+        let result = self.data.flush();
+        let generation = self
+            .data
+            .coalescing_generation
+            .fetch_add(1, Ordering::SeqCst)
+            + 1;
+
+        match self.data.coalescing.lock() {
+            Ok(mut current) => *current = coalescing,
+            Err(e) => *e.into_inner() = coalescing,
+        }
+
+        if let Some(coalescing) = coalescing {
+            spawn_flusher(Arc::downgrade(&self.data), coalescing.max_delay, generation);
+        }
+
+        result
+    }
+
+    // Sends the identity now and again first thing after every reconnect.
+    pub fn set_identity(&mut self, identity: Option<ClientIdentity>) -> Result<(), Error> {
+        let frame = match identity {
+            Some(identity) => Some(identity.encode()?),
+            None => None,
+        };
+
+        match self.data.identity.lock() {
+            Ok(mut current) => *current = frame.clone(),
+            Err(e) => *e.into_inner() = frame.clone(),
+        }
+
+        // A lazy client sends it once it connects.
+        match frame {
+            Some(_) if self.data.connect_pending.load(Ordering::SeqCst) => Ok(()),
+            Some(frame) => self.data.write_message(&frame),
+            None => Ok(()),
+        }
+    }
+
+    // Writes out frames buffered by write coalescing right away.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.data.flush()
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        match self.data.error_policy.lock() {
+            Ok(mut current) => *current = policy,
+            Err(e) => *e.into_inner() = policy,
+        }
+    }
+
+    // With a threshold set, a connection whose reads keep failing is
+    // declared dead once it is reached: on_connection_failed runs and the
+    // client reconnects, closing if that fails, instead of following the
+    // read error action.
+    pub fn set_failure_threshold(&mut self, threshold: Option<FailureThreshold>) {
+        match self.data.failure_threshold.lock() {
+            Ok(mut current) => *current = threshold,
+            Err(e) => *e.into_inner() = threshold,
+        }
+    }
+
+    pub fn set_memory_budget(&mut self, budget: Option<MemoryBudget>) {
+        self.data.memory.set_budget(budget);
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.data.memory_usage()
+    }
+
+    // Applies to every message sent or received from now on, except the
+    // identity frame, which the server reads before any envelope.
+    pub fn add_interceptor<I>(&mut self, interceptor: I)
+    where
+        I: Interceptor + 'static,
+    {
+        let mut interceptors = load_callback(&self.data.interceptors).to_vec();
+        interceptors.push(Arc::new(interceptor));
+
+        store_callback(&self.data.interceptors, Arc::new(interceptors));
+    }
+
+    pub fn clear_interceptors(&mut self) {
+        store_callback(&self.data.interceptors, Arc::new(Vec::new()));
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        match self.data.reconnect_policy.lock() {
+            Ok(mut current) => *current = policy,
+            Err(e) => *e.into_inner() = policy,
+        }
+    }
+
+    // Applies to threads started afterwards: call before receive and
+    // set_write_queue.
+    pub fn set_thread_hints(&mut self, hints: ThreadHints) {
+        match self.data.thread_hints.lock() {
+            Ok(mut current) => *current = hints,
+            Err(e) => *e.into_inner() = hints,
+        }
+    }
+
+    // Records every message sent and received, before fragmentation and
+    // after reassembly. Read it back with JournalReader.
+    pub fn set_journal(&mut self, config: Option<JournalConfig>) -> Result<(), Error> {
+        let journal = match config {
+            Some(config) => match Journal::open(config) {
+                Ok(journal) => Some(journal),
+                Err(e) => return Err(Error::Journal(e.to_string())),
+            },
+            None => None,
+        };
+
+        match self.data.journal.lock() {
+            Ok(mut current) => *current = journal,
+            Err(e) => *e.into_inner() = journal,
+        }
+
+        Ok(())
+    }
+
+    // Reads length-prefixed frames into a reusable buffer of buffer_size
+    // bytes and parses every complete frame out of each read, instead of
+    // reading each frame into a buffer of its own size. Pays off for many
+    // small frames. Takes effect when receive or poll starts.
+    pub fn set_bulk_reads(&mut self, buffer_size: Option<usize>) {
+        self.data
+            .bulk_read_size
+            .store(buffer_size.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    pub fn set_stop_on_callback_panic(&mut self, stop: bool) {
+        self.data
+            .stop_on_callback_panic
+            .store(stop, Ordering::SeqCst);
+    }
+
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_message_received, Arc::new(callback));
+    }
+
+    // Holds the target only weakly, so an application object that owns the
+    // client can receive its messages without an Arc cycle keeping both
+    // alive. Once the target is dropped the callback unregisters itself.
+    pub fn set_on_message_received_weak<T, F>(&mut self, target: Weak<T>, callback: F)
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&T, &[u8]) + Send + Sync + 'static,
+    {
+        let slot = Arc::downgrade(&self.data.on_message_received);
+
+        self.set_on_message_received(move |data| match target.upgrade() {
+            Some(target) => callback(&target, data),
+            None => {
+                if let Some(slot) = slot.upgrade() {
+                    store_callback(&slot, Arc::new(|_| {}));
+                }
+            }
+        });
+    }
+
+    // Callbacks run with the handler's lock released, so the slot can be
+    // set from inside on_message_received without deadlocking.
+    pub fn message_handler_slot(&self) -> MessageHandlerSlot {
+        MessageHandlerSlot {
+            slot: Arc::downgrade(&self.data.on_message_received),
+        }
+    }
+
+    // Delivers in batches instead of one message at a time: every frame
+    // parsed from one socket read arrives in a single call, which spares
+    // per-message overhead on high-frequency feeds. Replaces
+    // on_message_received, and takes effect for the receive loop when it
+    // starts. Bulk reads give the biggest batches.
+    pub fn set_on_batch_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[Vec<u8>]) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_batch_received, Arc::new(callback));
+        self.data.batching.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_on_text_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        let on_error = self.data.on_error.clone();
+
+        self.set_on_message_received(move |data| match std::str::from_utf8(data) {
+            Ok(text) => callback(text),
+            Err(e) => load_callback(&on_error)(&Error::InvalidUtf8(e)),
+        });
+    }
+
+    pub fn set_on_error<F>(&mut self, callback: F)
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_error, Arc::new(callback));
+    }
+
+    pub fn set_on_peer_closed_write<F>(&mut self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_peer_closed_write, Arc::new(callback));
+    }
+
+    // Called when the receive loop gives up a connection, on end of stream,
+    // a read error that closes or reconnects, or a frame it can't accept,
+    // with why it ended and the raw bytes of the frame it was cut off in,
+    // header included. The flag tells whether the stream ended mid-frame;
+    // it is false, and the bytes empty, when it ended cleanly between
+    // frames.
+    pub fn set_on_connection_closed<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8], bool, DisconnectReason) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_connection_closed, Arc::new(callback));
+    }
+
+    // Decides, from why the connection ended, whether a read or write error
+    // policy that reconnects should do so; returning false closes instead.
+    // Reconnects by default.
+    pub fn set_reconnect_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(DisconnectReason) -> bool + Send + Sync + 'static,
+    {
+        store_callback(&self.data.reconnect_filter, Arc::new(filter));
+    }
+
+    // Why the last connection ended, also kept in the session history.
+    pub fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.data.last_disconnect_reason()
+    }
+
+    // Called with the last read error when the failure threshold declares
+    // the connection dead, before reconnecting.
+    pub fn set_on_connection_failed<F>(&mut self, callback: F)
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_connection_failed, Arc::new(callback));
+    }
+
+    pub fn set_on_reconnected<F>(&mut self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_reconnected, Arc::new(callback));
+    }
+
+    pub fn set_slow_consumer_threshold(&mut self, threshold: Duration) {
+        if let Ok(mut slow_consumer_threshold) = self.data.slow_consumer_threshold.lock() {
+            *slow_consumer_threshold = threshold;
+        }
+    }
+
+    pub fn set_on_slow_consumer<F>(&mut self, callback: F)
+    where
+        F: Fn(Duration) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_slow_consumer, Arc::new(callback));
+    }
+
+    // Reports when the receive loop, the write queue's writer and the write
+    // coalescing timer start, stop or panic.
+    pub fn set_on_thread_event<F>(&mut self, callback: F)
+    where
+        F: Fn(&ThreadEvent) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.on_thread_event, Arc::new(callback));
+    }
+
+    // Called with the raw bytes of every successful socket read and write,
+    // framing included, for packet capture or hex dumps. Runs on the IO path,
+    // so it should be quick.
+    pub fn set_wire_tap<F>(&mut self, callback: F)
+    where
+        F: Fn(Direction, &[u8]) + Send + Sync + 'static,
+    {
+        store_callback(&self.data.wire_tap, Arc::new(callback));
+    }
+}
+
+// The receive loop's state, advanced one read or one frame at a time so
+// that both the receive thread and poll can drive it.
+struct ReceiveLoop {
+    data: Arc<TcpClientData>,
+    dispatcher: Dispatcher,
+    framing: Framing,
+    connection: Connection,
+    reassembler: Reassembler,
+    decoder: Option<StreamDecoder>,
+    chunk: Vec<u8>,
+    buffer: Vec<u8>,
+    read_bytes: usize,
+    // In bulk mode the buffer holds several frames; those before start
+    // have been delivered already.
+    bulk_size: usize,
+    start: usize,
+    consecutive_errors: u32,
+    // When the current run of read errors began.
+    failing_since: Option<Instant>,
+    // With batching on, frames collect here until the next read.
+    batch: Option<Vec<Vec<u8>>>,
+}
+
+enum Step {
+    Progressed,
+    // Nothing to do until the delay has passed.
+    Idle(Duration),
+    Stopped,
+}
+
+impl ReceiveLoop {
+    const HEADER_SIZE: usize = LENGTH_HEADER_SIZE;
+
+    fn new(data: Arc<TcpClientData>) -> Self {
+        let dispatch_mode = match data.dispatch_mode.lock() {
+            Ok(dispatch_mode) => *dispatch_mode,
+            Err(_) => DispatchMode::Inline,
+        };
+        let framing = data.framing();
+        let decoder = framing.decoder();
+        let bulk_size = data.bulk_read_size.load(Ordering::SeqCst);
+        let batching = data.batching.load(Ordering::SeqCst);
+
+        Self {
+            dispatcher: Dispatcher::new(dispatch_mode, data.clone()),
+            connection: data.connection(),
+            data,
+            framing,
+            reassembler: Reassembler::new(),
+            decoder,
+            chunk: vec![0; 4096],
+            buffer: vec![0; Self::HEADER_SIZE.max(bulk_size)],
+            read_bytes: 0,
+            bulk_size,
+            start: 0,
+            consecutive_errors: 0,
+            failing_since: None,
+            batch: batching.then(Vec::new),
+        }
+    }
+
+    fn step(&mut self) -> Step {
+        let data_ref = self.data.clone();
+        let data = data_ref.as_ref();
+        let header_size = Self::HEADER_SIZE;
+
+        if data.stop_receiving.load(Ordering::SeqCst) {
+            return Step::Stopped;
+        }
+
+        let current = data.connection();
+
+        // Whatever was buffered belongs to the socket that was replaced; the
+        // frames already complete are still delivered.
+        if current.generation != self.connection.generation {
+            self.flush_batch();
+            self.connection = current;
+            self.reassembler = Reassembler::new();
+            self.decoder = self.framing.decoder();
+            self.buffer.resize(header_size.max(self.bulk_size), 0);
+            self.read_bytes = 0;
+            self.start = 0;
+            self.consecutive_errors = 0;
+            self.failing_since = None;
+        }
+
+        if data.is_suspended() {
+            return Step::Idle(Duration::from_millis(50));
+        }
+
+        if self.decoder.is_none() && self.bulk_size > 0 {
+            if let Some(step) = self.take_bulk_frame() {
+                return step;
+            }
+        } else if self.decoder.is_none() && self.read_bytes >= header_size {
+            let arr: [u8; 8] = self.buffer[0..header_size].try_into().unwrap();
+            let amount_to_read = usize::from_le_bytes(arr);
+
+            if let Err(e) = Self::check_frame_length(data, amount_to_read)
+                .and_then(|_| self.check_buffer_memory(header_size + amount_to_read))
+            {
+                data.report_error(&e);
+                self.report_closed(data, DisconnectReason::ProtocolError);
+                data.close();
+                return Step::Stopped;
+            }
+
+            if self.buffer.len() != header_size + amount_to_read {
+                self.buffer.resize(header_size + amount_to_read, 0);
+            }
+
+            if self.read_bytes == header_size + amount_to_read {
+                let frame = &self.buffer[header_size..];
+                Self::deliver(
+                    data,
+                    &self.dispatcher,
+                    &mut self.reassembler,
+                    &mut self.batch,
+                    frame,
+                );
+
+                self.buffer.resize(header_size, 0);
+                self.read_bytes = 0;
+                return Step::Progressed;
+            }
+        }
+
+        self.flush_batch();
+        self.record_memory();
+
+        let socket = self.connection.socket.as_ref();
+        let result = match self.decoder {
+            Some(_) => socket.read(&mut self.chunk),
+            None => socket.read(&mut self.buffer[self.read_bytes..]),
+        };
+
+        if let Ok(size) = result {
+            if size > 0 {
+                match self.decoder.as_mut() {
+                    Some(decoder) => {
+                        data.record_io(Direction::Inbound, &self.chunk[..size]);
+
+                        let max_size = data.max_message_size.load(Ordering::SeqCst);
+                        let mut oversized = None;
+
+                        for message in decoder.push(&self.chunk[..size], max_size) {
+                            match message {
+                                Ok(message) => {
+                                    Self::emit(&self.dispatcher, &mut self.batch, &message)
+                                }
+                                Err(e @ Error::FrameTooLarge(_)) => oversized = Some(e),
+                                Err(e) => data.report_error(&e),
+                            }
+                        }
+
+                        let pending = decoder.pending_size();
+
+                        // Like an oversized length header, a message the
+                        // budget can't hold ends the connection before it
+                        // is buffered.
+                        let checked = match oversized {
+                            Some(e) => Err(e),
+                            None => self.check_decoder_memory(pending),
+                        };
+
+                        if let Err(e) = checked {
+                            data.report_error(&e);
+                            self.report_closed(data, DisconnectReason::ProtocolError);
+                            data.close();
+                            return Step::Stopped;
+                        }
+                    }
+                    None => {
+                        data.record_io(
+                            Direction::Inbound,
+                            &self.buffer[self.read_bytes..self.read_bytes + size],
+                        );
+                        self.read_bytes += size;
+                    }
+                }
+
+                self.consecutive_errors = 0;
+                self.failing_since = None;
+                return Step::Progressed;
+            }
+        }
+
+        if let Err(e) = &result {
+            match e.kind() {
+                io::ErrorKind::WouldBlock => {
+                    let delay = self.connection.socket.read_retry_delay();
+                    return Step::Idle(delay.unwrap_or(Duration::from_millis(100)));
+                }
+                io::ErrorKind::Interrupted => return Step::Progressed,
+                _ => {}
+            }
+        }
+
+        // A local close, a suspend or a reconnect from the write path also
+        // ends the old socket; none of them is the peer's doing.
+        if data.stop_receiving.load(Ordering::SeqCst)
+            || data.is_suspended()
+            || data.connection().generation != self.connection.generation
+        {
+            return Step::Progressed;
+        }
+
+        let policy = data.error_policy();
+        let action = match result {
+            Ok(_) => {
+                data.peer_closed_write.store(true, Ordering::SeqCst);
+
+                let on_peer_closed_write = load_callback(&data.on_peer_closed_write);
+                data.run_callback(|| on_peer_closed_write());
+                self.report_closed(data, data.peer_closed_reason());
+
+                match policy.peer_closed {
+                    ErrorAction::Continue | ErrorAction::Retry(_) => return Step::Stopped,
+                    action => action,
+                }
+            }
+            Err(e) => {
+                let error = Error::Socket(e.into());
+                data.report_error(&error);
+                self.consecutive_errors += 1;
+
+                let now = data.clock().now();
+                let failing_for =
+                    now.saturating_duration_since(*self.failing_since.get_or_insert(now));
+                let threshold = match data.failure_threshold.lock() {
+                    Ok(threshold) => *threshold,
+                    Err(e) => *e.into_inner(),
+                };
+
+                let action = match policy.read_error {
+                    _ if threshold
+                        .is_some_and(|t| t.is_reached(self.consecutive_errors, failing_for)) =>
+                    {
+                        let on_connection_failed = load_callback(&data.on_connection_failed);
+                        data.run_callback(|| on_connection_failed(&error));
+                        ErrorAction::Reconnect
+                    }
+                    ErrorAction::Retry(retries) if self.consecutive_errors > retries => {
+                        ErrorAction::Close
+                    }
+                    action => action,
+                };
+
+                if matches!(action, ErrorAction::Reconnect | ErrorAction::Close) {
+                    self.report_closed(data, data.disconnect_reason_for(&error));
+                }
+
+                action
+            }
+        };
+
+        match data.filter_reconnect(action) {
+            ErrorAction::Continue | ErrorAction::Retry(_) => {
+                return Step::Idle(Duration::from_millis(100));
+            }
+            ErrorAction::Reconnect => match data.reconnect(self.connection.generation) {
+                Ok(()) | Err(Error::Closed) => {}
+                Err(e) => {
+                    data.report_error(&e);
+                    data.close();
+                }
+            },
+            ErrorAction::Close => {
+                data.close();
+            }
+        }
+
+        Step::Progressed
+    }
+
+    fn report_closed(&self, data: &TcpClientData, reason: DisconnectReason) {
+        data.record_disconnect(reason);
+
+        let remaining = match &self.decoder {
+            Some(decoder) => decoder.remaining(),
+            None => &self.buffer[self.start..self.read_bytes],
+        };
+        let truncated = !remaining.is_empty();
+
+        let on_connection_closed = load_callback(&data.on_connection_closed);
+        data.run_callback(|| on_connection_closed(remaining, truncated, reason));
+        data.notify_watchers(ConnectionChange::Closed(reason));
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        let decoded = match &self.decoder {
+            Some(decoder) => decoder.remaining().len(),
+            None => 0,
+        };
+
+        self.buffer.len() + self.chunk.len() + decoded
+    }
+
+    fn record_memory(&self) {
+        self.data
+            .memory
+            .set_inbound(self.buffered_bytes(), self.reassembler.buffered());
+    }
+
+    // Whether growing the buffer to size bytes stays within the budget.
+    fn check_buffer_memory(&self, size: usize) -> Result<(), Error> {
+        let receive_buffer = self.buffered_bytes() - self.buffer.len() + size;
+
+        self.data.check_memory(MemoryUsage {
+            receive_buffer,
+            reassembly: self.reassembler.buffered(),
+            ..self.data.memory_usage()
+        })
+    }
+
+    // Framed decoders buffer a message until it is complete, so the budget
+    // is checked against the whole message they are waiting for.
+    fn check_decoder_memory(&self, pending: usize) -> Result<(), Error> {
+        self.data.check_memory(MemoryUsage {
+            receive_buffer: self.buffer.len() + self.chunk.len() + pending,
+            reassembly: self.reassembler.buffered(),
+            ..self.data.memory_usage()
+        })
+    }
+
+    // Delivers the next complete frame in the buffer, or otherwise makes
+    // room to read the rest of it. A frame larger than the bulk size grows
+    // the buffer until it has been delivered.
+    fn take_bulk_frame(&mut self) -> Option<Step> {
+        let data = self.data.as_ref();
+        let header_size = Self::HEADER_SIZE;
+        let available = self.read_bytes - self.start;
+        let mut needed = header_size;
+
+        if available >= header_size {
+            let header = &self.buffer[self.start..self.start + header_size];
+            let amount_to_read = usize::from_le_bytes(header.try_into().unwrap());
+
+            needed = header_size.saturating_add(amount_to_read);
+
+            if let Err(e) = Self::check_frame_length(data, amount_to_read)
+                .and_then(|_| self.check_buffer_memory(needed.max(self.bulk_size)))
+            {
+                data.report_error(&e);
+                self.report_closed(data, DisconnectReason::ProtocolError);
+                data.close();
+                return Some(Step::Stopped);
+            }
+
+            if available >= needed {
+                let frame = &self.buffer[self.start + header_size..self.start + needed];
+                Self::deliver(
+                    data,
+                    &self.dispatcher,
+                    &mut self.reassembler,
+                    &mut self.batch,
+                    frame,
+                );
+
+                self.start += needed;
+
+                if self.start == self.read_bytes {
+                    self.start = 0;
+                    self.read_bytes = 0;
+                }
+
+                return Some(Step::Progressed);
+            }
+        }
+
+        // Moves the partial frame to the front, leaving the rest of the
+        // buffer free for the next read.
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.read_bytes, 0);
+            self.read_bytes -= self.start;
+            self.start = 0;
+        }
+
+        self.buffer.resize(needed.max(self.bulk_size), 0);
+        None
+    }
+
+    // The rest of the stream can't be trusted after an oversized header, so
+    // callers close the connection when this fails.
+    fn check_frame_length(data: &TcpClientData, length: usize) -> Result<(), Error> {
+        let fragment_overhead = match data.max_fragment_size.load(Ordering::SeqCst) {
+            0 => 0,
+            _ => FRAGMENT_HEADER_SIZE,
+        };
+
+        data.check_message_size(length.saturating_sub(fragment_overhead))
+    }
+
+    fn deliver(
+        data: &TcpClientData,
+        dispatcher: &Dispatcher,
+        reassembler: &mut Reassembler,
+        batch: &mut Option<Vec<Vec<u8>>>,
+        frame: &[u8],
+    ) {
+        if data.max_fragment_size.load(Ordering::SeqCst) == 0 {
+            Self::emit(dispatcher, batch, frame);
+            return;
+        }
+
+        match reassembler.push(frame) {
+            Ok(Some(message)) => match data.check_message_size(message.len()) {
+                Ok(()) => Self::emit(dispatcher, batch, &message),
+                Err(e) => data.report_error(&e),
+            },
+            // Partial messages past the budget are dropped rather than
+            // held on to.
+            Ok(None) => {
+                let usage = MemoryUsage {
+                    reassembly: reassembler.buffered(),
+                    ..data.memory_usage()
+                };
+
+                if let Err(e) = data.check_memory(usage) {
+                    reassembler.clear();
+                    data.report_error(&e);
+                }
+            }
+            Err(e) => data.report_error(&e),
+        }
+    }
+
+    fn emit(dispatcher: &Dispatcher, batch: &mut Option<Vec<Vec<u8>>>, message: &[u8]) {
+        match batch {
+            Some(batch) => batch.push(message.to_vec()),
+            None => dispatcher.dispatch(message),
+        }
+    }
+
+    fn flush_batch(&mut self) {
+        if let Some(batch) = &mut self.batch {
+            if !batch.is_empty() {
+                self.dispatcher.dispatch_batch(std::mem::take(batch));
+            }
+        }
+    }
+
+    // Lets the dispatcher drain queued frames before closing the message
+    // streams, so iterators see every frame that was read. However the loop
+    // ended, receive can start a new one afterwards.
+    fn finish(mut self) {
+        self.flush_batch();
+        self.data.memory.set_inbound(0, 0);
+
+        let data = self.data.clone();
+        drop(self);
+        data.end_message_streams();
+        data.receiving.store(false, Ordering::SeqCst);
+    }
+}
+
+// The socket of a lazy client before it connects: reads find nothing and
+// writes fail.
+struct Unconnected;
+
+impl Transport for Unconnected {
+    fn read(&self, _buffer: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn write(&self, _data: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct FrameWriter<'a, 'p> {
+    client: &'a TcpClientData,
+    socket: &'a dyn Transport,
+    clock: &'a dyn Clock,
+    retries: u32,
+    stalled: Duration,
+    // Bytes of these parts written so far.
+    written: usize,
+    progress: Option<&'a mut Progress<'p>>,
+}
+
+const PROGRESS_CHUNK_SIZE: usize = 64 * 1024;
+
+// Bytes of a message written so far, frame headers included.
+struct Progress<'a> {
+    callback: &'a dyn Fn(usize, usize),
+    sent: usize,
+    total: usize,
+}
+
+impl FrameWriter<'_, '_> {
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        let socket = self.socket;
+        let mut written: usize = 0;
+
+        while written < data.len() {
+            // A blocking socket may take a whole multi-megabyte part in one
+            // write, so writes are capped to report progress along the way.
+            let end = match self.progress {
+                Some(_) => data.len().min(written + PROGRESS_CHUNK_SIZE),
+                None => data.len(),
+            };
+
+            // A transport that takes no bytes would otherwise be retried
+            // forever, so it fails like any other write error.
+            let result = match socket.write(&data[written..end]) {
+                Ok(0) => Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "Transport accepted no bytes",
+                )),
+                result => result,
+            };
+
+            match result {
+                Ok(size) => {
+                    self.client
+                        .record_io(Direction::Outbound, &data[written..written + size]);
+                    written += size;
+                    self.written += size;
+
+                    if let Some(progress) = &mut self.progress {
+                        progress.sent += size;
+                        let (sent, total) = (progress.sent, progress.total);
+                        self.client
+                            .run_callback(|| (progress.callback)(sent, total));
+                    }
+                }
+                Err(e) => self.handle_error(e)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        loop {
+            match self.socket.flush() {
+                Ok(()) => return Ok(()),
+                Err(e) => self.handle_error(e)?,
+            }
+        }
+    }
+
+    // Returns the error when the write should be given up on.
+    fn handle_error(&mut self, e: io::Error) -> Result<(), Error> {
+        // Interrupted writes are retried right away and don't count against
+        // the retry budget.
+        if e.kind() == io::ErrorKind::Interrupted {
+            Ok(())
+        } else if e.kind() == io::ErrorKind::WouldBlock {
+            let delay = self
+                .socket
+                .write_retry_delay()
+                .unwrap_or(Duration::from_millis(50));
+            let started = self.clock.now();
+            self.clock.sleep(delay);
+            self.stalled += self.clock.now().saturating_duration_since(started);
+            Ok(())
+        } else if ErrorReason::from_kind(e.kind()).is_disconnect() {
+            Err(Error::Disconnected(e.into()))
+        } else if self.retries > 0 {
+            self.retries -= 1;
+            Ok(())
+        } else {
+            Err(Error::Socket(e.into()))
+        }
+    }
+}
+
+// A MessageSink keeps the client data alive but not the connection: once the
+// TcpClient is dropped its sends fail.
+impl Drop for TcpClient {
+    fn drop(&mut self) {
+        let _ = self.data.flush();
+        self.data.close();
+
+        let receive_thread = match self.receive_thread.get_mut() {
+            Ok(receive_thread) => receive_thread.take(),
+            Err(e) => e.into_inner().take(),
+        };
+
+        // A client dropped from one of its own callbacks can't wait for the
+        // loop it is running on.
+        if let Some((thread_id, done)) = receive_thread {
+            if thread_id != thread::current().id() {
+                let _ = done.recv_timeout(DROP_JOIN_TIMEOUT);
+            }
+        }
+    }
+}
+
+// Callbacks are cloned out of their slot before being called, and a poisoned
+// slot is recovered, so a panicking user callback never wedges the client.
+fn load_callback<T: ?Sized>(slot: &Mutex<Arc<T>>) -> Arc<T> {
+    match slot.lock() {
+        Ok(callback) => callback.clone(),
+        Err(e) => e.into_inner().clone(),
+    }
+}
+
+fn store_callback<T: ?Sized>(slot: &Mutex<Arc<T>>, callback: Arc<T>) {
+    match slot.lock() {
+        Ok(mut current) => *current = callback,
+        Err(e) => *e.into_inner() = callback,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FlakyTransport;
+    use crate::testing::{
+        client_over, instant_reconnects, read_frame, wait_until, within, write_frame, TIMEOUT,
+    };
+    use crate::{BudgetAction, MemoryTransport};
+
+    const SENDERS: usize = 8;
+    const MESSAGES_PER_SENDER: usize = 200;
+
+    // Each payload names its sender and index, padded with the sender's
+    // byte to a length that varies per message, so a frame that was torn
+    // or interleaved with another can't pass for whole.
+    fn stress_payload(sender: usize, index: usize) -> Vec<u8> {
+        let mut payload = format!("{sender}:{index}:").into_bytes();
+        payload.resize(payload.len() + (index * 37) % 2000, b'a' + sender as u8);
+        payload
+    }
+
+    fn check_concurrent_sends(client: TcpClient, server: MemoryTransport) {
+        let client = Arc::new(client);
+        let reader = thread::spawn(move || {
+            let mut next = [0; SENDERS];
+
+            for _ in 0..SENDERS * MESSAGES_PER_SENDER {
+                let payload = read_frame(&server).expect("connection ended early");
+                let text = String::from_utf8_lossy(&payload).into_owned();
+                let mut fields = text.splitn(3, ':');
+                let sender: usize = fields.next().unwrap().parse().unwrap();
+                let index: usize = fields.next().unwrap().parse().unwrap();
+
+                // One sender's messages arrive in the order it sent them.
+                assert_eq!(index, next[sender]);
+                assert_eq!(payload, stress_payload(sender, index));
+                next[sender] += 1;
+            }
+
+            next
+        });
+
+        let senders: Vec<_> = (0..SENDERS)
+            .map(|sender| {
+                let client = client.clone();
+
+                thread::spawn(move || {
+                    for index in 0..MESSAGES_PER_SENDER {
+                        client.send(stress_payload(sender, index)).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for sender in senders {
+            sender.join().unwrap();
+        }
+
+        let received = within(move || reader.join().unwrap());
+        assert_eq!(received, [MESSAGES_PER_SENDER; SENDERS]);
+    }
+
+    #[test]
+    fn concurrent_sends_arrive_whole_and_complete() {
+        let (transport, server) = MemoryTransport::pair();
+        let client = client_over(vec![FlakyTransport::short_writes(transport, 7)]);
+
+        check_concurrent_sends(client, server);
+    }
+
+    #[test]
+    fn concurrent_queued_sends_arrive_whole_and_complete() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = client_over(vec![FlakyTransport::short_writes(transport, 7)]);
+        client.set_write_queue(true);
+
+        check_concurrent_sends(client, server);
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u64).to_le_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn read_available(server: &MemoryTransport) -> Vec<u8> {
+        server.set_nonblocking(true).unwrap();
+
+        let mut buffer = [0; 256];
+        match Transport::read(server, &mut buffer) {
+            Ok(size) => buffer[..size].to_vec(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Vec::new(),
+            Err(e) => panic!("server read failed: {e}"),
+        }
+    }
+
+    #[test]
+    fn interrupted_and_would_block_writes_are_retried_from_the_same_offset() {
+        let (transport, server) = MemoryTransport::pair();
+        let flaky = FlakyTransport::short_writes(transport, 3).with_write_faults([
+            Some(io::ErrorKind::Interrupted),
+            None,
+            Some(io::ErrorKind::WouldBlock),
+            None,
+            Some(io::ErrorKind::Interrupted),
+            Some(io::ErrorKind::Interrupted),
+        ]);
+
+        // Continue has no retry budget, so only the retries that don't use
+        // it can get this message out.
+        let client = client_over(vec![flaky]);
+        client.send("hello world").unwrap();
+
+        assert_eq!(read_frame(&server).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn a_write_that_takes_no_bytes_fails_instead_of_spinning() {
+        let (transport, _server) = MemoryTransport::pair();
+        let client = client_over(vec![FlakyTransport::short_writes(transport, 0)]);
+
+        let result = within(move || client.send("hello").map(|_| ()));
+        match result {
+            Err(Error::Socket(e)) => assert_eq!(e.kind(), io::ErrorKind::WriteZero),
+            other => panic!("expected WriteZero, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_reset_mid_frame_reconnects_and_resends_the_whole_frame() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let first = FlakyTransport::short_writes(first, 4)
+            .with_write_faults([None, Some(io::ErrorKind::ConnectionReset)]);
+        let second = FlakyTransport::short_writes(second, usize::MAX);
+
+        let mut client = client_over(vec![first, second]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        client.send("hello").unwrap();
+
+        // The first connection saw half a header and nothing after it.
+        assert_eq!(read_available(&first_server), &frame(b"hello")[..4]);
+        assert_eq!(read_frame(&second_server).unwrap(), b"hello");
+        assert_eq!(
+            client.last_disconnect_reason(),
+            Some(DisconnectReason::PeerClosed)
+        );
+    }
+
+    #[test]
+    fn a_torn_frame_closes_the_connection_under_continue() {
+        let (transport, server) = MemoryTransport::pair();
+        let flaky = FlakyTransport::short_writes(transport, 4)
+            .with_write_faults([None, Some(io::ErrorKind::Other)]);
+        let client = client_over(vec![flaky]);
+
+        assert!(matches!(client.send("hello"), Err(Error::Socket(_))));
+        assert!(client.data.is_closed());
+        assert_eq!(read_available(&server), &frame(b"hello")[..4]);
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried_without_reporting_errors() {
+        let (transport, server) = MemoryTransport::pair();
+        let flaky = FlakyTransport::short_writes(transport, usize::MAX)
+            .with_read_faults([io::ErrorKind::Interrupted, io::ErrorKind::Interrupted]);
+        let mut client = client_over(vec![flaky]);
+
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors_ref = errors.clone();
+        client.set_on_error(move |_| {
+            errors_ref.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let (sender, received) = channel();
+        let sender = Mutex::new(sender);
+        client.set_on_message_received(move |message| {
+            let _ = sender.lock().unwrap().send(message.to_vec());
+        });
+
+        client.receive().unwrap();
+        write_frame(&server, b"hello");
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hello");
+        assert_eq!(errors.load(Ordering::SeqCst), 0);
+    }
+
+    // Runs a receive loop over framing and returns the client with the
+    // errors it reports and the reason its connection closed for.
+    fn framed_client(
+        framing: Framing,
+        configure: impl FnOnce(&mut TcpClient),
+    ) -> (
+        TcpClient,
+        MemoryTransport,
+        Receiver<String>,
+        Receiver<DisconnectReason>,
+    ) {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+        client.set_framing(framing);
+        configure(&mut client);
+
+        let (errors, reported) = channel();
+        let errors = Mutex::new(errors);
+        client.set_on_error(move |e| {
+            let _ = errors.lock().unwrap().send(e.to_string());
+        });
+
+        let (reasons, closed) = channel();
+        let reasons = Mutex::new(reasons);
+        client.set_on_connection_closed(move |_, _, reason| {
+            let _ = reasons.lock().unwrap().send(reason);
+        });
+
+        client.receive().unwrap();
+        (client, server, reported, closed)
+    }
+
+    #[test]
+    fn an_mqtt_packet_announcing_more_than_the_max_size_closes_before_buffering() {
+        let (client, server, reported, closed) = framed_client(Framing::Mqtt, |client| {
+            client.set_max_message_size(Some(1024));
+        });
+
+        // A PUBLISH announcing about 256 MB, of which only a little arrives.
+        Transport::write(&server, &[0x30, 0xff, 0xff, 0xff, 0x7f, 0, 0, 0]).unwrap();
+
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            DisconnectReason::ProtocolError
+        );
+        assert_eq!(
+            reported.recv_timeout(TIMEOUT).unwrap(),
+            Error::FrameTooLarge(268_435_455).to_string()
+        );
+        wait_until(|| client.data.is_closed());
+    }
+
+    #[test]
+    fn an_mqtt_packet_the_budget_cannot_hold_closes_the_connection() {
+        let (client, server, reported, closed) = framed_client(Framing::Mqtt, |client| {
+            client.set_memory_budget(Some(MemoryBudget {
+                limit: 64 * 1024,
+                action: BudgetAction::Reject,
+            }));
+        });
+
+        // Announces 1 MB with no max size set.
+        Transport::write(&server, &[0x30, 0x80, 0x80, 0x40, 0]).unwrap();
+
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            DisconnectReason::ProtocolError
+        );
+        assert_eq!(
+            reported.recv_timeout(TIMEOUT).unwrap(),
+            Error::MemoryBudgetExceeded(64 * 1024).to_string()
+        );
+        wait_until(|| client.data.is_closed());
+    }
+
+    #[test]
+    fn a_line_growing_past_the_budget_closes_the_connection() {
+        let framing = Framing::Delimited {
+            delimiter: b"\n".to_vec(),
+            max_length: 1 << 20,
+        };
+        let (_client, server, reported, closed) = framed_client(framing, |client| {
+            client.set_memory_budget(Some(MemoryBudget {
+                limit: 64 * 1024,
+                action: BudgetAction::Reject,
+            }));
+        });
+
+        write_frame_chunks(&server, &vec![b'x'; 128 * 1024]);
+
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            DisconnectReason::ProtocolError
+        );
+        assert_eq!(
+            reported.recv_timeout(TIMEOUT).unwrap(),
+            Error::MemoryBudgetExceeded(64 * 1024).to_string()
+        );
+    }
+
+    // Writes raw bytes in small pieces, as a peer streaming them would.
+    fn write_frame_chunks(server: &MemoryTransport, bytes: &[u8]) {
+        for chunk in bytes.chunks(4096) {
+            if Transport::write(server, chunk).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn is_receiving(client: &TcpClient) -> bool {
+        client.data.receiving.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn a_second_receive_is_rejected() {
+        let (transport, _server) = MemoryTransport::pair();
+        let client = client_over(vec![transport]);
+
+        client.receive().unwrap();
+        assert!(matches!(client.receive(), Err(Error::ReceiveLoopRunning)));
+    }
+
+    #[test]
+    fn receive_after_the_peer_closed_reads_the_new_connection() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let mut client = client_over(vec![first, second]);
+        client.set_reconnect_policy(instant_reconnects());
+
+        let (sender, received) = channel();
+        let sender = Mutex::new(sender);
+        client.set_on_message_received(move |message| {
+            let _ = sender.lock().unwrap().send(message.to_vec());
+        });
+
+        // The default policy ends the loop when the peer stops writing.
+        client.receive().unwrap();
+        first_server.shutdown(Shutdown::Write).unwrap();
+        wait_until(|| !is_receiving(&client));
+
+        client.reconnect().unwrap();
+        client.receive().unwrap();
+        write_frame(&second_server, b"after");
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"after");
+    }
+
+    #[test]
+    fn receive_after_a_callback_panic_stopped_the_loop() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+        client.set_stop_on_callback_panic(true);
+
+        let (sender, received) = channel();
+        let sender = Mutex::new(sender);
+        client.set_on_message_received(move |message| {
+            if message == b"boom" {
+                panic!("boom");
+            }
+
+            let _ = sender.lock().unwrap().send(message.to_vec());
+        });
+
+        client.receive().unwrap();
+        write_frame(&server, b"boom");
+        wait_until(|| !is_receiving(&client));
+
+        client.receive().unwrap();
+        write_frame(&server, b"after");
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"after");
+    }
+
+    #[test]
+    fn send_from_on_reconnected_can_reconnect_again() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let (third, third_server) = MemoryTransport::pair();
+
+        // The first two connections are dead before the client writes.
+        first_server.shutdown(Shutdown::Both).unwrap();
+        second_server.shutdown(Shutdown::Both).unwrap();
+
+        let mut client = client_over(vec![first, second, third]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let data = client.downgrade();
+        client.set_on_reconnected(move || {
+            if let Some(data) = data.upgrade() {
+                let _ = data.send(b"hello", Lane::Data);
+            }
+        });
+
+        let client = within(move || {
+            client.send("first").unwrap();
+            client
+        });
+
+        // The callback ran once for the second connection, whose send
+        // reconnected, and once for the third; the send that failed on the
+        // second connection is rewritten on the third.
+        assert_eq!(read_frame(&third_server).unwrap(), b"hello");
+        assert_eq!(read_frame(&third_server).unwrap(), b"hello");
+        assert_eq!(read_frame(&third_server).unwrap(), b"first");
+        drop(client);
+    }
+
+    fn message_streams(client: &TcpClient) -> usize {
+        client.data.message_senders.lock().unwrap().len()
+    }
+
+    #[test]
+    fn wait_for_message_leaves_no_stream_behind() {
+        let (transport, server) = MemoryTransport::pair();
+        let client = client_over(vec![transport]);
+
+        for _ in 0..10 {
+            assert_eq!(
+                client.wait_for_message(Duration::from_millis(1)).unwrap(),
+                None
+            );
+        }
+        assert_eq!(message_streams(&client), 0);
+
+        write_frame(&server, b"hello");
+        assert_eq!(
+            client.wait_for_message(TIMEOUT).unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(message_streams(&client), 0);
+
+        // A stream the application holds stays registered.
+        let _messages = client.messages();
+        client.wait_for_message(Duration::from_millis(1)).unwrap();
+        assert_eq!(message_streams(&client), 1);
+    }
+
+    #[test]
+    fn wait_for_message_polls_in_manual_pump_mode() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+        client.enable_manual_pump();
+
+        assert_eq!(
+            client.wait_for_message(Duration::from_millis(10)).unwrap(),
+            None
+        );
+
+        write_frame(&server, b"pumped");
+        assert_eq!(
+            client.wait_for_message(TIMEOUT).unwrap(),
+            Some(b"pumped".to_vec())
+        );
+
+        // No receive thread was started behind the caller's back.
+        client.poll().unwrap();
+        assert!(matches!(client.receive(), Err(Error::ReceiveLoopRunning)));
+    }
 }
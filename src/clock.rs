@@ -0,0 +1,184 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// A clock that only moves when told to. Sleeping blocks until advance
+// moves the clock past the sleeper's wake time, so background threads wait
+// on the test instead of pushing virtual time forward themselves, and
+// timing-dependent code runs deterministically.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    state: Arc<(Mutex<MockState>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    elapsed: Duration,
+    sleepers: usize,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Arc::new((Mutex::new(MockState::default()), Condvar::new())),
+        }
+    }
+
+    // Wakes every sleeper whose wake time has now passed.
+    pub fn advance(&self, duration: Duration) {
+        let (state, woken) = &*self.state;
+
+        match state.lock() {
+            Ok(mut state) => state.elapsed += duration,
+            Err(e) => e.into_inner().elapsed += duration,
+        }
+
+        woken.notify_all();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    // How many threads are blocked in sleep, so a test can wait for a
+    // background thread to reach its sleep before advancing.
+    pub fn sleepers(&self) -> usize {
+        self.lock().sleepers
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        match self.state.0.lock() {
+            Ok(state) => state,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.lock();
+        let wake_at = state.elapsed + duration;
+
+        state.sleepers += 1;
+        while state.elapsed < wake_at {
+            state = match self.state.1.wait(state) {
+                Ok(state) => state,
+                Err(e) => e.into_inner(),
+            };
+        }
+        state.sleepers -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{wait_until, TIMEOUT};
+
+    #[test]
+    fn a_zero_sleep_returns_without_advancing() {
+        let clock = MockClock::new();
+        clock.sleep(Duration::ZERO);
+
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn sleep_blocks_until_the_clock_is_advanced_past_the_wake_time() {
+        let clock = MockClock::new();
+        let (sender, woken) = channel();
+        let sleeper = clock.clone();
+
+        thread::spawn(move || {
+            sleeper.sleep(Duration::from_secs(10));
+            let _ = sender.send(sleeper.elapsed());
+        });
+
+        wait_until(|| clock.sleepers() == 1);
+        clock.advance(Duration::from_secs(9));
+        assert!(woken.recv_timeout(Duration::from_millis(50)).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            woken.recv_timeout(TIMEOUT).unwrap(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[test]
+    fn sleeping_does_not_move_the_clock_for_other_threads() {
+        let clock = MockClock::new();
+        let started = clock.now();
+        let sleeper = clock.clone();
+
+        thread::spawn(move || sleeper.sleep(Duration::from_secs(60)));
+        wait_until(|| clock.sleepers() == 1);
+
+        assert_eq!(clock.now(), started);
+        clock.advance(Duration::from_secs(60));
+        wait_until(|| clock.sleepers() == 0);
+    }
+
+    #[test]
+    fn one_advance_wakes_every_sleeper_that_is_due() {
+        let clock = MockClock::new();
+        let (sender, woken) = channel();
+
+        for seconds in [1, 2, 3] {
+            let sleeper = clock.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                sleeper.sleep(Duration::from_secs(seconds));
+                let _ = sender.send(seconds);
+            });
+        }
+
+        wait_until(|| clock.sleepers() == 3);
+        clock.advance(Duration::from_secs(2));
+
+        let mut due = vec![
+            woken.recv_timeout(TIMEOUT).unwrap(),
+            woken.recv_timeout(TIMEOUT).unwrap(),
+        ];
+        due.sort();
+        assert_eq!(due, [1, 2]);
+        assert_eq!(clock.sleepers(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(woken.recv_timeout(TIMEOUT).unwrap(), 3);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+
+use crate::lifecycle::supervise;
+use crate::{IoThread, TcpClientData};
+
+// Buffers small frames and writes them in one go, trading a bounded delay
+// for fewer syscalls. The buffer is written once it holds capacity bytes,
+// when max_delay has passed, or on an explicit flush.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteCoalescing {
+    pub capacity: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for WriteCoalescing {
+    fn default() -> Self {
+        Self {
+            capacity: 16 * 1024,
+            max_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+// Flushes every max_delay until the client is dropped or coalescing is
+// reconfigured, which bumps the generation. Like the queue writer it only
+// holds a weak reference between flushes. In manual pump mode poll does the
+// flushing instead of a thread.
+pub(crate) fn spawn_flusher(data: Weak<TcpClientData>, max_delay: Duration, generation: u64) {
+    if let Some(client) = data.upgrade() {
+        if client.is_manual_pump() {
+            client.schedule(
+                max_delay,
+                Box::new(move |data| flush(data, generation).is_ok()),
+            );
+            return;
+        }
+    }
+
+    thread::spawn(move || {
+        supervise(&data.clone(), IoThread::Flusher, move || loop {
+            let clock = match data.upgrade() {
+                Some(data) => data.clock(),
+                None => return "Client dropped".to_string(),
+            };
+
+            clock.sleep(max_delay);
+
+            let data = match data.upgrade() {
+                Some(data) => data,
+                None => return "Client dropped".to_string(),
+            };
+
+            if let Err(reason) = flush(&data, generation) {
+                return reason;
+            }
+        });
+    });
+}
+
+// Fails with the reason to stop flushing.
+fn flush(data: &TcpClientData, generation: u64) -> Result<(), String> {
+    if data.is_closed() {
+        return Err("Connection closed".to_string());
+    }
+
+    if data.coalescing_generation() != generation {
+        return Err("Write coalescing reconfigured".to_string());
+    }
+
+    if let Err(e) = data.flush() {
+        data.report_error(&e);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use crate::{
+    ClientIdentity, DispatchMode, ErrorPolicy, FailureThreshold, Framing, JournalConfig, LocalBind,
+    MemoryBudget, ReconnectPolicy,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientConfig {
+    pub address: String,
+    // Tried in order when the primary address can't be reached, both on
+    // connect and on reconnect.
+    pub fallback_addresses: Vec<String>,
+    pub connect_timeout: Option<Duration>,
+    // Bounds connecting to each address as a whole, its lookup included.
+    // The connect error's handshake_stage says where it stalled.
+    pub handshake_timeout: Option<Duration>,
+    // Applies to the fallback addresses as well.
+    pub local_bind: Option<LocalBind>,
+    // Defers connecting until the first send, as TcpClient::connect_lazy.
+    pub lazy: bool,
+    pub dispatch_mode: DispatchMode,
+    pub slow_consumer_threshold: Duration,
+    pub max_fragment_size: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub framing: Framing,
+    pub error_policy: ErrorPolicy,
+    pub failure_threshold: Option<FailureThreshold>,
+    pub memory_budget: Option<MemoryBudget>,
+    pub reconnect_policy: ReconnectPolicy,
+    pub journal: Option<JournalConfig>,
+    // Sent as the first frame on every connection.
+    pub identity: Option<ClientIdentity>,
+}
+
+impl ClientConfig {
+    pub fn new(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            fallback_addresses: Vec::new(),
+            connect_timeout: None,
+            handshake_timeout: None,
+            local_bind: None,
+            lazy: false,
+            dispatch_mode: DispatchMode::Inline,
+            slow_consumer_threshold: Duration::from_millis(100),
+            max_fragment_size: None,
+            max_message_size: None,
+            framing: Framing::LengthPrefixed,
+            error_policy: ErrorPolicy::default(),
+            failure_threshold: None,
+            memory_budget: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            journal: None,
+            identity: None,
+        }
+    }
+}
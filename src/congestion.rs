@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+// Weight given to the newest send when updating the moving average.
+const SMOOTHING: f64 = 0.2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CongestionSignal {
+    pub congested: bool,
+    pub average_stall: Duration,
+    pub last_stall: Duration,
+    pub stalled_sends: u64,
+}
+
+pub(crate) struct CongestionTracker {
+    average_stall: f64,
+    last_stall: Duration,
+    stalled_sends: u64,
+    threshold: Duration,
+    pacing: bool,
+}
+
+impl CongestionTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            average_stall: 0.0,
+            last_stall: Duration::ZERO,
+            stalled_sends: 0,
+            threshold: Duration::from_millis(100),
+            pacing: false,
+        }
+    }
+
+    pub(crate) fn record(&mut self, stall: Duration) {
+        self.average_stall =
+            SMOOTHING * stall.as_secs_f64() + (1.0 - SMOOTHING) * self.average_stall;
+        self.last_stall = stall;
+
+        if !stall.is_zero() {
+            self.stalled_sends += 1;
+        }
+    }
+
+    pub(crate) fn set_threshold(&mut self, threshold: Duration) {
+        self.threshold = threshold;
+    }
+
+    pub(crate) fn set_pacing(&mut self, pacing: bool) {
+        self.pacing = pacing;
+    }
+
+    pub(crate) fn is_congested(&self) -> bool {
+        self.average_stall() > self.threshold
+    }
+
+    // With pacing enabled, a congested connection delays each send by the
+    // current average stall so callers back off instead of piling up writes.
+    pub(crate) fn pacing_delay(&self) -> Option<Duration> {
+        if self.pacing && self.is_congested() {
+            Some(self.average_stall())
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn signal(&self) -> CongestionSignal {
+        CongestionSignal {
+            congested: self.is_congested(),
+            average_stall: self.average_stall(),
+            last_stall: self.last_stall,
+            stalled_sends: self.stalled_sends,
+        }
+    }
+
+    fn average_stall(&self) -> Duration {
+        Duration::from_secs_f64(self.average_stall)
+    }
+}
@@ -0,0 +1,483 @@
+mod trace;
+
+pub use trace::*;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, SendTicket, TcpClient, TcpClientData};
+
+// Every frame starts with a flags byte saying which optional fields follow,
+// in this order, before the payload:
+// - a u64 LE deadline in milliseconds since the Unix epoch. Wall-clock time
+//   is used since the peer has no access to this side's monotonic clock.
+// - metadata: a u8 entry count, then per entry a u8 key length, the key, a
+//   u16 LE value length and the value, all UTF-8.
+const HAS_DEADLINE: u8 = 0x01;
+const HAS_METADATA: u8 = 0x02;
+const DEADLINE_SIZE: usize = 8;
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+type OnMessageReceivedCallback = Arc<dyn Fn(&MessageContext, &[u8]) + Send + Sync>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageContext {
+    deadline: Option<SystemTime>,
+    metadata: Vec<(String, String)>,
+}
+
+pub struct ContextClient {
+    client: TcpClient,
+    on_message_received: Arc<Mutex<OnMessageReceivedCallback>>,
+    expiry: Arc<Expiry>,
+}
+
+#[derive(Default)]
+struct Expiry {
+    drop_expired: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl MessageContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_deadline(mut self, deadline: SystemTime) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    // Adds an entry, replacing any earlier one with the same key. Keys are at
+    // most 255 bytes and values 65535; sending fails otherwise.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.retain(|(existing, _)| existing != key);
+        self.metadata.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    // Sets the traceparent and tracestate entries.
+    pub fn with_trace_context(self, trace: &TraceContext) -> Self {
+        let context = self.with_metadata(TRACEPARENT, &trace.traceparent());
+
+        match &trace.trace_state {
+            Some(state) => context.with_metadata(TRACESTATE, state),
+            None => context,
+        }
+    }
+
+    // None when the peer sent no traceparent, or an invalid one.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        TraceContext::parse(self.get(TRACEPARENT)?, self.get(TRACESTATE))
+    }
+
+    // The time by which the peer wants this message handled.
+    pub fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+
+    // Time left until the deadline, zero once it has passed, or None when
+    // the peer set no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| {
+            deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    // Entries in the order they were added.
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut flags = 0;
+        let mut frame = vec![0];
+
+        if let Some(deadline) = self.deadline {
+            // Deadlines before the Unix epoch are sent as the epoch itself.
+            let millis = match deadline.duration_since(UNIX_EPOCH) {
+                Ok(elapsed) => u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+                Err(_) => 0,
+            };
+
+            flags |= HAS_DEADLINE;
+            frame.extend_from_slice(&millis.to_le_bytes());
+        }
+
+        if !self.metadata.is_empty() {
+            let count = u8::try_from(self.metadata.len())
+                .map_err(|_| Error::Protocol("More than 255 metadata entries".to_string()))?;
+
+            flags |= HAS_METADATA;
+            frame.push(count);
+
+            for (key, value) in &self.metadata {
+                let key_length = u8::try_from(key.len()).map_err(|_| {
+                    Error::Protocol("Metadata key is longer than 255 bytes".to_string())
+                })?;
+                let value_length = u16::try_from(value.len()).map_err(|_| {
+                    Error::Protocol(format!("Metadata value for \"{key}\" is too long"))
+                })?;
+
+                frame.push(key_length);
+                frame.extend_from_slice(key.as_bytes());
+                frame.extend_from_slice(&value_length.to_le_bytes());
+                frame.extend_from_slice(value.as_bytes());
+            }
+        }
+
+        frame[0] = flags;
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    fn decode(frame: &[u8]) -> Result<(Self, &[u8]), Error> {
+        let mut reader = FrameReader { frame, position: 0 };
+        let flags = reader.take(1)?[0];
+
+        if flags & !(HAS_DEADLINE | HAS_METADATA) != 0 {
+            return Err(Error::Protocol(format!(
+                "Unknown context flags {flags:#04x}"
+            )));
+        }
+
+        let mut context = MessageContext::new();
+
+        if flags & HAS_DEADLINE != 0 {
+            let millis = u64::from_le_bytes(reader.take(DEADLINE_SIZE)?.try_into().unwrap());
+            context.deadline = UNIX_EPOCH.checked_add(Duration::from_millis(millis));
+        }
+
+        if flags & HAS_METADATA != 0 {
+            let count = reader.take(1)?[0];
+
+            for _ in 0..count {
+                let key_length = reader.take(1)?[0] as usize;
+                let key = reader.text(key_length)?;
+                let value_length = u16::from_le_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+                let value = reader.text(value_length)?;
+
+                context.metadata.push((key, value));
+            }
+        }
+
+        Ok((context, &frame[reader.position..]))
+    }
+}
+
+struct FrameReader<'a> {
+    frame: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], Error> {
+        let end = self.position + size;
+
+        if end > self.frame.len() {
+            return Err(Error::Protocol(
+                "Frame is too short for its context header".to_string(),
+            ));
+        }
+
+        let bytes = &self.frame[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn text(&mut self, size: usize) -> Result<String, Error> {
+        let bytes = self.take(size)?;
+
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(e) => Err(Error::InvalidUtf8(e)),
+        }
+    }
+}
+
+impl ContextClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let on_message_received: Arc<Mutex<OnMessageReceivedCallback>> =
+            Arc::new(Mutex::new(Arc::new(|_, _| {})));
+        let on_message_received_ref = on_message_received.clone();
+        let expiry = Arc::new(Expiry::default());
+        let expiry_ref = expiry.clone();
+        let data: Weak<TcpClientData> = client.downgrade();
+
+        client.set_on_message_received(move |frame| {
+            let (context, payload) = match MessageContext::decode(frame) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    if let Some(data) = data.upgrade() {
+                        data.report_error(&e);
+                    }
+                    return;
+                }
+            };
+
+            if expiry_ref.drop_expired.load(Ordering::SeqCst) && context.is_expired() {
+                expiry_ref.dropped.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+
+            let callback = match on_message_received_ref.lock() {
+                Ok(callback) => callback.clone(),
+                Err(e) => e.into_inner().clone(),
+            };
+
+            callback(&context, payload);
+        });
+
+        Self {
+            client,
+            on_message_received,
+            expiry,
+        }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // Drops messages whose deadline has already passed when they arrive,
+    // before the callback sees them, so a slow consumer skips stale ticks.
+    pub fn set_drop_expired(&mut self, drop_expired: bool) {
+        self.expiry
+            .drop_expired
+            .store(drop_expired, Ordering::SeqCst);
+    }
+
+    // How many messages were dropped for having expired.
+    pub fn expired_count(&self) -> u64 {
+        self.expiry.dropped.load(Ordering::SeqCst)
+    }
+
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.send_with_context(payload, &MessageContext::new())
+    }
+
+    pub fn send_with_deadline<T>(
+        &self,
+        payload: T,
+        deadline: SystemTime,
+    ) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.send_with_context(payload, &MessageContext::new().with_deadline(deadline))
+    }
+
+    pub fn send_with_metadata<T>(
+        &self,
+        payload: T,
+        metadata: &[(&str, &str)],
+    ) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let context = metadata
+            .iter()
+            .fold(MessageContext::new(), |context, (key, value)| {
+                context.with_metadata(key, value)
+            });
+
+        self.send_with_context(payload, &context)
+    }
+
+    pub fn send_with_context<T>(
+        &self,
+        payload: T,
+        context: &MessageContext,
+    ) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.client.send(context.encode(payload.as_ref())?)
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&MessageContext, &[u8]) + Send + Sync + 'static,
+    {
+        match self.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{client_over, write_frame, TIMEOUT};
+    use crate::MemoryTransport;
+
+    fn context() -> MessageContext {
+        MessageContext::new()
+            .with_deadline(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123))
+            .with_metadata("content-type", "application/json")
+            .with_metadata("route", "")
+    }
+
+    #[test]
+    fn contexts_round_trip_with_the_payload() {
+        for context in [context(), MessageContext::new()] {
+            let frame = context.encode(b"payload").unwrap();
+            let (decoded, payload) = MessageContext::decode(&frame).unwrap();
+
+            assert_eq!(decoded, context);
+            assert_eq!(payload, b"payload");
+        }
+
+        // Without a context only the flags byte is added.
+        assert_eq!(MessageContext::new().encode(b"x").unwrap(), [0, b'x']);
+    }
+
+    #[test]
+    fn metadata_replaces_earlier_entries_with_the_same_key() {
+        let context = MessageContext::new()
+            .with_metadata("a", "1")
+            .with_metadata("b", "2")
+            .with_metadata("a", "3");
+
+        assert_eq!(context.get("a"), Some("3"));
+        assert_eq!(context.metadata().len(), 2);
+        assert_eq!(context.get("missing"), None);
+    }
+
+    #[test]
+    fn every_truncated_header_is_a_protocol_error() {
+        let frame = context().encode(b"").unwrap();
+
+        for length in 0..frame.len() {
+            assert!(
+                matches!(
+                    MessageContext::decode(&frame[..length]),
+                    Err(Error::Protocol(_))
+                ),
+                "decoded {length} of {} bytes",
+                frame.len()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        assert!(matches!(
+            MessageContext::decode(&[0x80, b'x']),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_metadata_fails_to_encode() {
+        let key = "k".repeat(256);
+        assert!(MessageContext::new()
+            .with_metadata(&key, "v")
+            .encode(b"")
+            .is_err());
+
+        let value = "v".repeat(usize::from(u16::MAX) + 1);
+        assert!(MessageContext::new()
+            .with_metadata("k", &value)
+            .encode(b"")
+            .is_err());
+    }
+
+    #[test]
+    fn expired_messages_are_dropped_and_counted() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = ContextClient::new(client_over(vec![transport]));
+        client.set_drop_expired(true);
+
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        client.set_on_message_received(move |context, payload| {
+            let _ = messages
+                .lock()
+                .unwrap()
+                .send((context.is_expired(), payload.to_vec()));
+        });
+        client.client().receive().unwrap();
+
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        for (deadline, payload) in [(past, &b"stale"[..]), (future, b"fresh")] {
+            let frame = MessageContext::new()
+                .with_deadline(deadline)
+                .encode(payload)
+                .unwrap();
+            write_frame(&server, &frame);
+        }
+        write_frame(&server, &MessageContext::new().encode(b"timeless").unwrap());
+
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            (false, b"fresh".to_vec())
+        );
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            (false, b"timeless".to_vec())
+        );
+        assert_eq!(client.expired_count(), 1);
+    }
+
+    #[test]
+    fn expired_messages_are_delivered_unless_dropping_is_on() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = ContextClient::new(client_over(vec![transport]));
+
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        client.set_on_message_received(move |context, _| {
+            let _ = messages.lock().unwrap().send(context.remaining());
+        });
+        client.client().receive().unwrap();
+
+        let frame = MessageContext::new()
+            .with_deadline(UNIX_EPOCH)
+            .encode(b"late")
+            .unwrap();
+        write_frame(&server, &frame);
+
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(client.expired_count(), 0);
+    }
+
+    #[test]
+    fn trace_context_travels_in_the_metadata() {
+        let mut trace = TraceContext::new([1; 16], [2; 8], true);
+        trace.trace_state = Some("vendor=value".to_string());
+
+        let context = MessageContext::new().with_trace_context(&trace);
+        let frame = context.encode(b"").unwrap();
+        let (decoded, _) = MessageContext::decode(&frame).unwrap();
+
+        assert_eq!(decoded.trace_context(), Some(trace));
+        assert_eq!(MessageContext::new().trace_context(), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt::Write;
+
+// W3C Trace Context, carried in the traceparent and tracestate metadata
+// entries. Bridges to tracing libraries convert their span context to and
+// from this.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    // The sender's span, which becomes the parent of the receiver's.
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+    pub trace_state: Option<String>,
+}
+
+const VERSION: &str = "00";
+const SAMPLED: u8 = 0x01;
+
+impl TraceContext {
+    pub fn new(trace_id: [u8; 16], parent_id: [u8; 8], sampled: bool) -> Self {
+        Self {
+            trace_id,
+            parent_id,
+            flags: if sampled { SAMPLED } else { 0 },
+            trace_state: None,
+        }
+    }
+
+    // Accepts future versions as the spec asks, reading only the fields
+    // version 00 defines. All-zero ids are invalid.
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut fields = traceparent.trim().split('-');
+        let version = fields.next()?;
+        let trace_id = fields.next()?;
+        let parent_id = fields.next()?;
+        let flags = fields.next()?;
+
+        if version.len() != 2
+            || hex(version).is_none()
+            || version == "ff"
+            || (version == VERSION && fields.next().is_some())
+        {
+            return None;
+        }
+
+        let trace_id: [u8; 16] = hex(trace_id)?.try_into().ok()?;
+        let parent_id: [u8; 8] = hex(parent_id)?.try_into().ok()?;
+        let flags: [u8; 1] = hex(flags)?.try_into().ok()?;
+
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            parent_id,
+            flags: flags[0],
+            trace_state: tracestate
+                .map(str::trim)
+                .filter(|state| !state.is_empty())
+                .map(str::to_string),
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        let mut traceparent = String::with_capacity(55);
+        traceparent.push_str(VERSION);
+        traceparent.push('-');
+        push_hex(&mut traceparent, &self.trace_id);
+        traceparent.push('-');
+        push_hex(&mut traceparent, &self.parent_id);
+        traceparent.push('-');
+        push_hex(&mut traceparent, &[self.flags]);
+        traceparent
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    // The same trace continued from a new span on this side.
+    pub fn with_parent(&self, parent_id: [u8; 8]) -> Self {
+        Self {
+            parent_id,
+            ..self.clone()
+        }
+    }
+}
+
+fn hex(text: &str) -> Option<Vec<u8>> {
+    // Only lowercase is valid in traceparent.
+    if !text.len().is_multiple_of(2)
+        || !text
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok())
+        .collect()
+}
+
+fn push_hex(text: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(text, "{byte:02x}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn the_spec_example_parses_and_formats_back() {
+        let trace = TraceContext::parse(EXAMPLE, None).unwrap();
+
+        assert_eq!(trace.trace_id[..2], [0x4b, 0xf9]);
+        assert_eq!(trace.parent_id[7], 0xb7);
+        assert!(trace.is_sampled());
+        assert_eq!(trace.trace_state, None);
+        assert_eq!(trace.traceparent(), EXAMPLE);
+    }
+
+    #[test]
+    fn new_contexts_round_trip() {
+        for sampled in [true, false] {
+            let trace = TraceContext::new([0xab; 16], [0x01; 8], sampled);
+            let parsed = TraceContext::parse(&trace.traceparent(), None).unwrap();
+
+            assert_eq!(parsed, trace);
+            assert_eq!(parsed.is_sampled(), sampled);
+        }
+    }
+
+    #[test]
+    fn tracestate_is_trimmed_and_empty_is_none() {
+        let trace = TraceContext::parse(EXAMPLE, Some(" rojo=00f067aa0ba902b7 ")).unwrap();
+        assert_eq!(trace.trace_state.as_deref(), Some("rojo=00f067aa0ba902b7"));
+
+        assert_eq!(
+            TraceContext::parse(EXAMPLE, Some("  "))
+                .unwrap()
+                .trace_state,
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_traceparents_are_rejected() {
+        for traceparent in [
+            "",
+            "00",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            // Uppercase hex.
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            // All-zero ids.
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            // Ids and flags of the wrong length.
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            // Version ff is forbidden, and versions are two hex digits.
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "zz-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "000-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            // Version 00 has exactly four fields.
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0g",
+        ] {
+            assert_eq!(
+                TraceContext::parse(traceparent, None),
+                None,
+                "{traceparent}"
+            );
+        }
+    }
+
+    #[test]
+    fn future_versions_are_read_as_far_as_version_00_goes() {
+        let traceparent = "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-what-comes-next";
+        let trace = TraceContext::parse(traceparent, None).unwrap();
+
+        // Sent on as the version this side speaks.
+        assert_eq!(trace.traceparent(), EXAMPLE);
+    }
+
+    #[test]
+    fn with_parent_keeps_the_trace() {
+        let trace = TraceContext::parse(EXAMPLE, Some("a=b")).unwrap();
+        let child = trace.with_parent([7; 8]);
+
+        assert_eq!(child.trace_id, trace.trace_id);
+        assert_eq!(child.parent_id, [7; 8]);
+        assert_eq!(child.flags, trace.flags);
+        assert_eq!(child.trace_state, trace.trace_state);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::Direction;
+
+// How many attempts and sessions are kept; older ones are dropped first.
+const HISTORY_SIZE: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionAttempt {
+    pub address: String,
+    pub started: SystemTime,
+    pub duration: Duration,
+    // None when the attempt succeeded.
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub address: String,
+    pub started: SystemTime,
+    // Up to now for the session that is still open.
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // None while the session is still open.
+    pub end_reason: Option<String>,
+}
+
+// A snapshot of the client's recent connection history, oldest first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub attempts: Vec<ConnectionAttempt>,
+    pub sessions: Vec<SessionRecord>,
+}
+
+struct OpenSession {
+    address: String,
+    started: SystemTime,
+    started_at: Instant,
+}
+
+#[derive(Default)]
+struct History {
+    attempts: VecDeque<ConnectionAttempt>,
+    sessions: VecDeque<SessionRecord>,
+    current: Option<OpenSession>,
+}
+
+// Byte counts are kept outside the lock since they change on every read and
+// write.
+#[derive(Default)]
+pub(crate) struct ConnectionLog {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    history: Mutex<History>,
+}
+
+impl ConnectionLog {
+    pub(crate) fn record_attempt(&self, address: &str, duration: Duration, error: Option<String>) {
+        let attempt = ConnectionAttempt {
+            address: address.to_string(),
+            started: SystemTime::now() - duration,
+            duration,
+            error,
+        };
+
+        let mut history = self.history();
+        push_bounded(&mut history.attempts, attempt);
+    }
+
+    pub(crate) fn start_session(&self, address: &str) {
+        let mut history = self.history();
+
+        history.current = Some(OpenSession {
+            address: address.to_string(),
+            started: SystemTime::now(),
+            started_at: Instant::now(),
+        });
+    }
+
+    pub(crate) fn end_session(&self, reason: &str) {
+        let mut history = self.history();
+
+        if let Some(current) = history.current.take() {
+            let record = self.record(&current, Some(reason.to_string()));
+
+            self.bytes_sent.store(0, Ordering::Relaxed);
+            self.bytes_received.store(0, Ordering::Relaxed);
+            push_bounded(&mut history.sessions, record);
+        }
+    }
+
+    pub(crate) fn record_io(&self, direction: Direction, size: usize) {
+        let counter = match direction {
+            Direction::Inbound => &self.bytes_received,
+            Direction::Outbound => &self.bytes_sent,
+        };
+
+        counter.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Diagnostics {
+        let history = self.history();
+        let mut sessions: Vec<SessionRecord> = history.sessions.iter().cloned().collect();
+
+        if let Some(current) = &history.current {
+            sessions.push(self.record(current, None));
+        }
+
+        Diagnostics {
+            attempts: history.attempts.iter().cloned().collect(),
+            sessions,
+        }
+    }
+
+    fn history(&self) -> std::sync::MutexGuard<'_, History> {
+        match self.history.lock() {
+            Ok(history) => history,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    fn record(&self, session: &OpenSession, end_reason: Option<String>) -> SessionRecord {
+        SessionRecord {
+            address: session.address.clone(),
+            started: session.started,
+            duration: session.started_at.elapsed(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            end_reason,
+        }
+    }
+}
+
+impl Diagnostics {
+    // Timestamps are milliseconds since the Unix epoch and durations are
+    // milliseconds.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"attempts\":[");
+
+        for (index, attempt) in self.attempts.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+
+            let _ = write!(
+                json,
+                "{{\"address\":{},\"started\":{},\"duration\":{},\"error\":{}}}",
+                quote(&attempt.address),
+                unix_millis(attempt.started),
+                attempt.duration.as_millis(),
+                optional(&attempt.error),
+            );
+        }
+
+        json.push_str("],\"sessions\":[");
+
+        for (index, session) in self.sessions.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+
+            let _ = write!(
+                json,
+                "{{\"address\":{},\"started\":{},\"duration\":{},\"bytes_sent\":{},\
+                 \"bytes_received\":{},\"end_reason\":{}}}",
+                quote(&session.address),
+                unix_millis(session.started),
+                session.duration.as_millis(),
+                session.bytes_sent,
+                session.bytes_received,
+                optional(&session.end_reason),
+            );
+        }
+
+        json.push_str("]}");
+        json
+    }
+}
+
+fn push_bounded<T>(entries: &mut VecDeque<T>, entry: T) {
+    if entries.len() == HISTORY_SIZE {
+        entries.pop_front();
+    }
+
+    entries.push_back(entry);
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_millis(),
+        Err(_) => 0,
+    }
+}
+
+fn optional(text: &Option<String>) -> String {
+    match text {
+        Some(text) => quote(text),
+        None => "null".to_string(),
+    }
+}
+
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+
+    for character in text.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            character if (character as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", character as u32);
+            }
+            character => quoted.push(character),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,223 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::{Direction, TcpClientData};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DispatchMode {
+    // Callbacks run on the receive thread itself.
+    Inline,
+    // Frames are handed to a single dispatch thread and delivered in order.
+    Ordered,
+    // Frames are spread across the given number of worker threads, so
+    // callbacks may run concurrently and complete out of order.
+    Unordered(usize),
+}
+
+enum Delivery {
+    Message(Vec<u8>),
+    Batch(Vec<Vec<u8>>),
+}
+
+pub(crate) struct Dispatcher {
+    data: Arc<TcpClientData>,
+    sender: Option<Sender<Delivery>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(mode: DispatchMode, data: Arc<TcpClientData>) -> Self {
+        let workers = match mode {
+            DispatchMode::Inline => {
+                return Self {
+                    data,
+                    sender: None,
+                    workers: Vec::new(),
+                }
+            }
+            DispatchMode::Ordered => 1,
+            DispatchMode::Unordered(size) => size.max(1),
+        };
+
+        let (sender, receiver) = channel::<Delivery>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..workers)
+            .map(|_| {
+                let data_ref = data.clone();
+                let receiver_ref = receiver.clone();
+
+                thread::spawn(move || Self::work(&data_ref, &receiver_ref))
+            })
+            .collect();
+
+        Self {
+            data,
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub(crate) fn dispatch(&self, message: &[u8]) {
+        self.data.journal(Direction::Inbound, message);
+
+        match &self.sender {
+            Some(sender) => {
+                let _ = sender.send(Delivery::Message(message.to_vec()));
+            }
+            None => self.data.dispatch(message),
+        }
+    }
+
+    // A batch goes to a single worker, so its messages stay together.
+    pub(crate) fn dispatch_batch(&self, messages: Vec<Vec<u8>>) {
+        for message in &messages {
+            self.data.journal(Direction::Inbound, message);
+        }
+
+        match &self.sender {
+            Some(sender) => {
+                let _ = sender.send(Delivery::Batch(messages));
+            }
+            None => self.data.dispatch_batch(&messages),
+        }
+    }
+
+    fn work(data: &TcpClientData, receiver: &Mutex<Receiver<Delivery>>) {
+        loop {
+            let message = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return,
+            };
+
+            match message {
+                Ok(Delivery::Message(message)) => data.dispatch(&message),
+                Ok(Delivery::Batch(messages)) => data.dispatch_batch(&messages),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.sender = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::testing::{client_over, within};
+    use crate::MemoryTransport;
+
+    const MESSAGES: usize = 2000;
+
+    fn message(index: usize) -> Vec<u8> {
+        (index as u32).to_le_bytes().to_vec()
+    }
+
+    fn index(message: &[u8]) -> usize {
+        u32::from_le_bytes(message.try_into().unwrap()) as usize
+    }
+
+    // Records which handler got each message, so a message that reached
+    // both, or neither, shows up as a count other than one.
+    fn recorder(seen: &Arc<Mutex<Vec<usize>>>) -> impl Fn(&[u8]) + Send + Sync + 'static {
+        let seen = seen.clone();
+        move |message| seen.lock().unwrap()[index(message)] += 1
+    }
+
+    #[test]
+    fn replacing_the_handler_mid_dispatch_delivers_each_message_once() {
+        let (transport, _server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+
+        let old = Arc::new(Mutex::new(vec![0; MESSAGES]));
+        let new = Arc::new(Mutex::new(vec![0; MESSAGES]));
+        client.set_on_message_received(recorder(&old));
+
+        let data = client.downgrade().upgrade().unwrap();
+        let dispatcher = Dispatcher::new(DispatchMode::Unordered(4), data);
+        let slot = client.message_handler_slot();
+        let old_ref = old.clone();
+        let new_ref = new.clone();
+
+        within(move || {
+            let dispatching = thread::spawn(move || {
+                for index in 0..MESSAGES {
+                    dispatcher.dispatch(&message(index));
+                }
+
+                // Waits for the workers to drain the queue.
+                drop(dispatcher);
+            });
+
+            // Swaps back and forth while the workers are delivering.
+            for swap in 0..200 {
+                if swap % 2 == 0 {
+                    slot.set(recorder(&new_ref));
+                } else {
+                    slot.set(recorder(&old_ref));
+                }
+                thread::yield_now();
+            }
+
+            dispatching.join().unwrap();
+        });
+
+        let old = old.lock().unwrap();
+        let new = new.lock().unwrap();
+        for index in 0..MESSAGES {
+            assert_eq!(old[index] + new[index], 1, "message {index}");
+        }
+        drop(client);
+    }
+
+    #[test]
+    fn a_handler_can_replace_itself_while_dispatching() {
+        let (transport, _server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+
+        let old = Arc::new(Mutex::new(vec![0; MESSAGES]));
+        let new = Arc::new(Mutex::new(vec![0; MESSAGES]));
+        let slot = client.message_handler_slot();
+        let record_old = recorder(&old);
+        let new_ref = new.clone();
+        let replaced = AtomicBool::new(false);
+
+        // A login handler that hands over to the main one on its first call.
+        client.set_on_message_received(move |message| {
+            record_old(message);
+            if !replaced.swap(true, Ordering::SeqCst) {
+                slot.set(recorder(&new_ref));
+            }
+        });
+
+        let data = client.downgrade().upgrade().unwrap();
+        let dispatcher = Dispatcher::new(DispatchMode::Ordered, data);
+
+        within(move || {
+            for index in 0..MESSAGES {
+                dispatcher.dispatch(&message(index));
+            }
+            drop(dispatcher);
+        });
+
+        // Ordered delivery means only the first message saw the old handler.
+        let old = old.lock().unwrap();
+        let new = new.lock().unwrap();
+        assert_eq!(old[0], 1);
+        assert_eq!(new[0], 0);
+        for index in 1..MESSAGES {
+            assert_eq!((old[index], new[index]), (0, 1), "message {index}");
+        }
+        drop(client);
+    }
+}
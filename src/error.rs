@@ -0,0 +1,343 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::Utf8Error;
+use std::time::Duration;
+
+// Platform-independent classification of socket failures, so applications
+// can react to e.g. a refused connection the same way on Unix and Windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorReason {
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    BrokenPipe,
+    NetworkUnreachable,
+    NetworkDown,
+    HostUnreachable,
+    TimedOut,
+    AddressInUse,
+    AddressNotAvailable,
+    Interrupted,
+    UnexpectedEof,
+    Other,
+}
+
+// Why a connection ended, as reported to on_connection_closed, the
+// reconnect filter and the session history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    // disconnect or drop, or an error policy that closes locally.
+    LocalRequest,
+    PeerClosed,
+    // The peer closed after sending a retry-after hint or a kick notice.
+    Kicked,
+    // Also when the server closed after a timeout notice.
+    Timeout,
+    IoError(io::ErrorKind),
+    // The stream could no longer be trusted, e.g. after an oversized frame.
+    ProtocolError,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketError {
+    kind: io::ErrorKind,
+    reason: ErrorReason,
+    raw_os_error: Option<i32>,
+    message: String,
+    attempts: Vec<AddressAttempt>,
+    handshake_stage: Option<HandshakeStage>,
+}
+
+// The step of opening a connection that the handshake timeout ran out in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeStage {
+    Resolve,
+    Connect,
+}
+
+// One resolved address tried while connecting, and why it failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressAttempt {
+    pub address: SocketAddr,
+    pub duration: Duration,
+    pub error: SocketError,
+}
+
+// Carries every attempt of a failed connect through an io::Error, which is
+// what connectors return, until it becomes a SocketError.
+#[derive(Debug)]
+struct ConnectAttempts {
+    attempts: Vec<AddressAttempt>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Connect(SocketError),
+    Socket(SocketError),
+    // A write found the peer gone (EPIPE, ECONNRESET, ...).
+    Disconnected(SocketError),
+    InvalidUtf8(Utf8Error),
+    ReceiveLoopRunning,
+    CallbackPanicked(String),
+    InvalidTopic(String),
+    QueueClosed,
+    Closed,
+    Suspended,
+    WindowExhausted,
+    FrameTooLarge(usize),
+    // Carries the budget's limit in bytes.
+    MemoryBudgetExceeded(usize),
+    ThreadHints(String),
+    Journal(String),
+    Inbox(String),
+    RoutingTable(String),
+    Protocol(String),
+    Auth(String),
+}
+
+impl ErrorReason {
+    // std already maps the platform error codes (ECONNREFUSED,
+    // WSAECONNREFUSED, ...) onto io::ErrorKind, so the kind is the portable
+    // source of truth here.
+    pub fn from_kind(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::ConnectionRefused => ErrorReason::ConnectionRefused,
+            io::ErrorKind::ConnectionReset => ErrorReason::ConnectionReset,
+            io::ErrorKind::ConnectionAborted => ErrorReason::ConnectionAborted,
+            io::ErrorKind::NotConnected => ErrorReason::NotConnected,
+            io::ErrorKind::BrokenPipe => ErrorReason::BrokenPipe,
+            io::ErrorKind::NetworkUnreachable => ErrorReason::NetworkUnreachable,
+            io::ErrorKind::NetworkDown => ErrorReason::NetworkDown,
+            io::ErrorKind::HostUnreachable => ErrorReason::HostUnreachable,
+            io::ErrorKind::TimedOut => ErrorReason::TimedOut,
+            io::ErrorKind::AddrInUse => ErrorReason::AddressInUse,
+            io::ErrorKind::AddrNotAvailable => ErrorReason::AddressNotAvailable,
+            io::ErrorKind::Interrupted => ErrorReason::Interrupted,
+            io::ErrorKind::UnexpectedEof => ErrorReason::UnexpectedEof,
+            _ => ErrorReason::Other,
+        }
+    }
+
+    // Whether the failure means the connection itself is gone, so retrying
+    // on the same socket can't succeed.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            ErrorReason::ConnectionReset
+                | ErrorReason::ConnectionAborted
+                | ErrorReason::NotConnected
+                | ErrorReason::BrokenPipe
+        )
+    }
+}
+
+// Carries the stage of a handshake timeout through an io::Error, like the
+// attempts above.
+#[derive(Debug)]
+struct HandshakeTimedOut {
+    stage: HandshakeStage,
+}
+
+impl SocketError {
+    pub fn kind(&self) -> io::ErrorKind {
+        self.kind
+    }
+
+    pub fn reason(&self) -> ErrorReason {
+        self.reason
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.raw_os_error
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    // Each address tried, in order, when this is a failed connect to a
+    // hostname; empty for other errors.
+    pub fn attempts(&self) -> &[AddressAttempt] {
+        &self.attempts
+    }
+
+    // Where a connect stalled when it failed for running out of handshake
+    // timeout; None for other errors.
+    pub fn handshake_stage(&self) -> Option<HandshakeStage> {
+        self.handshake_stage
+    }
+}
+
+// Classified like the last attempt, which is what a plain connect reports.
+pub(crate) fn connect_failed(attempts: Vec<AddressAttempt>) -> io::Error {
+    let kind = match attempts.last() {
+        Some(attempt) => attempt.error.kind,
+        None => io::ErrorKind::InvalidInput,
+    };
+
+    io::Error::new(kind, ConnectAttempts { attempts })
+}
+
+pub(crate) fn handshake_timed_out(stage: HandshakeStage) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, HandshakeTimedOut { stage })
+}
+
+impl From<io::Error> for SocketError {
+    fn from(error: io::Error) -> Self {
+        let handshake_stage = error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<HandshakeTimedOut>())
+            .map(|inner| inner.stage);
+
+        let attempts = error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ConnectAttempts>())
+            .map(|inner| inner.attempts.clone());
+
+        if let Some(attempts) = attempts {
+            let raw_os_error = attempts
+                .last()
+                .and_then(|attempt| attempt.error.raw_os_error);
+
+            return Self {
+                kind: error.kind(),
+                reason: ErrorReason::from_kind(error.kind()),
+                raw_os_error,
+                message: error.to_string(),
+                attempts,
+                handshake_stage,
+            };
+        }
+
+        Self {
+            kind: error.kind(),
+            reason: ErrorReason::from_kind(error.kind()),
+            raw_os_error: error.raw_os_error(),
+            message: error.to_string(),
+            attempts: Vec::new(),
+            handshake_stage,
+        }
+    }
+}
+
+impl fmt::Display for ConnectAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.attempts.as_slice() {
+            [] => write!(f, "Address did not resolve to any socket address"),
+            [attempt] => write!(f, "{}", attempt.error),
+            attempts => {
+                write!(
+                    f,
+                    "Could not connect to any of {} addresses",
+                    attempts.len()
+                )?;
+
+                for attempt in attempts {
+                    write!(f, "; {}: {}", attempt.address, attempt.error)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectAttempts {}
+
+impl fmt::Display for HandshakeTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stage {
+            HandshakeStage::Resolve => write!(f, "Handshake timed out resolving the address"),
+            HandshakeStage::Connect => write!(f, "Handshake timed out connecting"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeTimedOut {}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error {
+    pub fn reason(&self) -> ErrorReason {
+        match self {
+            Error::Connect(e) | Error::Socket(e) | Error::Disconnected(e) => e.reason(),
+            _ => ErrorReason::Other,
+        }
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Connect(e) | Error::Socket(e) | Error::Disconnected(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl DisconnectReason {
+    pub fn from_kind(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::TimedOut => DisconnectReason::Timeout,
+            kind => DisconnectReason::IoError(kind),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::LocalRequest => write!(f, "Closed locally"),
+            DisconnectReason::PeerClosed => write!(f, "Closed by the peer"),
+            DisconnectReason::Kicked => write!(f, "Kicked by the server"),
+            DisconnectReason::Timeout => write!(f, "Timed out"),
+            DisconnectReason::IoError(kind) => write!(f, "IO error: {kind}"),
+            DisconnectReason::ProtocolError => write!(f, "Protocol error"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Socket(error.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connect(e) => write!(f, "Error on connection: {e}"),
+            Error::Socket(e) => write!(f, "Socket error: {e}"),
+            Error::Disconnected(e) => write!(f, "Peer disconnected: {e}"),
+            Error::InvalidUtf8(e) => write!(f, "Received message is not valid UTF-8: {e}"),
+            Error::ReceiveLoopRunning => write!(f, "Receive loop is already running"),
+            Error::CallbackPanicked(message) => write!(f, "Callback panicked: {message}"),
+            Error::InvalidTopic(topic) => write!(f, "Invalid topic: \"{topic}\""),
+            Error::QueueClosed => write!(f, "Write queue is closed"),
+            Error::Closed => write!(f, "Connection is closed"),
+            Error::Suspended => write!(f, "Connection is suspended"),
+            Error::FrameTooLarge(size) => {
+                write!(
+                    f,
+                    "Message of {size} bytes exceeds the maximum message size"
+                )
+            }
+            Error::WindowExhausted => write!(f, "Peer's receive window is exhausted"),
+            Error::MemoryBudgetExceeded(limit) => {
+                write!(f, "Memory budget of {limit} bytes exceeded")
+            }
+            Error::Journal(message) => write!(f, "Journal error: {message}"),
+            Error::Inbox(message) => write!(f, "Inbox error: {message}"),
+            Error::RoutingTable(message) => write!(f, "Routing table error: {message}"),
+            Error::ThreadHints(message) => write!(f, "Error applying thread hints: {message}"),
+            Error::Protocol(message) => write!(f, "Protocol error: {message}"),
+            Error::Auth(message) => write!(f, "Authentication error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
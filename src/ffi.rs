@@ -0,0 +1,121 @@
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr;
+
+use crate::{SendTicket, TcpClient};
+
+pub type TcpClientMessageCallback =
+    extern "C" fn(data: *const u8, length: usize, user_data: *mut c_void);
+
+// The user data pointer is owned by the C caller, who is responsible for it
+// being usable from the receive thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+/// # Safety
+///
+/// `address` must be a valid, NUL-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_connect(address: *const c_char) -> *mut TcpClient {
+    if address.is_null() {
+        return ptr::null_mut();
+    }
+
+    let address = match CStr::from_ptr(address).to_str() {
+        Ok(address) => address,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    match TcpClient::connect(address) {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `client` must come from `tcpclient_connect` and not have been freed, and
+/// `data` must point to at least `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_send(
+    client: *const TcpClient,
+    data: *const u8,
+    length: usize,
+) -> c_int {
+    let client = match client.as_ref() {
+        Some(client) => client,
+        None => return -1,
+    };
+
+    let data = if length == 0 {
+        &[]
+    } else if data.is_null() {
+        return -1;
+    } else {
+        std::slice::from_raw_parts(data, length)
+    };
+
+    match client.send(data).and_then(SendTicket::wait) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// # Safety
+///
+/// `client` must come from `tcpclient_connect` and not have been freed.
+/// `callback` is invoked from the receive thread with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_set_callback(
+    client: *mut TcpClient,
+    callback: TcpClientMessageCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let client = match client.as_mut() {
+        Some(client) => client,
+        None => return -1,
+    };
+
+    let user_data = UserData(user_data);
+
+    client.set_on_message_received(move |message| {
+        let user_data = &user_data;
+        callback(message.as_ptr(), message.len(), user_data.0);
+    });
+
+    0
+}
+
+/// # Safety
+///
+/// `client` must come from `tcpclient_connect` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_receive(client: *const TcpClient) -> c_int {
+    match client.as_ref().map(TcpClient::receive) {
+        Some(Ok(())) => 0,
+        _ => -1,
+    }
+}
+
+/// # Safety
+///
+/// `client` must come from `tcpclient_connect` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_disconnect(client: *const TcpClient) -> c_int {
+    match client.as_ref().map(TcpClient::disconnect) {
+        Some(true) => 0,
+        _ => -1,
+    }
+}
+
+/// # Safety
+///
+/// `client` must come from `tcpclient_connect` and must not be used again
+/// afterwards. Passing NULL is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn tcpclient_free(client: *mut TcpClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
@@ -0,0 +1,296 @@
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use crate::queue::Lane;
+use crate::{Error, SendTicket, TcpClient, TcpClientData};
+
+// Every frame starts with a kind byte. Data frames carry the payload; window
+// updates carry a u64 LE number of bytes the sender may add to its credit.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const WINDOW_UPDATE: u8 = 1;
+const WINDOW_UPDATE_SIZE: usize = 9;
+// Waiting senders wake up this often so a close is noticed even if its
+// notification raced with the wait.
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+type OnMessageReceivedCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+struct FlowState {
+    window: u64,
+    // Bytes we may still send. A single message may take it below zero; the
+    // next send then waits for the peer to catch up.
+    credit: Mutex<i64>,
+    credit_changed: Condvar,
+    consumed: Mutex<u64>,
+    closed: Mutex<bool>,
+    on_message_received: Mutex<OnMessageReceivedCallback>,
+}
+
+pub struct FlowClient {
+    client: TcpClient,
+    state: Arc<FlowState>,
+}
+
+impl FlowState {
+    fn handle(&self, data: &TcpClientData, frame: &[u8]) -> Result<(), Error> {
+        match frame.first() {
+            Some(&DATA) => {
+                let on_message_received = match self.on_message_received.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_message_received(&frame[1..]);
+                self.consume(data, frame.len() as u64 - 1)
+            }
+            Some(&WINDOW_UPDATE) if frame.len() == WINDOW_UPDATE_SIZE => {
+                let arr: [u8; 8] = frame[1..].try_into().unwrap();
+                let increment = u64::from_le_bytes(arr).min(i64::MAX as u64) as i64;
+
+                if let Ok(mut credit) = self.credit.lock() {
+                    *credit = credit.saturating_add(increment);
+                }
+
+                self.credit_changed.notify_all();
+                Ok(())
+            }
+            _ => Err(Error::Protocol(
+                "Unrecognized flow control frame".to_string(),
+            )),
+        }
+    }
+
+    // Hands credit back to the peer once half the window has been consumed,
+    // rather than after every message.
+    fn consume(&self, data: &TcpClientData, size: u64) -> Result<(), Error> {
+        let increment = match self.consumed.lock() {
+            Ok(mut consumed) => {
+                *consumed += size;
+
+                if *consumed >= self.window / 2 {
+                    std::mem::take(&mut *consumed)
+                } else {
+                    0
+                }
+            }
+            Err(_) => 0,
+        };
+
+        if increment == 0 {
+            return Ok(());
+        }
+
+        data.send(&window_update(increment), Lane::Control)
+            .map(|_| ())
+    }
+
+    fn close(&self) {
+        if let Ok(mut closed) = self.closed.lock() {
+            *closed = true;
+        }
+
+        self.credit_changed.notify_all();
+    }
+}
+
+impl FlowClient {
+    // Both peers announce their receive window when they start, so neither
+    // can send before the other is ready.
+    pub fn new(mut client: TcpClient, window: u64) -> Self {
+        let state = Arc::new(FlowState {
+            window: window.max(1),
+            credit: Mutex::new(0),
+            credit_changed: Condvar::new(),
+            consumed: Mutex::new(0),
+            closed: Mutex::new(false),
+            on_message_received: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.handle(&data, frame) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let state_ref = state.clone();
+
+        client.set_on_peer_closed_write(move || state_ref.close());
+
+        // Senders waiting for credit check whether the client closed. The
+        // connection may still be replaced, so this only wakes them.
+        let state_ref = state.clone();
+
+        client.set_on_connection_closed(move |_, _, _| state_ref.credit_changed.notify_all());
+
+        // Fails only when the receive loop is already running, which is fine
+        // since it picks up the handlers set above.
+        let _ = client.receive();
+
+        if let Err(e) = client.send(window_update(state.window)) {
+            state.close();
+
+            if let Some(data) = client.downgrade().upgrade() {
+                data.report_error(&e);
+            }
+        }
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // Bytes that can be sent before the peer grants more.
+    pub fn credit(&self) -> i64 {
+        match self.state.credit.lock() {
+            Ok(credit) => *credit,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    // Blocks while the peer's window is exhausted.
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.send_with(payload.as_ref(), None)
+    }
+
+    // Like send, but gives up with Error::WindowExhausted after the timeout.
+    pub fn send_timeout<T>(&self, payload: T, timeout: Duration) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.send_with(payload.as_ref(), Some(timeout))
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    fn send_with(&self, payload: &[u8], timeout: Option<Duration>) -> Result<SendTicket, Error> {
+        self.reserve(payload.len() as i64, timeout)?;
+
+        let mut frame = Vec::with_capacity(1 + payload.len());
+        frame.push(DATA);
+        frame.extend_from_slice(payload);
+
+        self.client.send(frame)
+    }
+
+    fn reserve(&self, size: i64, timeout: Option<Duration>) -> Result<(), Error> {
+        let clock = self.client.downgrade().upgrade().map(|data| data.clock());
+        let deadline = match (&clock, timeout) {
+            (Some(clock), Some(timeout)) => Some(clock.now() + timeout),
+            _ => None,
+        };
+
+        let mut credit = match self.state.credit.lock() {
+            Ok(credit) => credit,
+            Err(e) => e.into_inner(),
+        };
+
+        while *credit <= 0 {
+            if self.is_closed() {
+                return Err(Error::Closed);
+            }
+
+            let wait = match (&clock, deadline) {
+                (Some(clock), Some(deadline)) => {
+                    let remaining = deadline.saturating_duration_since(clock.now());
+
+                    if remaining.is_zero() {
+                        return Err(Error::WindowExhausted);
+                    }
+
+                    remaining.min(CLOSE_CHECK_INTERVAL)
+                }
+                _ => CLOSE_CHECK_INTERVAL,
+            };
+
+            credit = match self.state.credit_changed.wait_timeout(credit, wait) {
+                Ok((credit, _)) => credit,
+                Err(e) => e.into_inner().0,
+            };
+        }
+
+        *credit -= size;
+        Ok(())
+    }
+
+    // Disconnects and error policy closes don't reach the flow state, so
+    // the client is asked as well.
+    fn is_closed(&self) -> bool {
+        let closed = match self.state.closed.lock() {
+            Ok(closed) => *closed,
+            Err(e) => *e.into_inner(),
+        };
+
+        closed
+            || match self.client.downgrade().upgrade() {
+                Some(data) => data.is_closed(),
+                None => true,
+            }
+    }
+}
+
+fn window_update(increment: u64) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(WINDOW_UPDATE_SIZE);
+    frame.push(WINDOW_UPDATE);
+    frame.extend_from_slice(&increment.to_le_bytes());
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client_over, read_frame, within};
+    use crate::MemoryTransport;
+
+    #[test]
+    fn send_waiting_for_credit_fails_once_the_client_disconnects() {
+        let (transport, server) = MemoryTransport::pair();
+        let flow = Arc::new(FlowClient::new(client_over(vec![transport]), 1024));
+
+        // The peer announces no window, so there is no credit to send with.
+        assert_eq!(read_frame(&server).unwrap(), window_update(1024));
+
+        let flow_ref = flow.clone();
+        let sender = std::thread::spawn(move || flow_ref.send("blocked"));
+
+        std::thread::sleep(Duration::from_millis(50));
+        flow.client().disconnect();
+
+        let result = within(move || sender.join().unwrap());
+        assert!(matches!(result, Err(Error::Closed)));
+    }
+
+    #[test]
+    fn send_timeout_notices_a_disconnect_before_the_timeout() {
+        let (transport, server) = MemoryTransport::pair();
+        let flow = Arc::new(FlowClient::new(client_over(vec![transport]), 1024));
+        assert_eq!(read_frame(&server).unwrap(), window_update(1024));
+
+        let flow_ref = flow.clone();
+        let sender =
+            std::thread::spawn(move || flow_ref.send_timeout("blocked", Duration::from_secs(60)));
+
+        std::thread::sleep(Duration::from_millis(50));
+        flow.client().disconnect();
+
+        let result = within(move || sender.join().unwrap());
+        assert!(matches!(result, Err(Error::Closed)));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use crate::protocol::{FRAGMENT_HEADER_SIZE, LAST_FRAGMENT};
+use crate::Error;
+
+pub(crate) fn fragment_header(id: u32, index: u32, last: bool) -> [u8; FRAGMENT_HEADER_SIZE] {
+    let mut header = [0; FRAGMENT_HEADER_SIZE];
+
+    header[0..4].copy_from_slice(&id.to_le_bytes());
+    header[4..8].copy_from_slice(&index.to_le_bytes());
+    header[8] = if last { LAST_FRAGMENT } else { 0 };
+
+    header
+}
+
+pub(crate) struct Reassembler {
+    partial: HashMap<u32, (u32, Vec<u8>)>,
+}
+
+impl Reassembler {
+    pub(crate) fn new() -> Self {
+        Self {
+            partial: HashMap::new(),
+        }
+    }
+
+    // Bytes held by partly reassembled messages.
+    pub(crate) fn buffered(&self) -> usize {
+        self.partial
+            .values()
+            .map(|(_, message)| message.len())
+            .sum()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.partial.clear();
+    }
+
+    pub(crate) fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if frame.len() < FRAGMENT_HEADER_SIZE {
+            return Err(Error::Protocol(format!(
+                "Fragment of {} bytes is shorter than its header",
+                frame.len()
+            )));
+        }
+
+        let id = u32::from_le_bytes(frame[0..4].try_into().unwrap());
+        let index = u32::from_le_bytes(frame[4..8].try_into().unwrap());
+        let last = frame[8] & LAST_FRAGMENT != 0;
+        let chunk = &frame[FRAGMENT_HEADER_SIZE..];
+
+        let (next_index, mut message) = self.partial.remove(&id).unwrap_or_default();
+
+        if index != next_index {
+            return Err(Error::Protocol(format!(
+                "Fragment {index} of message {id} arrived, expected fragment {next_index}"
+            )));
+        }
+
+        message.extend_from_slice(chunk);
+
+        if last {
+            Ok(Some(message))
+        } else {
+            self.partial.insert(id, (next_index + 1, message));
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(id: u32, index: u32, last: bool, chunk: &[u8]) -> Vec<u8> {
+        let mut frame = fragment_header(id, index, last).to_vec();
+        frame.extend_from_slice(chunk);
+        frame
+    }
+
+    #[test]
+    fn fragments_in_order_reassemble_into_the_message() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(
+            reassembler.push(&fragment(1, 0, false, b"hel")).unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler.push(&fragment(1, 1, false, b"lo ")).unwrap(),
+            None
+        );
+        assert_eq!(reassembler.buffered(), 6);
+        assert_eq!(
+            reassembler.push(&fragment(1, 2, true, b"world")).unwrap(),
+            Some(b"hello world".to_vec())
+        );
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn a_single_last_fragment_is_a_whole_message() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(
+            reassembler.push(&fragment(7, 0, true, b"small")).unwrap(),
+            Some(b"small".to_vec())
+        );
+    }
+
+    #[test]
+    fn interleaved_messages_reassemble_separately() {
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(
+            reassembler.push(&fragment(1, 0, false, b"first ")).unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler
+                .push(&fragment(2, 0, false, b"second "))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler.push(&fragment(2, 1, true, b"done")).unwrap(),
+            Some(b"second done".to_vec())
+        );
+        assert_eq!(
+            reassembler.push(&fragment(1, 1, true, b"done")).unwrap(),
+            Some(b"first done".to_vec())
+        );
+    }
+
+    #[test]
+    fn a_missing_fragment_is_a_protocol_error() {
+        let mut reassembler = Reassembler::new();
+
+        reassembler.push(&fragment(1, 0, false, b"hel")).unwrap();
+        assert!(matches!(
+            reassembler.push(&fragment(1, 2, true, b"world")),
+            Err(Error::Protocol(_))
+        ));
+
+        // The broken message is dropped; the id can start over.
+        assert_eq!(reassembler.buffered(), 0);
+        assert_eq!(
+            reassembler.push(&fragment(1, 0, true, b"again")).unwrap(),
+            Some(b"again".to_vec())
+        );
+
+        // A message can't start partway through either.
+        assert!(reassembler.push(&fragment(3, 1, true, b"late")).is_err());
+    }
+
+    #[test]
+    fn a_frame_shorter_than_the_header_is_a_protocol_error() {
+        let mut reassembler = Reassembler::new();
+
+        assert!(matches!(
+            reassembler.push(&[0; FRAGMENT_HEADER_SIZE - 1]),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
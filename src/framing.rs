@@ -0,0 +1,339 @@
+use crate::Error;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    // Every message is preceded by its length as a u64 LE header.
+    #[default]
+    LengthPrefixed,
+    // Every message ends with the delimiter, which is appended on send and
+    // stripped on receive. Lines longer than max_length are reported and
+    // discarded.
+    Delimited {
+        delimiter: Vec<u8>,
+        max_length: usize,
+    },
+    // MQTT control packets, which carry their own variable-length size in
+    // the fixed header. Messages are written exactly as given.
+    Mqtt,
+}
+
+impl Framing {
+    // None for length-prefixed framing, which the receive loop reads
+    // directly into a frame-sized buffer.
+    pub(crate) fn decoder(&self) -> Option<StreamDecoder> {
+        match self {
+            Framing::LengthPrefixed => None,
+            Framing::Delimited {
+                delimiter,
+                max_length,
+            } => Some(StreamDecoder::Lines(LineDecoder::new(
+                delimiter,
+                *max_length,
+            ))),
+            Framing::Mqtt => Some(StreamDecoder::Mqtt(MqttDecoder::default())),
+        }
+    }
+}
+
+pub(crate) enum StreamDecoder {
+    Lines(LineDecoder),
+    Mqtt(MqttDecoder),
+}
+
+impl StreamDecoder {
+    // Returns every message completed by the data, in order. A message
+    // announcing more than max_size bytes, zero for no limit, fails with
+    // Error::FrameTooLarge before any of it is buffered; the rest of the
+    // stream can't be trusted after that.
+    pub(crate) fn push(&mut self, data: &[u8], max_size: usize) -> Vec<Result<Vec<u8>, Error>> {
+        match self {
+            StreamDecoder::Lines(decoder) => decoder.push(data),
+            StreamDecoder::Mqtt(decoder) => decoder.push(data, max_size),
+        }
+    }
+
+    // How many bytes the message still being received will take once
+    // complete, as far as is known yet, so memory is accounted for before
+    // it has all arrived.
+    pub(crate) fn pending_size(&self) -> usize {
+        match self {
+            StreamDecoder::Lines(decoder) => decoder.buffer.len(),
+            StreamDecoder::Mqtt(decoder) => decoder.pending_size(),
+        }
+    }
+
+    // The bytes of the message still being received.
+    pub(crate) fn remaining(&self) -> &[u8] {
+        match self {
+            StreamDecoder::Lines(decoder) => &decoder.buffer,
+            StreamDecoder::Mqtt(decoder) => &decoder.buffer,
+        }
+    }
+}
+
+pub(crate) struct LineDecoder {
+    delimiter: Vec<u8>,
+    max_length: usize,
+    buffer: Vec<u8>,
+    discarding: bool,
+}
+
+impl LineDecoder {
+    fn new(delimiter: &[u8], max_length: usize) -> Self {
+        Self {
+            delimiter: delimiter.to_vec(),
+            max_length,
+            buffer: Vec::new(),
+            discarding: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Vec<Result<Vec<u8>, Error>> {
+        let mut lines = Vec::new();
+
+        self.buffer.extend_from_slice(data);
+
+        while let Some(position) = self.find_delimiter() {
+            let line: Vec<u8> = self.buffer.drain(..position).collect();
+            self.buffer.drain(..self.delimiter.len());
+
+            if self.discarding {
+                self.discarding = false;
+            } else if line.len() > self.max_length {
+                lines.push(Err(self.too_long()));
+            } else {
+                lines.push(Ok(line));
+            }
+        }
+
+        // Keep just enough of an oversized line to recognize a delimiter that
+        // is split across reads.
+        if self.buffer.len() > self.max_length + self.delimiter.len() {
+            if !self.discarding {
+                self.discarding = true;
+                lines.push(Err(self.too_long()));
+            }
+
+            let keep = self.delimiter.len() - 1;
+            self.buffer.drain(..self.buffer.len() - keep);
+        }
+
+        lines
+    }
+
+    fn find_delimiter(&self) -> Option<usize> {
+        self.buffer
+            .windows(self.delimiter.len())
+            .position(|window| window == self.delimiter.as_slice())
+    }
+
+    fn too_long(&self) -> Error {
+        Error::Protocol(format!(
+            "line exceeds the maximum length of {} bytes",
+            self.max_length
+        ))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MqttDecoder {
+    buffer: Vec<u8>,
+}
+
+impl MqttDecoder {
+    fn push(&mut self, data: &[u8], max_size: usize) -> Vec<Result<Vec<u8>, Error>> {
+        let mut packets = Vec::new();
+
+        self.buffer.extend_from_slice(data);
+
+        while self.buffer.len() >= 2 {
+            let (remaining, length_size) = match remaining_length(&self.buffer[1..]) {
+                Ok(Some(length)) => length,
+                Ok(None) => break,
+                Err(e) => {
+                    // Nothing after a malformed header can be trusted.
+                    self.buffer.clear();
+                    packets.push(Err(e));
+                    break;
+                }
+            };
+
+            if max_size > 0 && remaining > max_size {
+                self.buffer.clear();
+                packets.push(Err(Error::FrameTooLarge(remaining)));
+                break;
+            }
+
+            let size = 1 + length_size + remaining;
+
+            if self.buffer.len() < size {
+                break;
+            }
+
+            packets.push(Ok(self.buffer.drain(..size).collect()));
+        }
+
+        packets
+    }
+
+    fn pending_size(&self) -> usize {
+        match self.buffer.get(1..).map(remaining_length) {
+            Some(Ok(Some((remaining, length_size)))) => 1 + length_size + remaining,
+            _ => self.buffer.len(),
+        }
+    }
+}
+
+// Decodes MQTT's remaining length: up to four bytes of seven bits each, least
+// significant first. Returns the length and how many bytes encoded it, or
+// None if more bytes are needed.
+pub(crate) fn remaining_length(bytes: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut length: usize = 0;
+
+    for (index, byte) in bytes.iter().enumerate() {
+        if index == 4 {
+            break;
+        }
+
+        length |= ((byte & 0x7f) as usize) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            return Ok(Some((length, index + 1)));
+        }
+    }
+
+    if bytes.len() >= 4 {
+        return Err(Error::Protocol(
+            "MQTT remaining length is longer than four bytes".to_string(),
+        ));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(delimiter: &str, max_length: usize) -> LineDecoder {
+        LineDecoder::new(delimiter.as_bytes(), max_length)
+    }
+
+    fn ok(lines: Vec<Result<Vec<u8>, Error>>) -> Vec<Vec<u8>> {
+        lines.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn lines_split_across_reads_are_joined() {
+        let mut decoder = lines("\n", 64);
+
+        assert!(decoder.push(b"hel").is_empty());
+        assert_eq!(ok(decoder.push(b"lo\nwor")), [b"hello".to_vec()]);
+        assert_eq!(
+            ok(decoder.push(b"ld\n\nlast\n")),
+            [b"world".to_vec(), Vec::new(), b"last".to_vec()]
+        );
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn a_crlf_delimiter_is_stripped_even_when_split_across_reads() {
+        let mut decoder = lines("\r\n", 64);
+
+        assert!(decoder.push(b"PING\r").is_empty());
+        assert_eq!(
+            ok(decoder.push(b"\nPONG\r\n")),
+            [b"PING".to_vec(), b"PONG".to_vec()]
+        );
+
+        // A lone carriage return is part of the line.
+        assert_eq!(ok(decoder.push(b"a\rb\r\n")), [b"a\rb".to_vec()]);
+    }
+
+    #[test]
+    fn an_over_long_line_is_reported_once_and_discarded() {
+        let mut decoder = lines("\r\n", 4);
+
+        let results = decoder.push(b"toolong");
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::Protocol(_))));
+
+        // The rest of it is dropped without holding on to it, and without
+        // losing a delimiter split across reads.
+        assert!(decoder.push(b"still going\r").is_empty());
+        assert!(decoder.buffer.len() < 4 + 2);
+        assert_eq!(ok(decoder.push(b"\nnext\r\n")), [b"next".to_vec()]);
+    }
+
+    #[test]
+    fn an_over_long_line_completed_in_one_read_is_an_error_in_its_place() {
+        let mut decoder = lines("\n", 4);
+
+        let results = decoder.push(b"ok\ntoo long\nfine\n");
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), b"ok");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), b"fine");
+    }
+
+    #[test]
+    fn mqtt_packets_split_across_reads_are_joined() {
+        let mut decoder = MqttDecoder::default();
+        let publish = [0x30, 3, b'a', b'b', b'c'];
+
+        assert!(decoder.push(&publish[..1], 0).is_empty());
+        assert!(decoder.push(&publish[1..3], 0).is_empty());
+        assert_eq!(decoder.pending_size(), publish.len());
+
+        let mut rest = publish[3..].to_vec();
+        rest.extend_from_slice(&[0xd0, 0]);
+        assert_eq!(
+            ok(decoder.push(&rest, 0)),
+            [publish.to_vec(), vec![0xd0, 0]]
+        );
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn remaining_length_takes_up_to_four_bytes() {
+        assert_eq!(remaining_length(&[0]).unwrap(), Some((0, 1)));
+        assert_eq!(remaining_length(&[0x7f]).unwrap(), Some((127, 1)));
+        assert_eq!(remaining_length(&[0x80, 0x01]).unwrap(), Some((128, 2)));
+        assert_eq!(
+            remaining_length(&[0xff, 0xff, 0xff, 0x7f]).unwrap(),
+            Some((268_435_455, 4))
+        );
+
+        // Incomplete until a byte without the continuation bit arrives.
+        assert_eq!(remaining_length(&[]).unwrap(), None);
+        assert_eq!(remaining_length(&[0x80, 0x80, 0x80]).unwrap(), None);
+    }
+
+    #[test]
+    fn a_fifth_remaining_length_byte_is_a_protocol_error() {
+        assert!(matches!(
+            remaining_length(&[0x80, 0x80, 0x80, 0x80]),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            remaining_length(&[0xff, 0xff, 0xff, 0xff, 0x01]),
+            Err(Error::Protocol(_))
+        ));
+
+        let mut decoder = MqttDecoder::default();
+        let results = decoder.push(&[0x30, 0x80, 0x80, 0x80, 0x80, 0x01], 0);
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::Protocol(_))));
+        assert!(decoder.buffer.is_empty());
+    }
+
+    #[test]
+    fn an_mqtt_packet_over_the_size_limit_fails_before_it_is_buffered() {
+        let mut decoder = MqttDecoder::default();
+
+        let results = decoder.push(&[0x30, 0x80, 0x01], 100);
+        assert_eq!(results.len(), 1);
+        assert!(matches!(results[0], Err(Error::FrameTooLarge(128))));
+        assert!(decoder.buffer.is_empty());
+    }
+}
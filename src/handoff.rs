@@ -0,0 +1,269 @@
+use crate::mqtt::QoS;
+use crate::sequence::SequenceCounters;
+use crate::{Error, QueueSnapshot};
+
+// Encoded state is the magic bytes and a format version, then the control
+// and data lanes, each a u32 LE message count and per message a u64 LE
+// length and the bytes, then a u8 that is 1 when sequence counters follow
+// as two u64 LE. Version 2 adds a u32 LE subscription count and per
+// subscription a u32 LE length, the filter and its QoS as a u8.
+const MAGIC: &[u8; 4] = b"TCST";
+const FORMAT_VERSION: u8 = 2;
+
+// What a client hands to the instance replacing it, e.g. after a config
+// reload, so the new one carries on where the old one stopped. Callbacks
+// and handlers can't be carried over and are registered again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientState {
+    // Messages that were still waiting in the old client's write queue.
+    pub pending: QueueSnapshot,
+    // Set when exported from a SequencedClient.
+    pub sequence: Option<SequenceCounters>,
+    // Set when exported from an MqttClient or a TopicClient.
+    pub subscriptions: Vec<TopicSubscription>,
+}
+
+// A filter the old client was subscribed to. Handlers can't be carried
+// over, so the importing layer takes one for all of them. TopicClient
+// patterns export as AtMostOnce.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicSubscription {
+    pub filter: String,
+    pub qos: QoS,
+}
+
+impl ClientState {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(FORMAT_VERSION);
+
+        for lane in [&self.pending.control, &self.pending.data] {
+            encoded.extend_from_slice(&(lane.len() as u32).to_le_bytes());
+
+            for message in lane {
+                encoded.extend_from_slice(&(message.len() as u64).to_le_bytes());
+                encoded.extend_from_slice(message);
+            }
+        }
+
+        match self.sequence {
+            Some(counters) => {
+                encoded.push(1);
+                encoded.extend_from_slice(&counters.next_sequence.to_le_bytes());
+                encoded.extend_from_slice(&counters.last_received.to_le_bytes());
+            }
+            None => encoded.push(0),
+        }
+
+        encoded.extend_from_slice(&(self.subscriptions.len() as u32).to_le_bytes());
+
+        for subscription in &self.subscriptions {
+            encoded.extend_from_slice(&(subscription.filter.len() as u32).to_le_bytes());
+            encoded.extend_from_slice(subscription.filter.as_bytes());
+            encoded.push(match subscription.qos {
+                QoS::AtMostOnce => 0,
+                QoS::AtLeastOnce => 1,
+            });
+        }
+
+        encoded
+    }
+
+    pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+        if !encoded.starts_with(MAGIC) {
+            return Err(Error::Protocol("Not an encoded client state".to_string()));
+        }
+
+        let mut reader = StateReader {
+            encoded,
+            position: MAGIC.len(),
+        };
+        let format_version = reader.take(1)?[0];
+
+        // Version 1 states, without subscriptions, still import.
+        if format_version == 0 || format_version > FORMAT_VERSION {
+            return Err(Error::Protocol(format!(
+                "Unknown client state format version {format_version}"
+            )));
+        }
+
+        let mut state = ClientState::default();
+
+        for lane in [&mut state.pending.control, &mut state.pending.data] {
+            let count = reader.u32()?;
+
+            for _ in 0..count {
+                let length = reader.u64()?;
+                let length = usize::try_from(length).map_err(|_| reader.truncated())?;
+
+                lane.push(reader.take(length)?.to_vec());
+            }
+        }
+
+        if reader.take(1)?[0] == 1 {
+            state.sequence = Some(SequenceCounters {
+                next_sequence: reader.u64()?,
+                last_received: reader.u64()?,
+            });
+        }
+
+        if format_version >= 2 {
+            let count = reader.u32()?;
+
+            for _ in 0..count {
+                let length = reader.u32()? as usize;
+                let filter = std::str::from_utf8(reader.take(length)?).map_err(|_| {
+                    Error::Protocol("Subscription filter is not valid UTF-8".to_string())
+                })?;
+                let qos = match reader.take(1)?[0] {
+                    0 => QoS::AtMostOnce,
+                    1 => QoS::AtLeastOnce,
+                    code => {
+                        return Err(Error::Protocol(format!("Unknown subscription QoS {code}")))
+                    }
+                };
+
+                state.subscriptions.push(TopicSubscription {
+                    filter: filter.to_string(),
+                    qos,
+                });
+            }
+        }
+
+        if reader.position != encoded.len() {
+            return Err(Error::Protocol(
+                "Encoded client state has trailing bytes".to_string(),
+            ));
+        }
+
+        Ok(state)
+    }
+}
+
+struct StateReader<'a> {
+    encoded: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], Error> {
+        let end = self.position.saturating_add(size);
+
+        if end > self.encoded.len() {
+            return Err(self.truncated());
+        }
+
+        let bytes = &self.encoded[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn truncated(&self) -> Error {
+        Error::Protocol("Encoded client state is truncated".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> ClientState {
+        ClientState {
+            pending: QueueSnapshot {
+                control: vec![b"ack".to_vec()],
+                data: vec![b"first".to_vec(), Vec::new(), b"third".to_vec()],
+            },
+            sequence: Some(SequenceCounters {
+                next_sequence: 42,
+                last_received: 7,
+            }),
+            subscriptions: vec![
+                TopicSubscription {
+                    filter: "sensors/+/temperature".to_string(),
+                    qos: QoS::AtLeastOnce,
+                },
+                TopicSubscription {
+                    filter: "alerts/#".to_string(),
+                    qos: QoS::AtMostOnce,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn states_round_trip_through_their_encoding() {
+        for state in [state(), ClientState::default()] {
+            assert_eq!(ClientState::decode(&state.encode()).unwrap(), state);
+        }
+    }
+
+    #[test]
+    fn every_truncation_is_rejected() {
+        let encoded = state().encode();
+
+        for length in 0..encoded.len() {
+            assert!(
+                matches!(
+                    ClientState::decode(&encoded[..length]),
+                    Err(Error::Protocol(_))
+                ),
+                "decoded {length} of {} bytes",
+                encoded.len()
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut encoded = state().encode();
+        encoded.push(0);
+
+        assert!(matches!(
+            ClientState::decode(&encoded),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn a_huge_message_length_is_truncation_rather_than_an_allocation() {
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(FORMAT_VERSION);
+        encoded.extend_from_slice(&1u32.to_le_bytes());
+        encoded.extend_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(matches!(
+            ClientState::decode(&encoded),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn version_one_states_decode_without_subscriptions() {
+        let state = ClientState {
+            subscriptions: Vec::new(),
+            ..state()
+        };
+        let mut encoded = state.encode();
+        encoded[MAGIC.len()] = 1;
+        encoded.truncate(encoded.len() - 4);
+
+        assert_eq!(ClientState::decode(&encoded).unwrap(), state);
+    }
+
+    #[test]
+    fn unknown_versions_and_foreign_bytes_are_rejected() {
+        let mut encoded = state().encode();
+        encoded[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert!(ClientState::decode(&encoded).is_err());
+
+        assert!(ClientState::decode(b"not a state").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::protocol::{IDENTITY_FORMAT_VERSION as FORMAT_VERSION, IDENTITY_MAGIC as MAGIC};
+use crate::Error;
+
+// An identity frame is the magic bytes and a format version, then the app
+// name, version and instance ID, each a u8 length and UTF-8 text, then a u8
+// field count and per field a u8 key length, the key, a u16 LE value length
+// and the value.
+
+// Who is connecting, sent as the first frame on every connection so that
+// servers can log and route by client. A server reads it back with decode.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub app_name: String,
+    pub version: String,
+    pub instance_id: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl ClientIdentity {
+    pub fn new(app_name: &str, version: &str) -> Self {
+        Self {
+            app_name: app_name.to_string(),
+            version: version.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_instance_id(mut self, instance_id: &str) -> Self {
+        self.instance_id = instance_id.to_string();
+        self
+    }
+
+    // Adds a field, replacing any earlier one with the same key.
+    pub fn with_field(mut self, key: &str, value: &str) -> Self {
+        self.fields.retain(|(existing, _)| existing != key);
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn field(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Whether a frame is an identity frame, so that a server can tell it
+    // apart from application data without decoding it.
+    pub fn is_identity_frame(frame: &[u8]) -> bool {
+        frame.starts_with(MAGIC)
+    }
+
+    // The app name, version, instance ID and keys are at most 255 bytes,
+    // values 65535, and there are at most 255 fields.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut frame = MAGIC.to_vec();
+        frame.push(FORMAT_VERSION);
+
+        for (name, text) in [
+            ("App name", &self.app_name),
+            ("Version", &self.version),
+            ("Instance ID", &self.instance_id),
+        ] {
+            let length = u8::try_from(text.len())
+                .map_err(|_| Error::Protocol(format!("{name} is longer than 255 bytes")))?;
+
+            frame.push(length);
+            frame.extend_from_slice(text.as_bytes());
+        }
+
+        let count = u8::try_from(self.fields.len())
+            .map_err(|_| Error::Protocol("More than 255 identity fields".to_string()))?;
+        frame.push(count);
+
+        for (key, value) in &self.fields {
+            let key_length = u8::try_from(key.len()).map_err(|_| {
+                Error::Protocol("Identity field key is longer than 255 bytes".to_string())
+            })?;
+            let value_length = u16::try_from(value.len())
+                .map_err(|_| Error::Protocol(format!("Identity field \"{key}\" is too long")))?;
+
+            frame.push(key_length);
+            frame.extend_from_slice(key.as_bytes());
+            frame.extend_from_slice(&value_length.to_le_bytes());
+            frame.extend_from_slice(value.as_bytes());
+        }
+
+        Ok(frame)
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, Error> {
+        if !Self::is_identity_frame(frame) {
+            return Err(Error::Protocol("Not an identity frame".to_string()));
+        }
+
+        let mut reader = FrameReader {
+            frame,
+            position: MAGIC.len(),
+        };
+        let format_version = reader.take(1)?[0];
+
+        if format_version != FORMAT_VERSION {
+            return Err(Error::Protocol(format!(
+                "Unknown identity format version {format_version}"
+            )));
+        }
+
+        let mut identity = ClientIdentity::default();
+
+        for text in [
+            &mut identity.app_name,
+            &mut identity.version,
+            &mut identity.instance_id,
+        ] {
+            let length = reader.take(1)?[0] as usize;
+            *text = reader.text(length)?;
+        }
+
+        let count = reader.take(1)?[0];
+
+        for _ in 0..count {
+            let key_length = reader.take(1)?[0] as usize;
+            let key = reader.text(key_length)?;
+            let value_length = u16::from_le_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+            let value = reader.text(value_length)?;
+
+            identity.fields.push((key, value));
+        }
+
+        Ok(identity)
+    }
+}
+
+struct FrameReader<'a> {
+    frame: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], Error> {
+        let end = self.position + size;
+
+        if end > self.frame.len() {
+            return Err(Error::Protocol("Identity frame is truncated".to_string()));
+        }
+
+        let bytes = &self.frame[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn text(&mut self, size: usize) -> Result<String, Error> {
+        let bytes = self.take(size)?;
+
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(e) => Err(Error::InvalidUtf8(e)),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, TcpClient};
+
+type OnMessageReceivedCallback = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+
+struct Inbox {
+    directory: PathBuf,
+    next_id: AtomicU64,
+    on_message_received: Mutex<OnMessageReceivedCallback>,
+}
+
+// Every received message is written to its own file in the inbox directory
+// before the callback sees it, and stays there until it is acked. Messages
+// left over from a crash are handed out again by redeliver.
+pub struct InboxClient {
+    client: TcpClient,
+    inbox: Arc<Inbox>,
+}
+
+impl Inbox {
+    fn open(directory: &Path) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        Self::remove_temporaries(directory)?;
+
+        let last_id = Self::list(directory)?.last().copied();
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            next_id: AtomicU64::new(last_id.map_or(0, |id| id + 1)),
+            on_message_received: Mutex::new(Arc::new(|_, _| {})),
+        })
+    }
+
+    // Left behind by a crash while persisting. Only done on open: at runtime
+    // a temporary file may be one the receive thread is still writing.
+    fn remove_temporaries(directory: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.ends_with(".tmp"))
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn list(directory: &Path) -> io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+
+        for entry in fs::read_dir(directory)? {
+            let name = entry?.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".msg"))
+                .and_then(|id| id.parse::<u64>().ok());
+
+            if let Some(id) = id {
+                ids.push(id);
+            }
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.directory.join(format!("{id:020}.msg"))
+    }
+
+    // Written to a temporary file and renamed, so a crash never leaves a
+    // truncated message behind. The directory is synced after the rename,
+    // which would otherwise only be in memory.
+    fn persist(&self, message: &[u8]) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let temporary = self.directory.join(format!("{id:020}.tmp"));
+        let mut file = File::create(&temporary)?;
+
+        file.write_all(message)?;
+        file.sync_all()?;
+        fs::rename(&temporary, self.path(id))?;
+        sync_directory(&self.directory)?;
+
+        Ok(id)
+    }
+
+    fn callback(&self) -> OnMessageReceivedCallback {
+        match self.on_message_received.lock() {
+            Ok(callback) => callback.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+}
+
+impl InboxClient {
+    pub fn new<P: AsRef<Path>>(mut client: TcpClient, directory: P) -> Result<Self, Error> {
+        let inbox = match Inbox::open(directory.as_ref()) {
+            Ok(inbox) => Arc::new(inbox),
+            Err(e) => return Err(Error::Inbox(e.to_string())),
+        };
+        let inbox_ref = inbox.clone();
+        let data = client.downgrade();
+
+        client.set_on_message_received(move |message| match inbox_ref.persist(message) {
+            Ok(id) => inbox_ref.callback()(id, message),
+            Err(e) => {
+                if let Some(data) = data.upgrade() {
+                    data.report_error(&Error::Inbox(e.to_string()));
+                }
+            }
+        });
+
+        Ok(Self { client, inbox })
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        match self.inbox.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    pub fn ack(&self, id: u64) -> Result<(), Error> {
+        match fs::remove_file(self.inbox.path(id)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(Error::Inbox(e.to_string())),
+        }
+    }
+
+    // Unacked messages, oldest first.
+    pub fn pending(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let inbox_error = |e: io::Error| Error::Inbox(e.to_string());
+        let mut pending = Vec::new();
+
+        for id in Inbox::list(&self.inbox.directory).map_err(inbox_error)? {
+            match fs::read(self.inbox.path(id)) {
+                Ok(message) => pending.push((id, message)),
+                // Acked concurrently.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(inbox_error(e)),
+            }
+        }
+
+        Ok(pending)
+    }
+
+    // Passes every unacked message to the callback again. Returns how many
+    // were delivered.
+    pub fn redeliver(&self) -> Result<usize, Error> {
+        let pending = self.pending()?;
+        let callback = self.inbox.callback();
+
+        for (id, message) in &pending {
+            callback(*id, message);
+        }
+
+        Ok(pending.len())
+    }
+}
+
+// Windows can't open a directory as a file; its renames are durable once
+// they return.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{client_over, write_frame, TempDir, TIMEOUT};
+    use crate::MemoryTransport;
+
+    // Receives the given messages into a fresh inbox client over directory
+    // and returns the ids they were persisted as, without acking any.
+    fn receive(directory: &Path, messages: &[&[u8]]) -> (InboxClient, Vec<u64>) {
+        let (transport, server) = MemoryTransport::pair();
+        let mut inbox = InboxClient::new(client_over(vec![transport]), directory).unwrap();
+
+        let (sender, received) = channel();
+        let sender = Mutex::new(sender);
+        inbox.set_on_message_received(move |id, message| {
+            let _ = sender.lock().unwrap().send((id, message.to_vec()));
+        });
+        inbox.client().receive().unwrap();
+
+        let mut ids = Vec::new();
+        for message in messages {
+            write_frame(&server, message);
+
+            let (id, received) = received.recv_timeout(TIMEOUT).unwrap();
+            assert_eq!(&received, message);
+            ids.push(id);
+        }
+
+        (inbox, ids)
+    }
+
+    #[test]
+    fn unacked_messages_survive_a_crash_and_are_redelivered() {
+        let directory = TempDir::new("inbox-redeliver");
+        let (inbox, ids) = receive(directory.path(), &[b"first", b"second"]);
+
+        // Crashes without acking, halfway through persisting a third.
+        drop(inbox);
+        fs::write(
+            directory.path().join(format!("{:020}.tmp", ids[1] + 1)),
+            b"thi",
+        )
+        .unwrap();
+
+        let (mut inbox, _) = receive(directory.path(), &[]);
+        assert_eq!(
+            inbox.pending().unwrap(),
+            vec![(ids[0], b"first".to_vec()), (ids[1], b"second".to_vec())]
+        );
+
+        let (sender, redelivered) = channel();
+        let sender = Mutex::new(sender);
+        inbox.set_on_message_received(move |id, _| {
+            let _ = sender.lock().unwrap().send(id);
+        });
+        assert_eq!(inbox.redeliver().unwrap(), 2);
+        assert_eq!(redelivered.try_iter().collect::<Vec<_>>(), ids);
+
+        // The half-written one is gone, and new messages don't reuse ids.
+        let files = fs::read_dir(directory.path()).unwrap().count();
+        assert_eq!(files, 2);
+        drop(inbox);
+
+        let (_inbox, new_ids) = receive(directory.path(), &[b"third"]);
+        assert!(new_ids[0] > ids[1]);
+    }
+
+    #[test]
+    fn acked_messages_are_not_redelivered() {
+        let directory = TempDir::new("inbox-ack");
+        let (inbox, ids) = receive(directory.path(), &[b"first", b"second"]);
+
+        inbox.ack(ids[0]).unwrap();
+        assert_eq!(inbox.pending().unwrap(), vec![(ids[1], b"second".to_vec())]);
+        assert!(matches!(inbox.ack(ids[0]), Err(Error::Inbox(_))));
+
+        drop(inbox);
+        let (inbox, _) = receive(directory.path(), &[]);
+        assert_eq!(inbox.redeliver().unwrap(), 1);
+    }
+
+    #[test]
+    fn listing_leaves_files_being_persisted_alone() {
+        let directory = TempDir::new("inbox-in-flight");
+        let (inbox, _) = receive(directory.path(), &[]);
+
+        let in_flight = directory.path().join(format!("{:020}.tmp", 0));
+        fs::write(&in_flight, b"half").unwrap();
+
+        assert!(inbox.pending().unwrap().is_empty());
+        assert!(in_flight.exists());
+    }
+}
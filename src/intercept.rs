@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use crate::Error;
+
+// Wraps every message the client sends in an application envelope, like a
+// tenant ID or an auth token, and strips it from every message received,
+// so neither call sites nor the layers built on the client deal with it.
+// Outbound runs before the message is size-checked and framed.
+pub trait Interceptor: Send + Sync {
+    // A send that fails here returns the error.
+    fn outbound(&self, payload: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    // A message that fails here is reported to on_error and dropped.
+    fn inbound(&self, payload: Vec<u8>) -> Result<Vec<u8>, Error>;
+}
+
+// Outbound in the order the interceptors were added and inbound in reverse,
+// so the last one added wraps outermost and is the first to strip.
+pub(crate) fn wrap(
+    interceptors: &[Arc<dyn Interceptor>],
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    interceptors
+        .iter()
+        .try_fold(payload.to_vec(), |payload, interceptor| {
+            interceptor.outbound(payload)
+        })
+}
+
+pub(crate) fn unwrap(
+    interceptors: &[Arc<dyn Interceptor>],
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    interceptors
+        .iter()
+        .rev()
+        .try_fold(payload.to_vec(), |payload, interceptor| {
+            interceptor.inbound(payload)
+        })
+}
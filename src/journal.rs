@@ -0,0 +1,370 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Error, TcpClient};
+
+// Each record is the timestamp in microseconds since the Unix epoch (u64 LE),
+// a direction byte and the message length (u64 LE), followed by the message.
+const RECORD_HEADER_SIZE: usize = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    // Once the current file would grow past this size it is rotated to
+    // "<path>.1", shifting older files up to "<path>.<max_files>".
+    pub max_file_size: u64,
+    pub max_files: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+pub(crate) struct Journal {
+    config: JournalConfig,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+pub struct JournalReader {
+    reader: BufReader<File>,
+}
+
+impl JournalConfig {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_file_size: 16 * 1024 * 1024,
+            max_files: 4,
+        }
+    }
+}
+
+impl Journal {
+    pub(crate) fn open(config: JournalConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            config,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    pub(crate) fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let record_size = (RECORD_HEADER_SIZE + data.len()) as u64;
+
+        if self.size > 0 && self.size + record_size > self.config.max_file_size {
+            self.rotate()?;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let direction = match direction {
+            Direction::Inbound => 0u8,
+            Direction::Outbound => 1u8,
+        };
+
+        self.file.write_all(&timestamp.to_le_bytes())?;
+        self.file.write_all(&[direction])?;
+        self.file.write_all(&(data.len() as u64).to_le_bytes())?;
+        self.file.write_all(data)?;
+        self.file.flush()?;
+        self.size += record_size;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.config.max_files == 0 {
+            self.file = BufWriter::new(File::create(&self.config.path)?);
+            self.size = 0;
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(rotated_path(&self.config.path, self.config.max_files));
+
+        for index in (1..self.config.max_files).rev() {
+            let from = rotated_path(&self.config.path, index);
+
+            if from.exists() {
+                fs::rename(from, rotated_path(&self.config.path, index + 1))?;
+            }
+        }
+
+        fs::rename(&self.config.path, rotated_path(&self.config.path, 1))?;
+
+        self.file = BufWriter::new(File::create(&self.config.path)?);
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl JournalReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+
+    fn read_entry(&mut self, mut header: [u8; RECORD_HEADER_SIZE]) -> io::Result<JournalEntry> {
+        self.reader.read_exact(&mut header[1..])?;
+
+        let timestamp = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown journal direction {other}"),
+                ))
+            }
+        };
+        // The length comes from the file, so it is only trusted as far as
+        // there are bytes to back it: a corrupt header fails instead of
+        // allocating what it claims.
+        let length = u64::from_le_bytes(header[9..17].try_into().unwrap());
+        let mut data = Vec::new();
+
+        (&mut self.reader).take(length).read_to_end(&mut data)?;
+
+        if (data.len() as u64) < length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "journal entry announces {length} bytes but only {} remain",
+                    data.len()
+                ),
+            ));
+        }
+
+        Ok(JournalEntry {
+            timestamp: UNIX_EPOCH + Duration::from_micros(timestamp),
+            direction,
+            data,
+        })
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = io::Result<JournalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+
+        match self.reader.read(&mut header[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+
+        Some(self.read_entry(header))
+    }
+}
+
+impl std::fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let direction = match self.direction {
+            Direction::Inbound => "<-",
+            Direction::Outbound => "->",
+        };
+
+        write!(f, "{micros} {direction} {} bytes:", self.data.len())?;
+
+        for byte in &self.data {
+            write!(f, " {byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Writes every entry of a journal to the given writer, one per line.
+pub fn dump_journal<P, W>(path: P, mut writer: W) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    for entry in JournalReader::open(path)? {
+        writeln!(writer, "{}", entry?)?;
+    }
+
+    Ok(())
+}
+
+// Sends the journal's outbound messages again, in order and without the
+// original timing. Returns how many were sent.
+pub fn replay_journal<P: AsRef<Path>>(path: P, client: &TcpClient) -> Result<usize, Error> {
+    let mut sent: usize = 0;
+
+    let journal_error = |e: io::Error| Error::Journal(e.to_string());
+
+    for entry in JournalReader::open(path).map_err(journal_error)? {
+        let entry = entry.map_err(journal_error)?;
+
+        if entry.direction == Direction::Outbound {
+            client.send(&entry.data)?.wait()?;
+            sent += 1;
+        }
+    }
+
+    Ok(sent)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn read_all(path: &Path) -> Vec<(Direction, Vec<u8>)> {
+        JournalReader::open(path)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.direction, entry.data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recorded_entries_read_back_in_order() {
+        let directory = TempDir::new("journal-round-trip");
+        let path = directory.path().join("journal");
+        let mut journal = Journal::open(JournalConfig::new(&path)).unwrap();
+
+        journal.record(Direction::Outbound, b"hello").unwrap();
+        journal.record(Direction::Inbound, b"").unwrap();
+        journal.record(Direction::Inbound, b"world").unwrap();
+
+        assert_eq!(
+            read_all(&path),
+            vec![
+                (Direction::Outbound, b"hello".to_vec()),
+                (Direction::Inbound, Vec::new()),
+                (Direction::Inbound, b"world".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_shifts_full_files_and_drops_the_oldest() {
+        let directory = TempDir::new("journal-rotate");
+        let path = directory.path().join("journal");
+        let record_size = (RECORD_HEADER_SIZE + 1) as u64;
+        let mut journal = Journal::open(JournalConfig {
+            path: path.clone(),
+            max_file_size: record_size * 2,
+            max_files: 2,
+        })
+        .unwrap();
+
+        for byte in b"abcdefg" {
+            journal.record(Direction::Outbound, &[*byte]).unwrap();
+        }
+
+        let data = |path: &Path| -> Vec<Vec<u8>> {
+            read_all(path).into_iter().map(|(_, data)| data).collect()
+        };
+        assert_eq!(data(&path), vec![b"g".to_vec()]);
+        assert_eq!(
+            data(&rotated_path(&path, 1)),
+            vec![b"e".to_vec(), b"f".to_vec()]
+        );
+        assert_eq!(
+            data(&rotated_path(&path, 2)),
+            vec![b"c".to_vec(), b"d".to_vec()]
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn reopening_appends_to_the_current_file() {
+        let directory = TempDir::new("journal-reopen");
+        let path = directory.path().join("journal");
+
+        Journal::open(JournalConfig::new(&path))
+            .unwrap()
+            .record(Direction::Outbound, b"first")
+            .unwrap();
+        Journal::open(JournalConfig::new(&path))
+            .unwrap()
+            .record(Direction::Outbound, b"second")
+            .unwrap();
+
+        assert_eq!(read_all(&path).len(), 2);
+    }
+
+    #[test]
+    fn a_corrupt_length_is_invalid_data_instead_of_a_huge_allocation() {
+        let directory = TempDir::new("journal-corrupt");
+        let path = directory.path().join("journal");
+        let mut record = vec![0; RECORD_HEADER_SIZE];
+        record[9..17].copy_from_slice(&u64::MAX.to_le_bytes());
+        record.extend_from_slice(b"short");
+        fs::write(&path, record).unwrap();
+
+        let error = JournalReader::open(&path)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_truncated_entry_fails_to_read() {
+        let directory = TempDir::new("journal-truncated");
+        let path = directory.path().join("journal");
+        let mut journal = Journal::open(JournalConfig::new(&path)).unwrap();
+        journal.record(Direction::Inbound, b"hello world").unwrap();
+        drop(journal);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+
+        let mut reader = JournalReader::open(&path).unwrap();
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // A cut in the header itself is an unexpected end of file.
+        fs::write(&path, &bytes[..5]).unwrap();
+        let error = JournalReader::open(&path)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,389 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::{DisconnectReason, Error, SendTicket, TcpClient, TcpClientData};
+
+// Every frame starts with a kind byte. Data frames carry the payload; kick
+// and timeout notices, sent by the server just before it closes, carry a
+// reason code, a u64 LE retry-after in milliseconds, zero for none, and a
+// UTF-8 message for people.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const KICKED: u8 = 1;
+pub(crate) const TIMED_OUT: u8 = 2;
+const NOTICE_HEADER_SIZE: usize = 10;
+
+// Why the server is closing the connection. Codes this version doesn't know
+// come through as Other, so servers can add reasons without breaking older
+// clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KickReason {
+    Unspecified,
+    ServerShutdown,
+    Overloaded,
+    PolicyViolation,
+    // Another connection logged in with the same identity.
+    SessionReplaced,
+    Idle,
+    Other(u8),
+}
+
+impl KickReason {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => KickReason::Unspecified,
+            1 => KickReason::ServerShutdown,
+            2 => KickReason::Overloaded,
+            3 => KickReason::PolicyViolation,
+            4 => KickReason::SessionReplaced,
+            5 => KickReason::Idle,
+            code => KickReason::Other(code),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            KickReason::Unspecified => 0,
+            KickReason::ServerShutdown => 1,
+            KickReason::Overloaded => 2,
+            KickReason::PolicyViolation => 3,
+            KickReason::SessionReplaced => 4,
+            KickReason::Idle => 5,
+            KickReason::Other(code) => code,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KickNotice {
+    // A timeout notice rather than a kick; the connection then ends as
+    // DisconnectReason::Timeout instead of Kicked.
+    pub timed_out: bool,
+    pub reason: KickReason,
+    pub message: String,
+    // How long the server wants the client to wait before reconnecting.
+    pub retry_after: Option<Duration>,
+}
+
+impl KickNotice {
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        if self.timed_out {
+            DisconnectReason::Timeout
+        } else {
+            DisconnectReason::Kicked
+        }
+    }
+
+    // The frame a server sends, for servers and test peers built on this
+    // crate. Delays are sent in whole milliseconds.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let retry_after = match self.retry_after {
+            Some(delay) => (delay.as_millis() as u64).max(1),
+            None => 0,
+        };
+
+        let mut frame = Vec::with_capacity(NOTICE_HEADER_SIZE + self.message.len());
+        frame.push(if self.timed_out { TIMED_OUT } else { KICKED });
+        frame.push(self.reason.code());
+        frame.extend_from_slice(&retry_after.to_le_bytes());
+        frame.extend_from_slice(self.message.as_bytes());
+        frame
+    }
+
+    fn parse(frame: &[u8]) -> Result<Self, Error> {
+        if frame.len() < NOTICE_HEADER_SIZE {
+            return Err(Error::Protocol(
+                "Kick notice is too short to carry a reason".to_string(),
+            ));
+        }
+
+        let retry_after = u64::from_le_bytes(frame[2..NOTICE_HEADER_SIZE].try_into().unwrap());
+
+        Ok(Self {
+            timed_out: frame[0] == TIMED_OUT,
+            reason: KickReason::from_code(frame[1]),
+            message: String::from_utf8_lossy(&frame[NOTICE_HEADER_SIZE..]).into_owned(),
+            retry_after: match retry_after {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+        })
+    }
+}
+
+type OnMessageReceivedCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+type OnKickedCallback = Arc<dyn Fn(&KickNotice) + Send + Sync>;
+
+struct KickState {
+    last_notice: Mutex<Option<KickNotice>>,
+    on_message_received: Mutex<OnMessageReceivedCallback>,
+    on_kicked: Mutex<OnKickedCallback>,
+}
+
+// Understands the notices a server sends before it kicks the client or
+// times it out: the connection's end is reported with the notice's reason,
+// and a retry-after in it delays the next reconnect attempt instead of the
+// reconnect policy.
+pub struct KickClient {
+    client: TcpClient,
+    state: Arc<KickState>,
+}
+
+impl KickState {
+    fn handle(&self, data: &TcpClientData, frame: &[u8]) -> Result<(), Error> {
+        match frame.first() {
+            Some(&DATA) => {
+                let on_message_received = match self.on_message_received.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_message_received(&frame[1..]);
+                Ok(())
+            }
+            Some(&KICKED) | Some(&TIMED_OUT) => {
+                let notice = KickNotice::parse(frame)?;
+                data.announce_disconnect(notice.disconnect_reason(), notice.retry_after);
+
+                match self.last_notice.lock() {
+                    Ok(mut last_notice) => *last_notice = Some(notice.clone()),
+                    Err(e) => *e.into_inner() = Some(notice.clone()),
+                }
+
+                let on_kicked = match self.on_kicked.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_kicked(&notice);
+                Ok(())
+            }
+            _ => Err(Error::Protocol("Unrecognized kick frame".to_string())),
+        }
+    }
+}
+
+impl KickClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(KickState {
+            last_notice: Mutex::new(None),
+            on_message_received: Mutex::new(Arc::new(|_| {})),
+            on_kicked: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.handle(&data, frame) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // The last notice received, kept across reconnects.
+    pub fn last_notice(&self) -> Option<KickNotice> {
+        match self.state.last_notice.lock() {
+            Ok(last_notice) => last_notice.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.client.send(frame(DATA, payload.as_ref()))
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    // Called when a notice arrives, before the server closes the
+    // connection.
+    pub fn set_on_kicked<F>(&mut self, callback: F)
+    where
+        F: Fn(&KickNotice) + Send + Sync + 'static,
+    {
+        match self.state.on_kicked.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{
+        client_over, instant_reconnects, read_frame, wait_until, write_frame, TIMEOUT,
+    };
+    use crate::{
+        Clock, ErrorAction, ErrorPolicy, MemoryTransport, MockClock, ReconnectPolicy, Transport,
+    };
+
+    fn notice(timed_out: bool, retry_after: Option<Duration>) -> KickNotice {
+        KickNotice {
+            timed_out,
+            reason: KickReason::ServerShutdown,
+            message: "Restarting for an upgrade".to_string(),
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn notices_round_trip_through_their_frame() {
+        for notice in [
+            notice(false, Some(Duration::from_secs(30))),
+            notice(true, None),
+        ] {
+            assert_eq!(KickNotice::parse(&notice.to_frame()).unwrap(), notice);
+        }
+    }
+
+    #[test]
+    fn unknown_reason_codes_are_kept() {
+        let mut frame = notice(false, None).to_frame();
+        frame[1] = 200;
+
+        let parsed = KickNotice::parse(&frame).unwrap();
+        assert_eq!(parsed.reason, KickReason::Other(200));
+        assert_eq!(parsed.reason.code(), 200);
+    }
+
+    #[test]
+    fn a_truncated_notice_is_a_protocol_error() {
+        let frame = notice(false, None).to_frame();
+
+        assert!(matches!(
+            KickNotice::parse(&frame[..NOTICE_HEADER_SIZE - 1]),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn a_kick_delays_the_reconnect_by_its_retry_after() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+
+        let clock = MockClock::new();
+        let mut client = client_over(vec![first, second]);
+        client.set_clock(clock.clone());
+        client.set_reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(1),
+            ..instant_reconnects()
+        });
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let (reasons, closed) = channel();
+        let reasons = Mutex::new(reasons);
+        client.set_on_connection_closed(move |_, _, reason| {
+            let _ = reasons.lock().unwrap().send(reason);
+        });
+
+        let (reconnects, reconnected) = channel();
+        let reconnects = Mutex::new(reconnects);
+        client.set_on_reconnected(move || {
+            let _ = reconnects.lock().unwrap().send(());
+        });
+
+        let mut kick = KickClient::new(client);
+        let (notices, kicked) = channel();
+        let notices = Mutex::new(notices);
+        kick.set_on_kicked(move |notice| {
+            let _ = notices.lock().unwrap().send(notice.clone());
+        });
+        kick.client().receive().unwrap();
+
+        let sent = notice(false, Some(Duration::from_secs(30)));
+        write_frame(&first_server, &sent.to_frame());
+        assert_eq!(kicked.recv_timeout(TIMEOUT).unwrap(), sent);
+
+        let kicked_at = clock.now();
+        first_server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            DisconnectReason::Kicked
+        );
+
+        // The server's delay, not the policy's second, holds the attempt.
+        wait_until(|| clock.sleepers() == 1);
+        assert_eq!(
+            kick.client().next_reconnect_at(),
+            Some(kicked_at + Duration::from_secs(30))
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.sleepers(), 1);
+
+        clock.advance(Duration::from_secs(29));
+        reconnected.recv_timeout(TIMEOUT).unwrap();
+        kick.send("back").unwrap();
+        assert_eq!(read_frame(&second_server).unwrap(), b"\x00back");
+        assert_eq!(kick.last_notice(), Some(sent));
+        assert_eq!(
+            kick.client().last_disconnect_reason(),
+            Some(DisconnectReason::Kicked)
+        );
+    }
+
+    #[test]
+    fn a_timeout_notice_ends_the_connection_as_a_timeout() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = client_over(vec![transport]);
+
+        let (reasons, closed) = channel();
+        let reasons = Mutex::new(reasons);
+        client.set_on_connection_closed(move |_, _, reason| {
+            let _ = reasons.lock().unwrap().send(reason);
+        });
+
+        let mut kick = KickClient::new(client);
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        kick.set_on_message_received(move |message| {
+            let _ = messages.lock().unwrap().send(message.to_vec());
+        });
+        kick.client().receive().unwrap();
+
+        write_frame(&server, b"\x00hello");
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hello");
+
+        write_frame(&server, &notice(true, None).to_frame());
+        server.shutdown(Shutdown::Both).unwrap();
+
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            DisconnectReason::Timeout
+        );
+        assert_eq!(kick.client().next_reconnect_at(), None);
+        assert!(kick.last_notice().unwrap().timed_out);
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use crate::{Error, TcpClient};
+
+// Probes start with this marker and a u32 LE sequence number, padded with
+// zeroes to the requested payload size.
+const PROBE_MARKER: &[u8; 8] = b"tcpprobe";
+const PROBE_HEADER_SIZE: usize = 12;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyReport {
+    pub sent: usize,
+    pub received: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    // Mean difference between consecutive round trips.
+    pub jitter: Duration,
+    pub loss: f64,
+}
+
+// Sends probes one at a time and waits for the server to echo each back.
+// Other messages arriving meanwhile are still delivered to the callback as
+// usual; a probe not echoed within the timeout counts as lost.
+pub(crate) fn run_latency_test(
+    client: &TcpClient,
+    count: usize,
+    payload_size: usize,
+    timeout: Duration,
+) -> Result<LatencyReport, Error> {
+    let clock = match client.downgrade().upgrade() {
+        Some(data) => data.clock(),
+        None => return Err(Error::Closed),
+    };
+    let messages = client.messages();
+
+    // Fails only when the receive loop is already running, which is fine
+    // since the stream above is fed by it.
+    let _ = client.receive();
+
+    let mut round_trips: Vec<Duration> = Vec::with_capacity(count);
+
+    for sequence in 0..count {
+        let mut probe = Vec::with_capacity(payload_size.max(PROBE_HEADER_SIZE));
+        probe.extend_from_slice(PROBE_MARKER);
+        probe.extend_from_slice(&(sequence as u32).to_le_bytes());
+        probe.resize(payload_size.max(PROBE_HEADER_SIZE), 0);
+
+        let started = clock.now();
+        let deadline = started + timeout;
+
+        client.send(&probe)?.wait()?;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(clock.now());
+
+            match messages.recv_timeout(remaining) {
+                Ok(message) if message == probe => {
+                    round_trips.push(clock.now().saturating_duration_since(started));
+                    break;
+                }
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Closed),
+            }
+        }
+    }
+
+    Ok(report(count, &round_trips))
+}
+
+fn report(sent: usize, round_trips: &[Duration]) -> LatencyReport {
+    let received = round_trips.len();
+    let loss = match sent {
+        0 => 0.0,
+        _ => (sent - received) as f64 / sent as f64,
+    };
+
+    if received == 0 {
+        return LatencyReport {
+            sent,
+            received,
+            min: Duration::ZERO,
+            max: Duration::ZERO,
+            mean: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss,
+        };
+    }
+
+    let total: Duration = round_trips.iter().sum();
+    let jitter = match received {
+        1 => Duration::ZERO,
+        _ => {
+            let differences: Duration = round_trips
+                .windows(2)
+                .map(|pair| pair[0].abs_diff(pair[1]))
+                .sum();
+
+            differences / (received - 1) as u32
+        }
+    };
+
+    LatencyReport {
+        sent,
+        received,
+        min: round_trips.iter().copied().min().unwrap_or_default(),
+        max: round_trips.iter().copied().max().unwrap_or_default(),
+        mean: total / received as u32,
+        jitter,
+        loss,
+    }
+}
@@ -1,3 +1,89 @@
+mod bind;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod client;
+mod clock;
+mod coalesce;
+mod config;
+mod congestion;
+mod diagnostics;
+mod dispatch;
+mod error;
+mod fragment;
+mod framing;
+mod handoff;
+mod identity;
+mod intercept;
+mod journal;
+mod latency;
+mod lifecycle;
+mod manager;
+mod memory;
+mod obfuscate;
+#[cfg(windows)]
+mod pipe;
+mod policy;
+mod pump;
+mod queue;
+mod resolver;
+mod stats;
+mod stream;
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+mod tcp_info;
+#[cfg(test)]
+mod testing;
+mod thread_hints;
+mod transport;
+mod typed;
 
+pub mod auth;
+pub mod context;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flow;
+pub mod inbox;
+pub mod kick;
+pub mod line;
+pub mod mqtt;
+pub mod msgpack_rpc;
+pub mod peers;
+pub mod presence;
+pub mod protocol;
+pub mod redirect;
+pub mod schema;
+pub mod sequence;
+pub mod topics;
+
+pub use bind::*;
+#[cfg(feature = "chaos")]
+pub use chaos::*;
 pub use client::*;
+pub use clock::*;
+pub use coalesce::*;
+pub use config::*;
+pub use congestion::*;
+pub use diagnostics::*;
+pub use dispatch::*;
+pub use error::*;
+pub use framing::*;
+pub use handoff::*;
+pub use identity::*;
+pub use intercept::*;
+pub use journal::*;
+pub use latency::*;
+pub use lifecycle::*;
+pub use manager::*;
+pub use memory::*;
+pub use obfuscate::*;
+#[cfg(windows)]
+pub use pipe::*;
+pub use policy::*;
+pub use queue::*;
+pub use resolver::*;
+pub use stats::*;
+pub use stream::*;
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+pub use tcp_info::*;
+pub use thread_hints::*;
+pub use transport::*;
+pub use typed::*;
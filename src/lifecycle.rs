@@ -0,0 +1,115 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Weak;
+use std::thread::{self, ThreadId};
+
+use crate::TcpClientData;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IoThread {
+    Receive,
+    // The write queue's writer, see set_write_queue.
+    Writer,
+    // The write coalescing timer, see set_write_coalescing.
+    Flusher,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ThreadEvent {
+    Started {
+        thread: IoThread,
+        id: ThreadId,
+    },
+    // Started again after an earlier thread of the same kind ended, e.g. a
+    // receive loop restarted after a panic.
+    Restarted {
+        thread: IoThread,
+        id: ThreadId,
+    },
+    Stopped {
+        thread: IoThread,
+        id: ThreadId,
+        reason: String,
+    },
+    // A panicked receive loop can be started again with receive.
+    Panicked {
+        thread: IoThread,
+        id: ThreadId,
+        message: String,
+    },
+}
+
+// Runs an IO thread's body, reporting its lifecycle to the client. The body
+// returns why it stopped. Nothing is reported once the client data is gone.
+pub(crate) fn supervise<F>(data: &Weak<TcpClientData>, thread: IoThread, body: F)
+where
+    F: FnOnce() -> String,
+{
+    let id = thread::current().id();
+
+    if let Some(data) = data.upgrade() {
+        let event = if data.record_thread_start(thread) {
+            ThreadEvent::Restarted { thread, id }
+        } else {
+            ThreadEvent::Started { thread, id }
+        };
+
+        data.thread_event(&event);
+    }
+
+    let event = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(reason) => ThreadEvent::Stopped { thread, id, reason },
+        Err(payload) => ThreadEvent::Panicked {
+            thread,
+            id,
+            message: panic_message(payload.as_ref())
+                .unwrap_or_else(|| "Thread panicked with a non-string payload".to_string()),
+        },
+    };
+
+    if let Some(data) = data.upgrade() {
+        if let ThreadEvent::Panicked { .. } = event {
+            data.recover_thread(thread);
+        }
+
+        data.thread_event(&event);
+    }
+}
+
+// The message panic! was given, which is a &str or a String unless the
+// payload came from panic_any.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> Option<String> {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        Some(message.to_string())
+    } else {
+        payload.downcast_ref::<String>().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload<F: FnOnce()>(f: F) -> Box<dyn Any + Send> {
+        panic::catch_unwind(AssertUnwindSafe(f)).unwrap_err()
+    }
+
+    #[test]
+    fn panic_message_reads_literal_and_formatted_messages() {
+        let literal = payload(|| panic!("literal"));
+        let formatted = payload(|| panic!("formatted {}", 1));
+
+        assert_eq!(panic_message(literal.as_ref()).as_deref(), Some("literal"));
+        assert_eq!(
+            panic_message(formatted.as_ref()).as_deref(),
+            Some("formatted 1")
+        );
+    }
+
+    #[test]
+    fn panic_message_is_none_for_other_payloads() {
+        let other = payload(|| panic::panic_any(42));
+
+        assert_eq!(panic_message(other.as_ref()), None);
+    }
+}
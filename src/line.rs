@@ -0,0 +1,94 @@
+use crate::{Error, Framing, SendTicket, TcpClient};
+
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8192;
+
+pub struct LineClient {
+    client: TcpClient,
+    delimiter: String,
+}
+
+impl LineClient {
+    pub fn new(client: TcpClient) -> Self {
+        Self::with_delimiter(client, "\n", DEFAULT_MAX_LINE_LENGTH)
+    }
+
+    pub fn with_delimiter(mut client: TcpClient, delimiter: &str, max_line_length: usize) -> Self {
+        let delimiter = match delimiter {
+            "" => "\n",
+            delimiter => delimiter,
+        };
+
+        client.set_framing(Framing::Delimited {
+            delimiter: delimiter.as_bytes().to_vec(),
+            max_length: max_line_length,
+        });
+
+        Self {
+            client,
+            delimiter: delimiter.to_string(),
+        }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn send_line(&self, line: &str) -> Result<SendTicket, Error> {
+        if line.contains(self.delimiter.as_str()) {
+            return Err(Error::Protocol("line contains the delimiter".to_string()));
+        }
+
+        self.client.send(line)
+    }
+
+    // With the default "\n" delimiter a trailing "\r" is stripped as well, so
+    // CRLF peers work without configuration.
+    pub fn set_on_line_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        let strip_carriage_return = self.delimiter == "\n";
+
+        self.client.set_on_text_received(move |line| {
+            if strip_carriage_return {
+                callback(line.strip_suffix('\r').unwrap_or(line));
+            } else {
+                callback(line);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing::{client_over, read_exact, TIMEOUT};
+    use crate::{MemoryTransport, Transport};
+
+    #[test]
+    fn crlf_lines_arrive_without_the_carriage_return() {
+        let (transport, server) = MemoryTransport::pair();
+        let mut client = LineClient::new(client_over(vec![transport]));
+
+        let (lines, received) = channel();
+        let lines = Mutex::new(lines);
+        client.set_on_line_received(move |line| {
+            let _ = lines.lock().unwrap().send(line.to_string());
+        });
+        client.client().receive().unwrap();
+
+        Transport::write(&server, b"HELO there\r\nbare\n").unwrap();
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), "HELO there");
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), "bare");
+
+        client.send_line("QUIT").unwrap();
+        let mut sent = [0; 5];
+        assert!(read_exact(&server, &mut sent));
+        assert_eq!(&sent, b"QUIT\n");
+
+        assert!(client.send_line("two\nlines").is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{ConnectionChange, DisconnectReason, TcpClient};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    Added(String),
+    Removed(String),
+    // Disconnected through the manager.
+    Disconnected(String),
+    // The connection ended on its own, as reported by on_connection_closed.
+    ConnectionClosed(String, DisconnectReason),
+    Reconnected(String),
+}
+
+type OnClientEventCallback = Arc<dyn Fn(&ClientEvent) + Send + Sync>;
+type Listeners = Arc<Mutex<Vec<OnClientEventCallback>>>;
+
+// Connection events are picked up without touching the clients' own
+// callbacks, so the application can still set those through get_mut.
+#[derive(Default)]
+pub struct ClientManager {
+    clients: HashMap<String, TcpClient>,
+    // The connection watcher registered on each client.
+    watchers: HashMap<String, u64>,
+    listeners: Listeners,
+}
+
+impl ClientManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the client previously registered under the same name, which is
+    // handed back untouched rather than disconnected.
+    pub fn add(&mut self, name: &str, client: TcpClient) -> Option<TcpClient> {
+        let listeners = self.listeners.clone();
+        let watched = name.to_string();
+        let watcher = client.watch_connection(move |change| {
+            let event = match change {
+                ConnectionChange::Closed(reason) => {
+                    ClientEvent::ConnectionClosed(watched.clone(), reason)
+                }
+                ConnectionChange::Reconnected => ClientEvent::Reconnected(watched.clone()),
+            };
+
+            broadcast(&listeners, event);
+        });
+
+        self.unwatch(name);
+        self.watchers.insert(name.to_string(), watcher);
+        let previous = self.clients.insert(name.to_string(), client);
+
+        if previous.is_some() {
+            self.broadcast(ClientEvent::Removed(name.to_string()));
+        }
+
+        self.broadcast(ClientEvent::Added(name.to_string()));
+        previous
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<TcpClient> {
+        self.unwatch(name);
+        let client = self.clients.remove(name);
+
+        if client.is_some() {
+            self.broadcast(ClientEvent::Removed(name.to_string()));
+        }
+
+        client
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TcpClient> {
+        self.clients.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut TcpClient> {
+        self.clients.get_mut(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.clients.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.clients.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn disconnect(&self, name: &str) -> bool {
+        match self.clients.get(name) {
+            Some(client) => {
+                let result = client.disconnect();
+                self.broadcast(ClientEvent::Disconnected(name.to_string()));
+                result
+            }
+            None => false,
+        }
+    }
+
+    // Disconnects and drops every client. Returns the number of clients that
+    // were shut down cleanly.
+    pub fn shutdown_all(&mut self) -> usize {
+        let clients: Vec<(String, TcpClient)> = self.clients.drain().collect();
+        let mut disconnected: usize = 0;
+
+        for (name, client) in clients {
+            if let Some(watcher) = self.watchers.remove(&name) {
+                client.unwatch_connection(watcher);
+            }
+
+            if client.disconnect() {
+                disconnected += 1;
+            }
+
+            self.broadcast(ClientEvent::Disconnected(name.clone()));
+            self.broadcast(ClientEvent::Removed(name));
+        }
+
+        disconnected
+    }
+
+    pub fn on_event<F>(&mut self, callback: F)
+    where
+        F: Fn(&ClientEvent) + Send + Sync + 'static,
+    {
+        match self.listeners.lock() {
+            Ok(mut listeners) => listeners.push(Arc::new(callback)),
+            Err(e) => e.into_inner().push(Arc::new(callback)),
+        }
+    }
+
+    fn broadcast(&self, event: ClientEvent) {
+        broadcast(&self.listeners, event);
+    }
+
+    fn unwatch(&mut self, name: &str) {
+        if let (Some(watcher), Some(client)) = (self.watchers.remove(name), self.clients.get(name))
+        {
+            client.unwatch_connection(watcher);
+        }
+    }
+}
+
+// Listeners run outside the lock, so clients reporting from their own
+// threads don't wait on each other's listeners.
+fn broadcast(listeners: &Listeners, event: ClientEvent) {
+    let listeners: Vec<OnClientEventCallback> = match listeners.lock() {
+        Ok(listeners) => listeners.clone(),
+        Err(e) => e.into_inner().clone(),
+    };
+
+    for listener in listeners {
+        listener(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{client_over, instant_reconnects, TIMEOUT};
+    use crate::{ErrorAction, ErrorPolicy, MemoryTransport, Transport};
+
+    #[test]
+    fn connection_events_are_forwarded_alongside_the_clients_callbacks() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let mut client = client_over(vec![first, second]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let (callbacks, called) = channel();
+        let closes = Mutex::new(callbacks.clone());
+        client.set_on_connection_closed(move |_, _, _| {
+            let _ = closes.lock().unwrap().send("closed");
+        });
+        let reconnects = Mutex::new(callbacks);
+        client.set_on_reconnected(move || {
+            let _ = reconnects.lock().unwrap().send("reconnected");
+        });
+
+        let mut manager = ClientManager::new();
+        let (events, received) = channel();
+        let events = Mutex::new(events);
+        manager.on_event(move |event| {
+            let _ = events.lock().unwrap().send(event.clone());
+        });
+
+        manager.add("chat", client);
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            ClientEvent::Added("chat".to_string())
+        );
+
+        manager.get("chat").unwrap().receive().unwrap();
+        first_server.shutdown(Shutdown::Both).unwrap();
+
+        assert_eq!(called.recv_timeout(TIMEOUT).unwrap(), "closed");
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            ClientEvent::ConnectionClosed("chat".to_string(), DisconnectReason::PeerClosed)
+        );
+        assert_eq!(called.recv_timeout(TIMEOUT).unwrap(), "reconnected");
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            ClientEvent::Reconnected("chat".to_string())
+        );
+
+        // Once removed, the client is no longer followed.
+        let client = manager.remove("chat").unwrap();
+        assert_eq!(
+            received.recv_timeout(TIMEOUT).unwrap(),
+            ClientEvent::Removed("chat".to_string())
+        );
+
+        second_server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(called.recv_timeout(TIMEOUT).unwrap(), "closed");
+        drop(client);
+        assert!(received.try_recv().is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// What a send does when queueing its message would exceed the budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BudgetAction {
+    // Fail the send with Error::MemoryBudgetExceeded.
+    Reject,
+    // Block the send until the writer has made room, failing it if that
+    // takes longer than this.
+    Wait(Duration),
+}
+
+// A cap on the bytes the client holds on to: messages in the write queue,
+// the receive buffers and partly reassembled messages. Sends over the
+// budget follow the action; incoming frames can't be held back without
+// losing the stream, so a frame that doesn't fit closes the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryBudget {
+    pub limit: usize,
+    pub action: BudgetAction,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub queued: usize,
+    pub receive_buffer: usize,
+    pub reassembly: usize,
+    // How many sends and frames were turned away for exceeding the budget.
+    pub budget_exceeded: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.queued
+            .saturating_add(self.receive_buffer)
+            .saturating_add(self.reassembly)
+    }
+}
+
+// The inbound figures are updated by the receive loop before every read;
+// the write queue keeps count of its own bytes.
+#[derive(Default)]
+pub(crate) struct MemoryAccount {
+    budget: Mutex<Option<MemoryBudget>>,
+    receive_buffer: AtomicUsize,
+    reassembly: AtomicUsize,
+    exceeded: AtomicU64,
+}
+
+impl MemoryAccount {
+    pub(crate) fn budget(&self) -> Option<MemoryBudget> {
+        match self.budget.lock() {
+            Ok(budget) => *budget,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    pub(crate) fn set_budget(&self, budget: Option<MemoryBudget>) {
+        match self.budget.lock() {
+            Ok(mut current) => *current = budget,
+            Err(e) => *e.into_inner() = budget,
+        }
+    }
+
+    pub(crate) fn set_inbound(&self, receive_buffer: usize, reassembly: usize) {
+        self.receive_buffer.store(receive_buffer, Ordering::SeqCst);
+        self.reassembly.store(reassembly, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_exceeded(&self) {
+        self.exceeded.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn usage(&self, queued: usize) -> MemoryUsage {
+        MemoryUsage {
+            queued,
+            receive_buffer: self.receive_buffer.load(Ordering::SeqCst),
+            reassembly: self.reassembly.load(Ordering::SeqCst),
+            budget_exceeded: self.exceeded.load(Ordering::SeqCst),
+        }
+    }
+}
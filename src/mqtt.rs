@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::framing::remaining_length;
+use crate::queue::Lane;
+use crate::topics::matches;
+use crate::{ClientState, Error, Framing, SendTicket, TcpClient, TcpClientData, TopicSubscription};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const UNSUBSCRIBE: u8 = 0xa2;
+const UNSUBACK: u8 = 0xb0;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+const PUBLISH_DUP: u8 = 0x08;
+const PUBLISH_RETAIN: u8 = 0x01;
+
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttOptions {
+    pub client_id: String,
+    // PINGREQ is sent this often; zero disables keep-alive.
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+type MessageHandler = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+struct Subscription {
+    filter: String,
+    qos: QoS,
+    handler: MessageHandler,
+}
+
+#[derive(Default)]
+struct MqttState {
+    connect_packet: Vec<u8>,
+    connack: Mutex<Option<Sender<u8>>>,
+    subscriptions: Mutex<Vec<Subscription>>,
+    // QoS 1 publishes waiting for their PUBACK, resent after a reconnect.
+    in_flight: Mutex<HashMap<u16, Vec<u8>>>,
+    next_packet_id: AtomicU16,
+}
+
+// A minimal MQTT 3.1.1 client over the regular TcpClient, so reconnects,
+// error policies and callbacks work as they do for the other layers.
+pub struct MqttClient {
+    client: TcpClient,
+    state: Arc<MqttState>,
+}
+
+impl MqttOptions {
+    pub fn new(client_id: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            keep_alive: Duration::from_secs(60),
+            clean_session: true,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl MqttState {
+    fn handle(&self, data: &TcpClientData, packet: &[u8]) -> Result<(), Error> {
+        let (_, length_size) = match remaining_length(&packet[1..])? {
+            Some(length) => length,
+            None => return Err(malformed("packet")),
+        };
+        let body = &packet[1 + length_size..];
+
+        match packet[0] & 0xf0 {
+            CONNACK => {
+                let code = *body.get(1).ok_or_else(|| malformed("CONNACK"))?;
+                let connack = match self.connack.lock() {
+                    Ok(mut connack) => connack.take(),
+                    Err(_) => None,
+                };
+
+                if let Some(connack) = connack {
+                    let _ = connack.send(code);
+                } else if code != 0 {
+                    return Err(connection_refused(code));
+                }
+
+                Ok(())
+            }
+            PUBLISH => self.handle_publish(data, packet[0], body),
+            PUBACK => {
+                let id = packet_id(body)?;
+
+                if let Ok(mut in_flight) = self.in_flight.lock() {
+                    in_flight.remove(&id);
+                }
+
+                Ok(())
+            }
+            SUBACK => match body.get(2..) {
+                Some(codes) if codes.contains(&0x80) => Err(Error::Protocol(
+                    "MQTT broker rejected a subscription".to_string(),
+                )),
+                Some(_) => Ok(()),
+                None => Err(malformed("SUBACK")),
+            },
+            UNSUBACK | PINGRESP => Ok(()),
+            _ => Err(Error::Protocol(format!(
+                "Unexpected MQTT packet type 0x{:02x}",
+                packet[0]
+            ))),
+        }
+    }
+
+    fn handle_publish(&self, data: &TcpClientData, flags: u8, body: &[u8]) -> Result<(), Error> {
+        let (topic, rest) = read_string(body)?;
+        let (id, payload) = match (flags >> 1) & 0x03 {
+            0 => (None, rest),
+            1 => (Some(packet_id(rest)?), &rest[2..]),
+            _ => return Err(Error::Protocol("MQTT QoS 2 is not supported".to_string())),
+        };
+
+        let handlers: Vec<MessageHandler> = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .filter(|subscription| matches(&subscription.filter, topic))
+                .map(|subscription| subscription.handler.clone())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        for handler in handlers {
+            handler(topic, payload);
+        }
+
+        // Acked only once the handlers have run.
+        match id {
+            Some(id) => data
+                .send(&packet(PUBACK, &id.to_be_bytes()), Lane::Control)
+                .map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    // Called after the transport reconnected: the broker needs a new
+    // CONNECT, and with a clean session it forgot the subscriptions too.
+    // Any failure leaves the rest to the restore of the next connection.
+    fn restore(&self, data: &TcpClientData) -> Result<(), Error> {
+        data.send_on_reconnect(&self.connect_packet, Lane::Control)?;
+
+        let subscriptions: Vec<(String, QoS)> = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .map(|subscription| (subscription.filter.clone(), subscription.qos))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        for (filter, qos) in subscriptions {
+            data.send_on_reconnect(&self.subscribe_packet(&filter, qos), Lane::Data)?;
+        }
+
+        let in_flight: Vec<Vec<u8>> = match self.in_flight.lock() {
+            Ok(in_flight) => in_flight.values().cloned().collect(),
+            Err(_) => Vec::new(),
+        };
+
+        for mut publish in in_flight {
+            publish[0] |= PUBLISH_DUP;
+            data.send_on_reconnect(&publish, Lane::Data)?;
+        }
+
+        Ok(())
+    }
+
+    fn packet_id(&self) -> u16 {
+        // Zero is not a valid packet id.
+        loop {
+            let id = self.next_packet_id.fetch_add(1, Ordering::SeqCst);
+
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    fn subscribe_packet(&self, filter: &str, qos: QoS) -> Vec<u8> {
+        let mut body = self.packet_id().to_be_bytes().to_vec();
+        write_string(&mut body, filter);
+        body.push(qos_bits(qos));
+
+        packet(SUBSCRIBE, &body)
+    }
+}
+
+impl MqttClient {
+    // Sends CONNECT and waits for the broker's CONNACK.
+    pub fn new(mut client: TcpClient, options: MqttOptions) -> Result<Self, Error> {
+        let state = Arc::new(MqttState {
+            connect_packet: connect_packet(&options),
+            ..MqttState::default()
+        });
+
+        client.set_framing(Framing::Mqtt);
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |packet| {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.handle(&data, packet) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_reconnected(move || {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.restore(&data) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let (sender, receiver) = channel::<u8>();
+
+        if let Ok(mut connack) = state.connack.lock() {
+            *connack = Some(sender);
+        }
+
+        // Fails only when the receive loop is already running, which is fine
+        // since it picks up the handlers set above.
+        let _ = client.receive();
+
+        client.send(&state.connect_packet)?.wait()?;
+
+        match receiver.recv_timeout(CONNACK_TIMEOUT) {
+            Ok(0) => {}
+            Ok(code) => return Err(connection_refused(code)),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::Protocol(
+                    "MQTT broker did not answer CONNECT".to_string(),
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Closed),
+        }
+
+        if !options.keep_alive.is_zero() {
+            spawn_keep_alive(client.downgrade(), options.keep_alive);
+        }
+
+        Ok(Self { client, state })
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // Wildcards follow MQTT: "+" matches one level, a trailing "#" the rest.
+    pub fn subscribe<F>(&mut self, filter: &str, qos: QoS, handler: F) -> Result<SendTicket, Error>
+    where
+        F: Fn(&str, &[u8]) + Send + Sync + 'static,
+    {
+        self.add_subscription(filter, qos, Arc::new(handler))
+    }
+
+    fn add_subscription(
+        &mut self,
+        filter: &str,
+        qos: QoS,
+        handler: MessageHandler,
+    ) -> Result<SendTicket, Error> {
+        if let Ok(mut subscriptions) = self.state.subscriptions.lock() {
+            subscriptions.push(Subscription {
+                filter: filter.to_string(),
+                qos,
+                handler,
+            });
+        }
+
+        self.client.send(self.state.subscribe_packet(filter, qos))
+    }
+
+    // The transport's state with the subscriptions added. Publishes still
+    // waiting for their PUBACK stay with this client, which resends them
+    // if it reconnects.
+    pub fn export_state(&self) -> ClientState {
+        let subscriptions = match self.state.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .map(|subscription| TopicSubscription {
+                    filter: subscription.filter.clone(),
+                    qos: subscription.qos,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        ClientState {
+            subscriptions,
+            ..self.client.export_state()
+        }
+    }
+
+    // Subscribes again to every exported filter, all delivered to the one
+    // handler, then sends the pending messages and returns their tickets
+    // like TcpClient::import_state.
+    pub fn import_state<F>(
+        &mut self,
+        state: &ClientState,
+        handler: F,
+    ) -> Result<Vec<SendTicket>, Error>
+    where
+        F: Fn(&str, &[u8]) + Send + Sync + 'static,
+    {
+        let handler: MessageHandler = Arc::new(handler);
+
+        for subscription in &state.subscriptions {
+            self.add_subscription(&subscription.filter, subscription.qos, handler.clone())?;
+        }
+
+        self.client.import_state(state)
+    }
+
+    pub fn unsubscribe(&mut self, filter: &str) -> Result<SendTicket, Error> {
+        if let Ok(mut subscriptions) = self.state.subscriptions.lock() {
+            subscriptions.retain(|subscription| subscription.filter != filter);
+        }
+
+        let mut body = self.state.packet_id().to_be_bytes().to_vec();
+        write_string(&mut body, filter);
+
+        self.client.send(packet(UNSUBSCRIBE, &body))
+    }
+
+    // With QoS 1 the message is kept until the broker acks it and resent
+    // after a reconnect.
+    pub fn publish<T>(
+        &self,
+        topic: &str,
+        payload: T,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(Error::InvalidTopic(topic.to_string()));
+        }
+
+        let mut body = Vec::new();
+        write_string(&mut body, topic);
+
+        let id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => {
+                let id = self.state.packet_id();
+                body.extend_from_slice(&id.to_be_bytes());
+                Some(id)
+            }
+        };
+
+        body.extend_from_slice(payload.as_ref());
+
+        let mut flags = PUBLISH | (qos_bits(qos) << 1);
+
+        if retain {
+            flags |= PUBLISH_RETAIN;
+        }
+
+        let publish = packet(flags, &body);
+
+        if let Some(id) = id {
+            if let Ok(mut in_flight) = self.state.in_flight.lock() {
+                in_flight.insert(id, publish.clone());
+            }
+        }
+
+        self.client.send(publish)
+    }
+
+    // Sends DISCONNECT so the broker discards the will, then closes.
+    pub fn disconnect(&self) -> bool {
+        let _ = self
+            .client
+            .send(packet(DISCONNECT, &[]))
+            .and_then(SendTicket::wait);
+
+        self.client.disconnect()
+    }
+}
+
+fn spawn_keep_alive(data: Weak<TcpClientData>, interval: Duration) {
+    let clock = match data.upgrade() {
+        Some(data) if data.is_manual_pump() => {
+            data.schedule(interval, Box::new(keep_alive));
+            return;
+        }
+        Some(data) => data.clock(),
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        clock.sleep(interval);
+
+        let data = match data.upgrade() {
+            Some(data) => data,
+            None => return,
+        };
+
+        if !keep_alive(&data) {
+            return;
+        }
+    });
+}
+
+// Returns false once the connection is closed.
+fn keep_alive(data: &TcpClientData) -> bool {
+    if data.is_closed() {
+        return false;
+    }
+
+    if !data.is_suspended() {
+        if let Err(e) = data.send(&packet(PINGREQ, &[]), Lane::Control) {
+            data.report_error(&e);
+        }
+    }
+
+    true
+}
+
+fn connect_packet(options: &MqttOptions) -> Vec<u8> {
+    let mut flags: u8 = 0;
+
+    if options.clean_session {
+        flags |= 0x02;
+    }
+
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let keep_alive = options.keep_alive.as_secs().min(u16::MAX as u64) as u16;
+    let mut body = Vec::new();
+
+    write_string(&mut body, "MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    write_string(&mut body, &options.client_id);
+
+    if let Some(username) = &options.username {
+        write_string(&mut body, username);
+    }
+
+    if let Some(password) = &options.password {
+        body.extend_from_slice(&(password.len() as u16).to_be_bytes());
+        body.extend_from_slice(password);
+    }
+
+    packet(CONNECT, &body)
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining = body.len();
+
+    loop {
+        let mut byte = (remaining & 0x7f) as u8;
+        remaining >>= 7;
+
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+
+        packet.push(byte);
+
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn read_string(bytes: &[u8]) -> Result<(&str, &[u8]), Error> {
+    if bytes.len() < 2 {
+        return Err(malformed("string"));
+    }
+
+    let length = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let rest = &bytes[2..];
+
+    if rest.len() < length {
+        return Err(malformed("string"));
+    }
+
+    match std::str::from_utf8(&rest[..length]) {
+        Ok(value) => Ok((value, &rest[length..])),
+        Err(e) => Err(Error::InvalidUtf8(e)),
+    }
+}
+
+fn packet_id(bytes: &[u8]) -> Result<u16, Error> {
+    match bytes.get(..2) {
+        Some(id) => Ok(u16::from_be_bytes([id[0], id[1]])),
+        None => Err(malformed("packet id")),
+    }
+}
+
+fn qos_bits(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+    }
+}
+
+fn malformed(what: &str) -> Error {
+    Error::Protocol(format!("Malformed MQTT {what}"))
+}
+
+fn connection_refused(code: u8) -> Error {
+    Error::Protocol(format!(
+        "MQTT broker refused the connection with code {code}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::*;
+    use crate::testing::{client_over, instant_reconnects, read_exact};
+    use crate::{ErrorAction, ErrorPolicy, MemoryTransport, Transport};
+
+    fn read_packet(broker: &MemoryTransport) -> Vec<u8> {
+        let mut packet = vec![0];
+        assert!(read_exact(broker, &mut packet));
+
+        loop {
+            let mut byte = [0];
+            assert!(read_exact(broker, &mut byte));
+            packet.push(byte[0]);
+
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let (length, _) = remaining_length(&packet[1..]).unwrap().unwrap();
+        let mut body = vec![0; length];
+        assert!(read_exact(broker, &mut body));
+
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    #[test]
+    fn reconnect_restores_subscriptions_and_in_flight_publishes() {
+        let (first, first_broker) = MemoryTransport::pair();
+        let (second, second_broker) = MemoryTransport::pair();
+        let (third, third_broker) = MemoryTransport::pair();
+
+        // The restore fails on the second connection and is redone on the
+        // third.
+        second_broker.shutdown(Shutdown::Both).unwrap();
+
+        let mut client = client_over(vec![first, second, third]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let broker = thread::spawn(move || {
+            assert_eq!(read_packet(&first_broker)[0], CONNECT);
+            Transport::write(&first_broker, &[CONNACK, 2, 0, 0]).unwrap();
+            first_broker
+        });
+
+        let options = MqttOptions {
+            keep_alive: Duration::ZERO,
+            ..MqttOptions::new("test")
+        };
+        let mut mqtt = MqttClient::new(client, options).unwrap();
+        let first_broker = broker.join().unwrap();
+
+        mqtt.subscribe("sensors/+", QoS::AtLeastOnce, |_, _| {})
+            .unwrap();
+        mqtt.publish("sensors/a", "21.5", QoS::AtLeastOnce, false)
+            .unwrap();
+        assert_eq!(read_packet(&first_broker)[0], SUBSCRIBE);
+        assert_eq!(read_packet(&first_broker)[0] & PUBLISH_DUP, 0);
+
+        // The publish is never acked, so it is still in flight.
+        first_broker.shutdown(Shutdown::Both).unwrap();
+
+        assert_eq!(read_packet(&third_broker)[0], CONNECT);
+
+        let subscribe = read_packet(&third_broker);
+        assert_eq!(subscribe[0], SUBSCRIBE);
+        assert!(subscribe.ends_with(b"sensors/+\x01"));
+
+        let publish = read_packet(&third_broker);
+        assert_eq!(publish[0] & 0xf0, PUBLISH);
+        assert_ne!(publish[0] & PUBLISH_DUP, 0);
+        assert!(publish.ends_with(b"21.5"));
+    }
+
+    fn connected(
+        transport: MemoryTransport,
+        broker: MemoryTransport,
+    ) -> (MqttClient, MemoryTransport) {
+        let broker = thread::spawn(move || {
+            assert_eq!(read_packet(&broker)[0], CONNECT);
+            Transport::write(&broker, &[CONNACK, 2, 0, 0]).unwrap();
+            broker
+        });
+
+        let options = MqttOptions {
+            keep_alive: Duration::ZERO,
+            ..MqttOptions::new("test")
+        };
+        let mqtt = MqttClient::new(client_over(vec![transport]), options).unwrap();
+        (mqtt, broker.join().unwrap())
+    }
+
+    #[test]
+    fn imported_subscriptions_are_sent_to_the_new_broker() {
+        let (transport, broker) = MemoryTransport::pair();
+        let (mut old, _old_broker) = connected(transport, broker);
+        old.subscribe("sensors/+", QoS::AtLeastOnce, |_, _| {})
+            .unwrap();
+        old.subscribe("alerts/#", QoS::AtMostOnce, |_, _| {})
+            .unwrap();
+        let state = old.export_state();
+
+        let (transport, broker) = MemoryTransport::pair();
+        let (mut new, new_broker) = connected(transport, broker);
+        new.import_state(&state, |_, _| {}).unwrap();
+
+        let subscribe = read_packet(&new_broker);
+        assert_eq!(subscribe[0], SUBSCRIBE);
+        assert!(subscribe.ends_with(b"sensors/+\x01"));
+        assert!(read_packet(&new_broker).ends_with(b"alerts/#\x00"));
+    }
+}
@@ -0,0 +1,459 @@
+mod value;
+
+pub use value::*;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::queue::Lane;
+use crate::{Error, SendTicket, TcpClient, TcpClientData};
+
+const REQUEST: i64 = 0;
+const RESPONSE: i64 = 1;
+const NOTIFICATION: i64 = 2;
+// Calls waiting for a response wake up this often to notice that the client
+// closed, should that race with the pending calls being failed.
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RpcError {
+    Transport(Error),
+    Remote(Value),
+    Timeout,
+    Disconnected,
+    // Every attempt of call_with_retry failed; holds each attempt's error in
+    // order.
+    RetriesExhausted(Vec<RpcError>),
+}
+
+// Only use with idempotent methods: a request that timed out may still have
+// been handled by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub attempt_timeout: Duration,
+    // The whole call, backoff included, gives up once this has passed.
+    pub deadline: Option<Duration>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+}
+
+impl RetryConfig {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1).saturating_pow(attempt);
+
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            attempt_timeout: Duration::from_secs(1),
+            deadline: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2,
+        }
+    }
+}
+
+type PendingResponse = Sender<Result<Value, Value>>;
+type RequestHandler = Arc<dyn Fn(&[Value]) -> Result<Value, Value> + Send + Sync>;
+type NotificationHandler = Arc<dyn Fn(&[Value]) + Send + Sync>;
+
+#[derive(Default)]
+struct RpcState {
+    pending: Mutex<HashMap<u32, PendingResponse>>,
+    request_handlers: Mutex<HashMap<String, RequestHandler>>,
+    notification_handlers: Mutex<HashMap<String, NotificationHandler>>,
+}
+
+pub struct RpcClient {
+    client: TcpClient,
+    state: Arc<RpcState>,
+    next_id: AtomicU32,
+}
+
+impl RpcState {
+    fn handle(&self, data: &TcpClientData, message: &[u8]) -> Result<(), Error> {
+        let value = Value::decode(message).map_err(Error::Protocol)?;
+        let fields = match value.as_array() {
+            Some(fields) if !fields.is_empty() => fields,
+            _ => {
+                return Err(Error::Protocol(
+                    "MessagePack-RPC message is not a non-empty array".to_string(),
+                ))
+            }
+        };
+
+        match (fields[0].as_i64(), fields.len()) {
+            (Some(REQUEST), 4) => {
+                let id = Self::message_id(&fields[1])?;
+                let method = Self::method(&fields[2])?;
+                let params = Self::params(&fields[3])?;
+
+                let handler = match self.request_handlers.lock() {
+                    Ok(handlers) => handlers.get(method).cloned(),
+                    Err(_) => None,
+                };
+
+                let (error, result) = match handler.map(|handler| handler(params)) {
+                    Some(Ok(result)) => (Value::Nil, result),
+                    Some(Err(error)) => (error, Value::Nil),
+                    None => (Value::from(format!("Unknown method: {method}")), Value::Nil),
+                };
+
+                let response =
+                    Value::Array(vec![Value::Int(RESPONSE), Value::from(id), error, result]);
+
+                data.send(&response.encode(), Lane::Data).map(|_| ())
+            }
+            (Some(RESPONSE), 4) => {
+                let id = Self::message_id(&fields[1])?;
+                let pending = match self.pending.lock() {
+                    Ok(mut pending) => pending.remove(&id),
+                    Err(_) => None,
+                };
+
+                if let Some(pending) = pending {
+                    let response = if fields[2].is_nil() {
+                        Ok(fields[3].clone())
+                    } else {
+                        Err(fields[2].clone())
+                    };
+
+                    let _ = pending.send(response);
+                }
+
+                Ok(())
+            }
+            (Some(NOTIFICATION), 3) => {
+                let method = Self::method(&fields[1])?;
+                let params = Self::params(&fields[2])?;
+
+                let handler = match self.notification_handlers.lock() {
+                    Ok(handlers) => handlers.get(method).cloned(),
+                    Err(_) => None,
+                };
+
+                if let Some(handler) = handler {
+                    handler(params);
+                }
+
+                Ok(())
+            }
+            _ => Err(Error::Protocol(
+                "Unrecognized MessagePack-RPC message".to_string(),
+            )),
+        }
+    }
+
+    // Responses to requests sent on a connection that ended never arrive, so
+    // their calls fail with RpcError::Disconnected instead of waiting.
+    fn fail_pending(&self) {
+        match self.pending.lock() {
+            Ok(mut pending) => pending.clear(),
+            Err(e) => e.into_inner().clear(),
+        }
+    }
+
+    fn message_id(value: &Value) -> Result<u32, Error> {
+        match value.as_u64().map(u32::try_from) {
+            Some(Ok(id)) => Ok(id),
+            _ => Err(Error::Protocol(
+                "MessagePack-RPC message id is not a 32-bit unsigned integer".to_string(),
+            )),
+        }
+    }
+
+    fn method(value: &Value) -> Result<&str, Error> {
+        match value.as_str() {
+            Some(method) => Ok(method),
+            None => Err(Error::Protocol(
+                "MessagePack-RPC method name is not a string".to_string(),
+            )),
+        }
+    }
+
+    fn params(value: &Value) -> Result<&[Value], Error> {
+        match value.as_array() {
+            Some(params) => Ok(params),
+            None => Err(Error::Protocol(
+                "MessagePack-RPC params are not an array".to_string(),
+            )),
+        }
+    }
+}
+
+impl RpcClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(RpcState::default());
+
+        // The handlers live inside the client data, so they only hold a weak
+        // reference back to it to avoid a cycle.
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |message| {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.handle(&data, message) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let state_ref = state.clone();
+        client.set_on_peer_closed_write(move || state_ref.fail_pending());
+
+        let state_ref = state.clone();
+        client.set_on_connection_closed(move |_, _, _| state_ref.fail_pending());
+
+        let state_ref = state.clone();
+        client.set_on_reconnected(move || state_ref.fail_pending());
+
+        // Fails only when the receive loop is already running, which is fine
+        // since it picks up the handlers set above.
+        let _ = client.receive();
+
+        Self {
+            client,
+            state,
+            next_id: AtomicU32::new(0),
+        }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        self.call_with(method, params, None)
+    }
+
+    pub fn call_timeout(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        timeout: Duration,
+    ) -> Result<Value, RpcError> {
+        self.call_with(method, params, Some(timeout))
+    }
+
+    // Retries on timeouts, disconnects and transport errors, with backoff
+    // between attempts. Remote errors and a closed client fail right away.
+    pub fn call_with_retry(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        config: RetryConfig,
+    ) -> Result<Value, RpcError> {
+        let clock = match self.client.downgrade().upgrade() {
+            Some(data) => data.clock(),
+            None => return Err(RpcError::Transport(Error::Closed)),
+        };
+        let deadline = config.deadline.map(|deadline| clock.now() + deadline);
+        let mut failures: Vec<RpcError> = Vec::new();
+
+        for attempt in 0..config.max_attempts {
+            if attempt > 0 {
+                clock.sleep(config.backoff(attempt - 1));
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(clock.now());
+
+                    if remaining.is_zero() {
+                        break;
+                    }
+
+                    remaining.min(config.attempt_timeout)
+                }
+                None => config.attempt_timeout,
+            };
+
+            match self.call_with(method, params.clone(), Some(timeout)) {
+                Ok(value) => return Ok(value),
+                Err(e @ (RpcError::Remote(_) | RpcError::Transport(Error::Closed))) => {
+                    return Err(e)
+                }
+                Err(e) => failures.push(e),
+            }
+        }
+
+        Err(RpcError::RetriesExhausted(failures))
+    }
+
+    pub fn notify(&self, method: &str, params: Vec<Value>) -> Result<(), RpcError> {
+        let notification = Value::Array(vec![
+            Value::Int(NOTIFICATION),
+            Value::from(method),
+            Value::Array(params),
+        ]);
+
+        self.client
+            .send(notification.encode())
+            .and_then(SendTicket::wait)
+            .map_err(RpcError::Transport)
+    }
+
+    pub fn set_on_request<F>(&mut self, method: &str, handler: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, Value> + Send + Sync + 'static,
+    {
+        if let Ok(mut handlers) = self.state.request_handlers.lock() {
+            handlers.insert(method.to_string(), Arc::new(handler));
+        }
+    }
+
+    pub fn set_on_notification<F>(&mut self, method: &str, handler: F)
+    where
+        F: Fn(&[Value]) + Send + Sync + 'static,
+    {
+        if let Ok(mut handlers) = self.state.notification_handlers.lock() {
+            handlers.insert(method.to_string(), Arc::new(handler));
+        }
+    }
+
+    fn call_with(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+        timeout: Option<Duration>,
+    ) -> Result<Value, RpcError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = channel();
+
+        if let Ok(mut pending) = self.state.pending.lock() {
+            pending.insert(id, sender);
+        }
+
+        let request = Value::Array(vec![
+            Value::Int(REQUEST),
+            Value::from(id),
+            Value::from(method),
+            Value::Array(params),
+        ]);
+
+        if let Err(e) = self
+            .client
+            .send(request.encode())
+            .and_then(SendTicket::wait)
+        {
+            self.forget(id);
+            return Err(RpcError::Transport(e));
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let wait = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+
+                    if remaining.is_zero() {
+                        self.forget(id);
+                        return Err(RpcError::Timeout);
+                    }
+
+                    remaining.min(CLOSE_CHECK_INTERVAL)
+                }
+                None => CLOSE_CHECK_INTERVAL,
+            };
+
+            match receiver.recv_timeout(wait) {
+                Ok(response) => return response.map_err(RpcError::Remote),
+                Err(RecvTimeoutError::Disconnected) => return Err(RpcError::Disconnected),
+                Err(RecvTimeoutError::Timeout) if self.is_closed() => {
+                    self.forget(id);
+                    return Err(RpcError::Disconnected);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        match self.client.downgrade().upgrade() {
+            Some(data) => data.is_closed(),
+            None => true,
+        }
+    }
+
+    fn forget(&self, id: u32) {
+        if let Ok(mut pending) = self.state.pending.lock() {
+            pending.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{client_over, read_frame, within, write_frame};
+    use crate::{MemoryTransport, Transport};
+
+    // The id of the request the server just received.
+    fn read_request(server: &MemoryTransport) -> Value {
+        let request = Value::decode(&read_frame(server).unwrap()).unwrap();
+        request.as_array().unwrap()[1].clone()
+    }
+
+    fn call_in_background(rpc: Arc<RpcClient>) -> thread::JoinHandle<Result<Value, RpcError>> {
+        thread::spawn(move || rpc.call("sum", vec![1.into(), 2.into()]))
+    }
+
+    #[test]
+    fn a_call_returns_the_response_with_its_id() {
+        let (transport, server) = MemoryTransport::pair();
+        let rpc = Arc::new(RpcClient::new(client_over(vec![transport])));
+        let call = call_in_background(rpc.clone());
+
+        let id = read_request(&server);
+        let response = Value::Array(vec![Value::Int(RESPONSE), id, Value::Nil, 3.into()]);
+        write_frame(&server, &response.encode());
+
+        assert_eq!(within(move || call.join().unwrap()), Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn a_call_fails_when_the_server_drops_mid_call() {
+        let (transport, server) = MemoryTransport::pair();
+        let rpc = Arc::new(RpcClient::new(client_over(vec![transport])));
+        let call = call_in_background(rpc.clone());
+
+        read_request(&server);
+        server.shutdown(Shutdown::Both).unwrap();
+
+        assert_eq!(
+            within(move || call.join().unwrap()),
+            Err(RpcError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn a_call_fails_when_the_client_disconnects_mid_call() {
+        let (transport, server) = MemoryTransport::pair();
+        let rpc = Arc::new(RpcClient::new(client_over(vec![transport])));
+        let call = call_in_background(rpc.clone());
+
+        read_request(&server);
+        rpc.client().disconnect();
+
+        assert_eq!(
+            within(move || call.join().unwrap()),
+            Err(RpcError::Disconnected)
+        );
+    }
+}
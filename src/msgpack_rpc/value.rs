@@ -0,0 +1,536 @@
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    // Integers that fit in an i64 always decode to Int, whatever their wire
+    // encoding; UInt is only used above i64::MAX.
+    Int(i64),
+    UInt(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Binary(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Ext(i8, Vec<u8>),
+}
+
+impl Value {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Int(value) => u64::try_from(*value).ok(),
+            Value::UInt(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F32(value) => Some(*value as f64),
+            Value::F64(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Binary(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(Value, Value)]> {
+        match self {
+            Value::Map(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.encode_into(&mut buffer);
+        buffer
+    }
+
+    pub fn encode_into(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Nil => buffer.push(0xc0),
+            Value::Bool(false) => buffer.push(0xc2),
+            Value::Bool(true) => buffer.push(0xc3),
+            Value::Int(value) => {
+                if *value >= 0 {
+                    encode_uint(*value as u64, buffer);
+                } else {
+                    encode_negative_int(*value, buffer);
+                }
+            }
+            Value::UInt(value) => encode_uint(*value, buffer),
+            Value::F32(value) => {
+                buffer.push(0xca);
+                buffer.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::F64(value) => {
+                buffer.push(0xcb);
+                buffer.extend_from_slice(&value.to_be_bytes());
+            }
+            Value::String(value) => {
+                let len = value.len();
+
+                if len < 32 {
+                    buffer.push(0xa0 | len as u8);
+                } else {
+                    encode_len(len, [0xd9, 0xda, 0xdb], buffer);
+                }
+
+                buffer.extend_from_slice(value.as_bytes());
+            }
+            Value::Binary(value) => {
+                encode_len(value.len(), [0xc4, 0xc5, 0xc6], buffer);
+                buffer.extend_from_slice(value);
+            }
+            Value::Array(values) => {
+                let len = values.len();
+
+                if len < 16 {
+                    buffer.push(0x90 | len as u8);
+                } else {
+                    encode_len16(len, [0xdc, 0xdd], buffer);
+                }
+
+                for value in values {
+                    value.encode_into(buffer);
+                }
+            }
+            Value::Map(entries) => {
+                let len = entries.len();
+
+                if len < 16 {
+                    buffer.push(0x80 | len as u8);
+                } else {
+                    encode_len16(len, [0xde, 0xdf], buffer);
+                }
+
+                for (key, value) in entries {
+                    key.encode_into(buffer);
+                    value.encode_into(buffer);
+                }
+            }
+            Value::Ext(kind, data) => {
+                match data.len() {
+                    1 => buffer.push(0xd4),
+                    2 => buffer.push(0xd5),
+                    4 => buffer.push(0xd6),
+                    8 => buffer.push(0xd7),
+                    16 => buffer.push(0xd8),
+                    len => encode_len(len, [0xc7, 0xc8, 0xc9], buffer),
+                }
+
+                buffer.push(*kind as u8);
+                buffer.extend_from_slice(data);
+            }
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, String> {
+        let mut decoder = Decoder {
+            data,
+            position: 0,
+            depth: 0,
+        };
+        let value = decoder.value()?;
+
+        if decoder.position != data.len() {
+            return Err(format!(
+                "Trailing {} bytes after MessagePack value",
+                data.len() - decoder.position
+            ));
+        }
+
+        Ok(value)
+    }
+}
+
+fn encode_uint(value: u64, buffer: &mut Vec<u8>) {
+    if value < 0x80 {
+        buffer.push(value as u8);
+    } else if value <= u8::MAX as u64 {
+        buffer.push(0xcc);
+        buffer.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        buffer.push(0xcd);
+        buffer.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        buffer.push(0xce);
+        buffer.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        buffer.push(0xcf);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn encode_negative_int(value: i64, buffer: &mut Vec<u8>) {
+    if value >= -32 {
+        buffer.push(value as u8);
+    } else if value >= i8::MIN as i64 {
+        buffer.push(0xd0);
+        buffer.push(value as u8);
+    } else if value >= i16::MIN as i64 {
+        buffer.push(0xd1);
+        buffer.extend_from_slice(&(value as i16).to_be_bytes());
+    } else if value >= i32::MIN as i64 {
+        buffer.push(0xd2);
+        buffer.extend_from_slice(&(value as i32).to_be_bytes());
+    } else {
+        buffer.push(0xd3);
+        buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn encode_len(len: usize, markers: [u8; 3], buffer: &mut Vec<u8>) {
+    if len <= u8::MAX as usize {
+        buffer.push(markers[0]);
+        buffer.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        buffer.push(markers[1]);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(markers[2]);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn encode_len16(len: usize, markers: [u8; 2], buffer: &mut Vec<u8>) {
+    if len <= u16::MAX as usize {
+        buffer.push(markers[0]);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(markers[1]);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+// Arrays and maps nested deeper than this are rejected, so a peer can't
+// overflow the receive thread's stack with a few KB of nested markers.
+const MAX_DEPTH: usize = 128;
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    // Arrays and maps open around the value being decoded.
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.data.len() - self.position < len {
+            return Err("Unexpected end of MessagePack data".to_string());
+        }
+
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take_array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take_array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    fn string(&mut self, len: usize) -> Result<Value, String> {
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(value) => Ok(Value::String(value.to_string())),
+            Err(e) => Err(format!("MessagePack string is not valid UTF-8: {e}")),
+        }
+    }
+
+    fn nested<F>(&mut self, decode: F) -> Result<Value, String>
+    where
+        F: FnOnce(&mut Self) -> Result<Value, String>,
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(format!(
+                "MessagePack value is nested deeper than {MAX_DEPTH} levels"
+            ));
+        }
+
+        self.depth += 1;
+        let value = decode(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn array(&mut self, len: usize) -> Result<Value, String> {
+        self.nested(|decoder| {
+            let mut values = Vec::with_capacity(len.min(decoder.data.len()));
+
+            for _ in 0..len {
+                values.push(decoder.value()?);
+            }
+
+            Ok(Value::Array(values))
+        })
+    }
+
+    fn map(&mut self, len: usize) -> Result<Value, String> {
+        self.nested(|decoder| {
+            let mut entries = Vec::with_capacity(len.min(decoder.data.len()));
+
+            for _ in 0..len {
+                let key = decoder.value()?;
+                let value = decoder.value()?;
+                entries.push((key, value));
+            }
+
+            Ok(Value::Map(entries))
+        })
+    }
+
+    fn ext(&mut self, len: usize) -> Result<Value, String> {
+        let kind = self.u8()? as i8;
+        Ok(Value::Ext(kind, self.take(len)?.to_vec()))
+    }
+
+    fn uint(value: u64) -> Value {
+        match i64::try_from(value) {
+            Ok(value) => Value::Int(value),
+            Err(_) => Value::UInt(value),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let marker = self.u8()?;
+
+        match marker {
+            0x00..=0x7f => Ok(Value::Int(marker as i64)),
+            0x80..=0x8f => self.map((marker & 0x0f) as usize),
+            0x90..=0x9f => self.array((marker & 0x0f) as usize),
+            0xa0..=0xbf => self.string((marker & 0x1f) as usize),
+            0xc0 => Ok(Value::Nil),
+            0xc2 => Ok(Value::Bool(false)),
+            0xc3 => Ok(Value::Bool(true)),
+            0xc4 => {
+                let len = self.u8()? as usize;
+                Ok(Value::Binary(self.take(len)?.to_vec()))
+            }
+            0xc5 => {
+                let len = self.u16()? as usize;
+                Ok(Value::Binary(self.take(len)?.to_vec()))
+            }
+            0xc6 => {
+                let len = self.u32()? as usize;
+                Ok(Value::Binary(self.take(len)?.to_vec()))
+            }
+            0xc7 => {
+                let len = self.u8()? as usize;
+                self.ext(len)
+            }
+            0xc8 => {
+                let len = self.u16()? as usize;
+                self.ext(len)
+            }
+            0xc9 => {
+                let len = self.u32()? as usize;
+                self.ext(len)
+            }
+            0xca => Ok(Value::F32(f32::from_be_bytes(self.take_array()?))),
+            0xcb => Ok(Value::F64(f64::from_be_bytes(self.take_array()?))),
+            0xcc => Ok(Value::Int(self.u8()? as i64)),
+            0xcd => Ok(Value::Int(self.u16()? as i64)),
+            0xce => Ok(Value::Int(self.u32()? as i64)),
+            0xcf => Ok(Self::uint(self.u64()?)),
+            0xd0 => Ok(Value::Int(self.u8()? as i8 as i64)),
+            0xd1 => Ok(Value::Int(self.u16()? as i16 as i64)),
+            0xd2 => Ok(Value::Int(self.u32()? as i32 as i64)),
+            0xd3 => Ok(Value::Int(self.u64()? as i64)),
+            0xd4 => self.ext(1),
+            0xd5 => self.ext(2),
+            0xd6 => self.ext(4),
+            0xd7 => self.ext(8),
+            0xd8 => self.ext(16),
+            0xd9 => {
+                let len = self.u8()? as usize;
+                self.string(len)
+            }
+            0xda => {
+                let len = self.u16()? as usize;
+                self.string(len)
+            }
+            0xdb => {
+                let len = self.u32()? as usize;
+                self.string(len)
+            }
+            0xdc => {
+                let len = self.u16()? as usize;
+                self.array(len)
+            }
+            0xdd => {
+                let len = self.u32()? as usize;
+                self.array(len)
+            }
+            0xde => {
+                let len = self.u16()? as usize;
+                self.map(len)
+            }
+            0xdf => {
+                let len = self.u32()? as usize;
+                self.map(len)
+            }
+            0xe0..=0xff => Ok(Value::Int(marker as i8 as i64)),
+            0xc1 => Err("Invalid MessagePack marker 0xc1".to_string()),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Decoder::uint(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::F64(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Value::Binary(value.to_vec())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::Array(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_arrays(depth: usize) -> Vec<u8> {
+        let mut data = vec![0x91; depth];
+        data.push(0xc0);
+        data
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::Map(vec![
+            (
+                Value::from("list"),
+                Value::Array(vec![1.into(), Value::Nil]),
+            ),
+            (Value::from("flag"), Value::Bool(true)),
+        ]);
+
+        assert_eq!(Value::decode(&value.encode()).unwrap(), value);
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_decodes() {
+        let mut value = Value::decode(&nested_arrays(MAX_DEPTH)).unwrap();
+
+        for _ in 0..MAX_DEPTH {
+            value = value.as_array().unwrap()[0].clone();
+        }
+        assert!(value.is_nil());
+    }
+
+    #[test]
+    fn deeper_nesting_is_rejected_instead_of_overflowing_the_stack() {
+        let error = Value::decode(&nested_arrays(MAX_DEPTH + 1)).unwrap_err();
+        assert!(error.contains("nested deeper"), "{error}");
+
+        // Deep enough to overflow a thread's stack without the limit.
+        assert!(Value::decode(&nested_arrays(1_000_000)).is_err());
+
+        let mut maps = vec![0x81; 10_000];
+        maps.push(0xc0);
+        assert!(Value::decode(&maps).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::OBFUSCATION_SALT_SIZE;
+use crate::Transport;
+
+// Disguises the byte stream on networks that block traffic they recognize.
+// This is not encryption: anyone who knows or guesses the key, or watches
+// enough traffic, can undo it. Offsets count bytes from the start of the
+// stream in one direction, after the salt, so a transform never needs to
+// remember what it has seen.
+pub trait Obfuscation: Send + Sync {
+    fn obfuscate(&self, salt: &[u8], offset: u64, bytes: &mut [u8]);
+
+    // Undoes the peer's obfuscate. The default suits transforms that are
+    // their own inverse, like XOR.
+    fn deobfuscate(&self, salt: &[u8], offset: u64, bytes: &mut [u8]) {
+        self.obfuscate(salt, offset, bytes);
+    }
+}
+
+// XORs the stream with a keystream made from the key and the salt, the
+// same in both directions. For a server to implement it: the seed is the
+// 64-bit FNV-1a hash of the key followed by the salt, and the byte at
+// offset n is byte n % 8 (little-endian) of splitmix64(seed + n / 8).
+#[derive(Clone, Debug)]
+pub struct XorObfuscation {
+    key: Vec<u8>,
+}
+
+// Wraps a transport so everything it carries is obfuscated. A fresh salt
+// is written in the clear first, so two connections with the same key
+// never look alike; the server reads it before anything else and
+// obfuscates what it sends with the same salt.
+pub struct ObfuscatedTransport<T: Transport> {
+    inner: T,
+    obfuscation: Arc<dyn Obfuscation>,
+    salt: [u8; OBFUSCATION_SALT_SIZE],
+    // Held across the write so the offset always matches what was written.
+    written: Mutex<u64>,
+    read: Mutex<u64>,
+}
+
+impl XorObfuscation {
+    pub fn new<K>(key: K) -> Self
+    where
+        K: Into<Vec<u8>>,
+    {
+        Self { key: key.into() }
+    }
+
+    fn seed(&self, salt: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+        for byte in self.key.iter().chain(salt) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+
+        hash
+    }
+}
+
+impl Obfuscation for XorObfuscation {
+    fn obfuscate(&self, salt: &[u8], offset: u64, bytes: &mut [u8]) {
+        let seed = self.seed(salt);
+
+        for (position, byte) in (offset..).zip(bytes.iter_mut()) {
+            let block = splitmix64(seed.wrapping_add(position / 8));
+            *byte ^= block.to_le_bytes()[(position % 8) as usize];
+        }
+    }
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Unpredictable enough to tell connections apart, which is all a salt for
+// obfuscation needs.
+fn new_salt() -> [u8; OBFUSCATION_SALT_SIZE] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut salt = [0; OBFUSCATION_SALT_SIZE];
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_nanos() as u64,
+        Err(_) => 0,
+    };
+
+    for chunk in salt.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(nanos);
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::SeqCst));
+
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+
+    salt
+}
+
+impl<T: Transport> ObfuscatedTransport<T> {
+    // Writes the salt, so the inner transport must still be blocking, as
+    // it is inside a connector.
+    pub fn new(inner: T, obfuscation: Arc<dyn Obfuscation>) -> io::Result<Self> {
+        let salt = new_salt();
+        let mut remaining = &salt[..];
+
+        while !remaining.is_empty() {
+            match inner.write(remaining) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(size) => remaining = &remaining[size..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self {
+            inner,
+            obfuscation,
+            salt,
+            written: Mutex::new(0),
+            read: Mutex::new(0),
+        })
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+}
+
+impl<T: Transport> Transport for ObfuscatedTransport<T> {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut read = match self.read.lock() {
+            Ok(read) => read,
+            Err(e) => e.into_inner(),
+        };
+
+        let size = self.inner.read(buffer)?;
+
+        self.obfuscation
+            .deobfuscate(&self.salt, *read, &mut buffer[..size]);
+        *read += size as u64;
+
+        Ok(size)
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut written = match self.written.lock() {
+            Ok(written) => written,
+            Err(e) => e.into_inner(),
+        };
+
+        let mut obfuscated = data.to_vec();
+        self.obfuscation
+            .obfuscate(&self.salt, *written, &mut obfuscated);
+
+        let size = self.inner.write(&obfuscated)?;
+        *written += size as u64;
+
+        Ok(size)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read_retry_delay(&self) -> Option<Duration> {
+        self.inner.read_retry_delay()
+    }
+
+    fn write_retry_delay(&self) -> Option<Duration> {
+        self.inner.write_retry_delay()
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        self.inner.as_tcp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client_over, read_exact, FlakyTransport};
+    use crate::MemoryTransport;
+
+    const KEY: &[u8] = b"not a secret";
+
+    fn xor() -> Arc<dyn Obfuscation> {
+        Arc::new(XorObfuscation::new(KEY))
+    }
+
+    // The server's end: the salt comes first, in the clear.
+    fn read_salt(server: &MemoryTransport) -> [u8; OBFUSCATION_SALT_SIZE] {
+        let mut salt = [0; OBFUSCATION_SALT_SIZE];
+        assert!(read_exact(server, &mut salt));
+        salt
+    }
+
+    fn write_all<T: Transport>(transport: &T, mut data: &[u8]) {
+        while !data.is_empty() {
+            match transport.write(data) {
+                Ok(size) => data = &data[size..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("write failed: {e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn partial_writes_keep_the_keystream_in_step() {
+        let (transport, server) = MemoryTransport::pair();
+        // Six writes take the salt, then the message stalls once partway.
+        let faults = [None; 8]
+            .into_iter()
+            .chain([Some(io::ErrorKind::WouldBlock)]);
+        let flaky = FlakyTransport::short_writes(transport, 3).with_write_faults(faults);
+        let obfuscated = ObfuscatedTransport::new(flaky, xor()).unwrap();
+
+        let salt = read_salt(&server);
+        assert_eq!(salt, obfuscated.salt());
+
+        let message = b"the quick brown fox jumps over the lazy dog";
+        write_all(&obfuscated, message);
+
+        let mut received = vec![0; message.len()];
+        assert!(read_exact(&server, &mut received));
+        assert_ne!(&received[..], &message[..]);
+
+        XorObfuscation::new(KEY).deobfuscate(&salt, 0, &mut received);
+        assert_eq!(&received[..], &message[..]);
+    }
+
+    #[test]
+    fn reads_in_small_pieces_are_deobfuscated_at_their_offset() {
+        let (transport, server) = MemoryTransport::pair();
+        let obfuscated = ObfuscatedTransport::new(transport, xor()).unwrap();
+        let salt = read_salt(&server);
+
+        let message = b"a reply from the server".to_vec();
+        let mut sent = message.clone();
+        XorObfuscation::new(KEY).obfuscate(&salt, 0, &mut sent);
+        Transport::write(&server, &sent).unwrap();
+
+        let mut received = Vec::new();
+        let mut buffer = [0; 5];
+        while received.len() < message.len() {
+            let size = obfuscated.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..size]);
+        }
+
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn a_client_frames_through_the_obfuscation() {
+        let (transport, server) = MemoryTransport::pair();
+        let flaky = FlakyTransport::short_writes(transport, 5);
+        let client = client_over(vec![ObfuscatedTransport::new(flaky, xor()).unwrap()]);
+        let salt = read_salt(&server);
+
+        client.send("hello").unwrap().wait().unwrap();
+
+        let mut frame = vec![0; 8 + 5];
+        assert!(read_exact(&server, &mut frame));
+        XorObfuscation::new(KEY).deobfuscate(&salt, 0, &mut frame);
+        assert_eq!(frame[..8], 5u64.to_le_bytes());
+        assert_eq!(&frame[8..], b"hello");
+    }
+
+    #[test]
+    fn every_connection_gets_its_own_salt() {
+        let (first, _first_server) = MemoryTransport::pair();
+        let (second, _second_server) = MemoryTransport::pair();
+
+        let first = ObfuscatedTransport::new(first, xor()).unwrap();
+        let second = ObfuscatedTransport::new(second, xor()).unwrap();
+        assert_ne!(first.salt(), second.salt());
+    }
+}
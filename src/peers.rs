@@ -0,0 +1,232 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::{Error, SendTicket, TcpClient, TcpClientData};
+
+// Every frame starts with a kind byte and a u64 LE peer ID. A relaying
+// server forwards what another client sent as a sent-message frame with the
+// sender's ID, and announces a client going away with a disconnected frame,
+// which has no payload. Clients address a peer by sending a sent-message
+// frame with the recipient's ID.
+pub(crate) const SENT_MESSAGE: u8 = 0;
+pub(crate) const DISCONNECTED: u8 = 1;
+const HEADER_SIZE: usize = 9;
+
+type OnPeerMessageCallback = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+type OnPeerLeftCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+struct PeerState {
+    // Peers that sent something and haven't left since.
+    roster: Mutex<BTreeSet<u64>>,
+    on_peer_message: Mutex<OnPeerMessageCallback>,
+    on_peer_left: Mutex<OnPeerLeftCallback>,
+}
+
+// Routes peer traffic through a relaying server, as chat-style apps do, and
+// keeps the roster of peers that are known to be connected.
+pub struct PeerClient {
+    client: TcpClient,
+    state: Arc<PeerState>,
+}
+
+impl PeerState {
+    fn handle(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() < HEADER_SIZE {
+            return Err(Error::Protocol(
+                "Frame is too short to carry a peer header".to_string(),
+            ));
+        }
+
+        let id = u64::from_le_bytes(frame[1..HEADER_SIZE].try_into().unwrap());
+
+        match frame[0] {
+            SENT_MESSAGE => {
+                match self.roster.lock() {
+                    Ok(mut roster) => roster.insert(id),
+                    Err(e) => e.into_inner().insert(id),
+                };
+
+                let on_peer_message = match self.on_peer_message.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_peer_message(id, &frame[HEADER_SIZE..]);
+                Ok(())
+            }
+            DISCONNECTED => {
+                let known = match self.roster.lock() {
+                    Ok(mut roster) => roster.remove(&id),
+                    Err(e) => e.into_inner().remove(&id),
+                };
+
+                if known {
+                    self.left(id);
+                }
+
+                Ok(())
+            }
+            kind => Err(Error::Protocol(format!("Unknown peer frame kind {kind}"))),
+        }
+    }
+
+    fn left(&self, id: u64) {
+        let on_peer_left = match self.on_peer_left.lock() {
+            Ok(callback) => callback.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+        on_peer_left(id);
+    }
+
+    // Departures announced while disconnected were missed, so every known
+    // peer is reported as gone and the roster starts over.
+    fn forget_all(&self) {
+        let roster = match self.roster.lock() {
+            Ok(mut roster) => std::mem::take(&mut *roster),
+            Err(e) => std::mem::take(&mut *e.into_inner()),
+        };
+
+        for id in roster {
+            self.left(id);
+        }
+    }
+}
+
+impl PeerClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(PeerState {
+            roster: Mutex::new(BTreeSet::new()),
+            on_peer_message: Mutex::new(Arc::new(|_, _| {})),
+            on_peer_left: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Err(e) = state_ref.handle(frame) {
+                if let Some(data) = data.upgrade() {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let state_ref = state.clone();
+
+        client.set_on_reconnected(move || state_ref.forget_all());
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // The IDs of known peers, in ascending order.
+    pub fn peers(&self) -> Vec<u64> {
+        match self.state.roster.lock() {
+            Ok(roster) => roster.iter().copied().collect(),
+            Err(e) => e.into_inner().iter().copied().collect(),
+        }
+    }
+
+    pub fn is_known(&self, id: u64) -> bool {
+        match self.state.roster.lock() {
+            Ok(roster) => roster.contains(&id),
+            Err(e) => e.into_inner().contains(&id),
+        }
+    }
+
+    pub fn send_to<T>(&self, id: u64, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let payload = payload.as_ref();
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.push(SENT_MESSAGE);
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        self.client.send(frame)
+    }
+
+    // A peer joins the roster with its first message.
+    pub fn set_on_peer_message<F>(&mut self, callback: F)
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_peer_message.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    // Called for peers in the roster only, and for all of them after a
+    // reconnect, from on_reconnected; sends from there may reconnect again.
+    pub fn set_on_peer_left<F>(&mut self, callback: F)
+    where
+        F: Fn(u64) + Send + Sync + 'static,
+    {
+        match self.state.on_peer_left.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::*;
+    use crate::queue::Lane;
+    use crate::testing::{
+        client_over, instant_reconnects, read_frame, wait_until, within, write_frame,
+    };
+    use crate::{ErrorAction, ErrorPolicy, MemoryTransport, Transport};
+
+    fn peer_frame(kind: u8, id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn peers_left_on_reconnect_can_be_messaged_from_the_callback() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let (third, third_server) = MemoryTransport::pair();
+
+        // The goodbye fails on the second connection, reconnecting again
+        // from inside on_reconnected.
+        second_server.shutdown(Shutdown::Both).unwrap();
+
+        let mut client = client_over(vec![first, second, third]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let data = client.downgrade();
+        let mut peers = PeerClient::new(client);
+        peers.set_on_peer_left(move |id| {
+            if let Some(data) = data.upgrade() {
+                let _ = data.send(&peer_frame(SENT_MESSAGE, id, b"bye"), Lane::Data);
+            }
+        });
+        peers.client().receive().unwrap();
+
+        write_frame(&first_server, &peer_frame(SENT_MESSAGE, 7, b"hi"));
+        wait_until(|| peers.is_known(7));
+
+        first_server.shutdown(Shutdown::Both).unwrap();
+
+        let frame = within(move || read_frame(&third_server));
+        assert_eq!(frame.unwrap(), peer_frame(SENT_MESSAGE, 7, b"bye"));
+        assert!(peers.peers().is_empty());
+    }
+}
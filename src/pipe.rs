@@ -0,0 +1,109 @@
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::windows::io::AsRawHandle;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::Transport;
+
+const ERROR_BROKEN_PIPE: i32 = 109;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn PeekNamedPipe(
+        pipe: *mut c_void,
+        buffer: *mut c_void,
+        buffer_size: u32,
+        bytes_read: *mut u32,
+        total_bytes_available: *mut u32,
+        bytes_left_this_message: *mut u32,
+    ) -> i32;
+}
+
+// The client end of a Windows named pipe in byte mode, e.g.
+// \\.\pipe\name. The handle is synchronous, and a blocking read would hold
+// up writes on it, so in nonblocking mode reads first peek at how much is
+// buffered and never wait.
+pub struct PipeTransport {
+    file: File,
+    nonblocking: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl PipeTransport {
+    pub fn connect(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(Self {
+            file,
+            nonblocking: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    fn available(&self) -> io::Result<usize> {
+        let mut available: u32 = 0;
+        let peeked = unsafe {
+            PeekNamedPipe(
+                self.file.as_raw_handle(),
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                &mut available,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if peeked == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(available as usize)
+    }
+}
+
+impl Transport for PipeTransport {
+    // A server closing its end reads as end of stream, as on a socket.
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+
+        let mut size = buffer.len();
+
+        if self.nonblocking.load(Ordering::SeqCst) {
+            size = match self.available() {
+                Ok(0) => return Err(io::ErrorKind::WouldBlock.into()),
+                Ok(available) => size.min(available),
+                Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE) => return Ok(0),
+                Err(e) => return Err(e),
+            };
+        }
+
+        match (&self.file).read(&mut buffer[..size]) {
+            Err(e) if e.raw_os_error() == Some(ERROR_BROKEN_PIPE) => Ok(0),
+            result => result,
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        (&self.file).write(data)
+    }
+
+    // Pipes can't be half-closed, so any shutdown ends both directions; the
+    // handle itself is closed when the transport is dropped.
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    // Retry the failed read or write up to this many consecutive times before
+    // closing. There is nothing to retry when the peer closes, so there it
+    // behaves like Continue.
+    Retry(u32),
+    // Reconnect according to the reconnect policy, closing if that fails.
+    Reconnect,
+    // Report the error and carry on.
+    Continue,
+    // Report the error and close the connection.
+    Close,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorPolicy {
+    pub read_error: ErrorAction,
+    pub write_error: ErrorAction,
+    pub peer_closed: ErrorAction,
+}
+
+// When to declare a connection dead whose reads keep failing, whatever the
+// read error action says: after this many consecutive read errors, or once
+// they have gone on this long without a successful read. Either limit can
+// be left out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FailureThreshold {
+    pub max_consecutive_errors: Option<u32>,
+    pub max_failure_duration: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            read_error: ErrorAction::Close,
+            write_error: ErrorAction::Continue,
+            peer_closed: ErrorAction::Continue,
+        }
+    }
+}
+
+impl FailureThreshold {
+    pub fn is_reached(&self, consecutive_errors: u32, failing_for: Duration) -> bool {
+        self.max_consecutive_errors
+            .is_some_and(|limit| consecutive_errors >= limit)
+            || self
+                .max_failure_duration
+                .is_some_and(|limit| failing_for >= limit)
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1).saturating_pow(attempt);
+
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::peers::PeerClient;
+
+type OnPeerMessageCallback = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+type OnChangeCallback = Arc<dyn Fn(PresenceChange) + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceChange {
+    Joined(u64),
+    Left(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerPresence {
+    pub id: u64,
+    pub online: bool,
+    // When the peer last sent something, or left.
+    pub last_seen: SystemTime,
+}
+
+// Every peer seen so far, in ascending ID order, e.g. to populate a UI on
+// connect.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RosterSnapshot {
+    pub peers: Vec<PeerPresence>,
+}
+
+impl RosterSnapshot {
+    pub fn online(&self) -> impl Iterator<Item = &PeerPresence> {
+        self.peers.iter().filter(|peer| peer.online)
+    }
+
+    // Timestamps are milliseconds since the Unix epoch.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"peers\":[");
+
+        for (index, peer) in self.peers.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+
+            let last_seen = match peer.last_seen.duration_since(UNIX_EPOCH) {
+                Ok(elapsed) => elapsed.as_millis(),
+                Err(_) => 0,
+            };
+
+            let _ = write!(
+                json,
+                "{{\"id\":{},\"online\":{},\"last_seen\":{}}}",
+                peer.id, peer.online, last_seen,
+            );
+        }
+
+        json.push_str("]}");
+        json
+    }
+}
+
+struct PresenceState {
+    // Departed peers stay, offline, until forgotten.
+    peers: Mutex<BTreeMap<u64, PeerPresence>>,
+    on_peer_message: Mutex<OnPeerMessageCallback>,
+    on_change: Mutex<OnChangeCallback>,
+}
+
+// Tracks who is online on top of a PeerClient, with when each peer was last
+// seen, and reports joins and leaves as they happen.
+pub struct PresenceClient {
+    peers: PeerClient,
+    state: Arc<PresenceState>,
+}
+
+impl PresenceState {
+    fn seen(&self, id: u64) {
+        let presence = PeerPresence {
+            id,
+            online: true,
+            last_seen: SystemTime::now(),
+        };
+
+        let previous = match self.peers.lock() {
+            Ok(mut peers) => peers.insert(id, presence),
+            Err(e) => e.into_inner().insert(id, presence),
+        };
+
+        if !previous.is_some_and(|previous| previous.online) {
+            self.changed(PresenceChange::Joined(id));
+        }
+    }
+
+    fn left(&self, id: u64) {
+        let presence = PeerPresence {
+            id,
+            online: false,
+            last_seen: SystemTime::now(),
+        };
+
+        let previous = match self.peers.lock() {
+            Ok(mut peers) => peers.insert(id, presence),
+            Err(e) => e.into_inner().insert(id, presence),
+        };
+
+        if previous.is_some_and(|previous| previous.online) {
+            self.changed(PresenceChange::Left(id));
+        }
+    }
+
+    fn changed(&self, change: PresenceChange) {
+        let on_change = match self.on_change.lock() {
+            Ok(callback) => callback.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+
+        on_change(change);
+    }
+}
+
+impl PresenceClient {
+    pub fn new(mut peers: PeerClient) -> Self {
+        let state = Arc::new(PresenceState {
+            peers: Mutex::new(BTreeMap::new()),
+            on_peer_message: Mutex::new(Arc::new(|_, _| {})),
+            on_change: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let state_ref = state.clone();
+
+        peers.set_on_peer_message(move |id, payload| {
+            state_ref.seen(id);
+
+            let on_peer_message = match state_ref.on_peer_message.lock() {
+                Ok(callback) => callback.clone(),
+                Err(e) => e.into_inner().clone(),
+            };
+
+            on_peer_message(id, payload);
+        });
+
+        let state_ref = state.clone();
+
+        peers.set_on_peer_left(move |id| state_ref.left(id));
+
+        Self { peers, state }
+    }
+
+    pub fn peers(&self) -> &PeerClient {
+        &self.peers
+    }
+
+    pub fn snapshot(&self) -> RosterSnapshot {
+        let peers = match self.state.peers.lock() {
+            Ok(peers) => peers.values().copied().collect(),
+            Err(e) => e.into_inner().values().copied().collect(),
+        };
+
+        RosterSnapshot { peers }
+    }
+
+    pub fn is_online(&self, id: u64) -> bool {
+        self.presence(id).is_some_and(|presence| presence.online)
+    }
+
+    pub fn last_seen(&self, id: u64) -> Option<SystemTime> {
+        self.presence(id).map(|presence| presence.last_seen)
+    }
+
+    fn presence(&self, id: u64) -> Option<PeerPresence> {
+        match self.state.peers.lock() {
+            Ok(peers) => peers.get(&id).copied(),
+            Err(e) => e.into_inner().get(&id).copied(),
+        }
+    }
+
+    // Drops a departed peer from the roster. Returns false for peers that
+    // are online or unknown.
+    pub fn forget(&self, id: u64) -> bool {
+        let mut peers = match self.state.peers.lock() {
+            Ok(peers) => peers,
+            Err(e) => e.into_inner(),
+        };
+
+        match peers.get(&id) {
+            Some(presence) if !presence.online => peers.remove(&id).is_some(),
+            _ => false,
+        }
+    }
+
+    // Replaces PeerClient's on_peer_message, which presence tracking uses.
+    pub fn set_on_peer_message<F>(&mut self, callback: F)
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_peer_message.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    // Called before the message that brought a peer online is delivered.
+    // After a reconnect every online peer is reported as left.
+    pub fn set_on_change<F>(&mut self, callback: F)
+    where
+        F: Fn(PresenceChange) + Send + Sync + 'static,
+    {
+        match self.state.on_change.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::fmt::Write;
+
+use crate::diagnostics::quote;
+use crate::{auth, flow, peers, sequence};
+
+// The wire format's constants, shared with the code that reads and writes
+// it, and a description of every frame layout for implementations in other
+// languages to generate code from or check themselves against.
+
+// Every length-prefixed frame starts with its payload length as a u64 LE.
+pub const LENGTH_HEADER_SIZE: usize = 8;
+// With fragmentation on, every payload starts with the message id (u32 LE),
+// the fragment index (u32 LE) and a flags byte.
+pub const FRAGMENT_HEADER_SIZE: usize = 9;
+pub const LAST_FRAGMENT: u8 = 1;
+pub const IDENTITY_MAGIC: &[u8; 4] = b"TCID";
+pub const IDENTITY_FORMAT_VERSION: u8 = 1;
+// Sent in the clear before anything else on an obfuscated connection.
+pub const OBFUSCATION_SALT_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    U8,
+    U16Le,
+    U32Le,
+    U64Le,
+    // Fixed bytes that identify the frame.
+    Magic(&'static [u8]),
+    // UTF-8 text preceded by its length as a u8.
+    Text8,
+    // UTF-8 text preceded by its length as a u16 LE.
+    Text16,
+    // Everything up to the end of the frame.
+    Rest,
+    // The fields, repeated as many times as the named earlier field says.
+    Repeated {
+        count: &'static str,
+        fields: &'static [Field],
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    // The value the field always has, like a kind byte.
+    pub value: Option<u64>,
+}
+
+// A frame layout. Frames of layers other than core are the payload of a
+// core frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameLayout {
+    pub layer: &'static str,
+    pub name: &'static str,
+    pub fields: &'static [Field],
+}
+
+const fn field(name: &'static str, field_type: FieldType) -> Field {
+    Field {
+        name,
+        field_type,
+        value: None,
+    }
+}
+
+const fn kind(value: u8) -> Field {
+    Field {
+        name: "kind",
+        field_type: FieldType::U8,
+        value: Some(value as u64),
+    }
+}
+
+const PAYLOAD: Field = field("payload", FieldType::Rest);
+
+pub static FRAMES: &[FrameLayout] = &[
+    FrameLayout {
+        layer: "core",
+        name: "frame",
+        fields: &[field("length", FieldType::U64Le), PAYLOAD],
+    },
+    FrameLayout {
+        layer: "core",
+        name: "fragment",
+        fields: &[
+            field("message_id", FieldType::U32Le),
+            field("index", FieldType::U32Le),
+            field("flags", FieldType::U8),
+            PAYLOAD,
+        ],
+    },
+    FrameLayout {
+        layer: "core",
+        name: "identity",
+        fields: &[
+            field("magic", FieldType::Magic(IDENTITY_MAGIC)),
+            Field {
+                name: "format_version",
+                field_type: FieldType::U8,
+                value: Some(IDENTITY_FORMAT_VERSION as u64),
+            },
+            field("app_name", FieldType::Text8),
+            field("version", FieldType::Text8),
+            field("instance_id", FieldType::Text8),
+            field("field_count", FieldType::U8),
+            field(
+                "fields",
+                FieldType::Repeated {
+                    count: "field_count",
+                    fields: &[
+                        field("key", FieldType::Text8),
+                        field("value", FieldType::Text16),
+                    ],
+                },
+            ),
+        ],
+    },
+    FrameLayout {
+        layer: "auth",
+        name: "data",
+        fields: &[kind(auth::DATA), PAYLOAD],
+    },
+    FrameLayout {
+        layer: "auth",
+        name: "auth",
+        fields: &[kind(auth::AUTH), field("token", FieldType::Rest)],
+    },
+    FrameLayout {
+        layer: "auth",
+        name: "auth_expired",
+        fields: &[kind(auth::AUTH_EXPIRED)],
+    },
+    FrameLayout {
+        layer: "sequence",
+        name: "data",
+        fields: &[
+            kind(sequence::DATA),
+            field("sequence", FieldType::U64Le),
+            PAYLOAD,
+        ],
+    },
+    FrameLayout {
+        layer: "sequence",
+        name: "resume",
+        fields: &[
+            kind(sequence::RESUME),
+            field("last_received", FieldType::U64Le),
+        ],
+    },
+    FrameLayout {
+        layer: "flow",
+        name: "data",
+        fields: &[kind(flow::DATA), PAYLOAD],
+    },
+    FrameLayout {
+        layer: "flow",
+        name: "window_update",
+        fields: &[
+            kind(flow::WINDOW_UPDATE),
+            field("increment", FieldType::U64Le),
+        ],
+    },
+    FrameLayout {
+        layer: "peers",
+        name: "sent_message",
+        fields: &[
+            kind(peers::SENT_MESSAGE),
+            field("peer_id", FieldType::U64Le),
+            PAYLOAD,
+        ],
+    },
+    FrameLayout {
+        layer: "peers",
+        name: "disconnected",
+        fields: &[
+            kind(peers::DISCONNECTED),
+            field("peer_id", FieldType::U64Le),
+        ],
+    },
+    FrameLayout {
+        layer: "schema",
+        name: "message",
+        fields: &[
+            field("type_id", FieldType::U32Le),
+            field("version", FieldType::U16Le),
+            PAYLOAD,
+        ],
+    },
+    FrameLayout {
+        layer: "topics",
+        name: "message",
+        fields: &[field("topic", FieldType::Text16), PAYLOAD],
+    },
+];
+
+pub fn to_json() -> String {
+    let mut json = String::from("{\"frames\":[");
+
+    for (index, frame) in FRAMES.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+
+        let _ = write!(
+            json,
+            "{{\"layer\":{},\"name\":{},\"fields\":",
+            quote(frame.layer),
+            quote(frame.name),
+        );
+        push_json_fields(&mut json, frame.fields);
+        json.push('}');
+    }
+
+    json.push_str("]}");
+    json
+}
+
+fn push_json_fields(json: &mut String, fields: &[Field]) {
+    json.push('[');
+
+    for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+
+        let _ = write!(
+            json,
+            "{{\"name\":{},\"type\":{}",
+            quote(field.name),
+            quote(type_name(&field.field_type)),
+        );
+
+        if let Some(value) = field.value {
+            let _ = write!(json, ",\"value\":{value}");
+        }
+
+        match field.field_type {
+            FieldType::Magic(bytes) => {
+                let _ = write!(json, ",\"bytes\":{}", quote(&hex(bytes)));
+            }
+            FieldType::Repeated { count, fields } => {
+                let _ = write!(json, ",\"count\":{},\"fields\":", quote(count));
+                push_json_fields(json, fields);
+            }
+            _ => {}
+        }
+
+        json.push('}');
+    }
+
+    json.push(']');
+}
+
+// One frame node per layout, with a field child per field:
+//
+//   frame "sequence" "resume" {
+//       field "kind" type="u8" value=1
+//       field "last_received" type="u64le"
+//   }
+pub fn to_kdl() -> String {
+    let mut kdl = String::new();
+
+    for frame in FRAMES {
+        let _ = writeln!(kdl, "frame {} {} {{", quote(frame.layer), quote(frame.name));
+        push_kdl_fields(&mut kdl, frame.fields, 1);
+        kdl.push_str("}\n");
+    }
+
+    kdl
+}
+
+fn push_kdl_fields(kdl: &mut String, fields: &[Field], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for field in fields {
+        let _ = write!(
+            kdl,
+            "{indent}field {} type={}",
+            quote(field.name),
+            quote(type_name(&field.field_type)),
+        );
+
+        if let Some(value) = field.value {
+            let _ = write!(kdl, " value={value}");
+        }
+
+        match field.field_type {
+            FieldType::Magic(bytes) => {
+                let _ = writeln!(kdl, " bytes={}", quote(&hex(bytes)));
+            }
+            FieldType::Repeated { count, fields } => {
+                let _ = writeln!(kdl, " count={} {{", quote(count));
+                push_kdl_fields(kdl, fields, depth + 1);
+                let _ = writeln!(kdl, "{indent}}}");
+            }
+            _ => kdl.push('\n'),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn type_name(field_type: &FieldType) -> &'static str {
+    match field_type {
+        FieldType::U8 => "u8",
+        FieldType::U16Le => "u16le",
+        FieldType::U32Le => "u32le",
+        FieldType::U64Le => "u64le",
+        FieldType::Magic(_) => "magic",
+        FieldType::Text8 => "text8",
+        FieldType::Text16 => "text16",
+        FieldType::Rest => "rest",
+        FieldType::Repeated { .. } => "repeated",
+    }
+}
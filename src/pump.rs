@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::TcpClientData;
+
+// Returns whether the task should keep running.
+pub(crate) type PeriodicTask = Box<dyn FnMut(&TcpClientData) -> bool + Send>;
+
+struct Timer {
+    interval: Duration,
+    due: Instant,
+    task: PeriodicTask,
+}
+
+// Periodic work that a background thread would otherwise do, run from
+// TcpClient::poll in manual pump mode.
+#[derive(Default)]
+pub(crate) struct Timers {
+    timers: Mutex<Vec<Timer>>,
+}
+
+impl Timers {
+    pub(crate) fn add(&self, now: Instant, interval: Duration, task: PeriodicTask) {
+        let timer = Timer {
+            interval,
+            due: now + interval,
+            task,
+        };
+
+        match self.timers.lock() {
+            Ok(mut timers) => timers.push(timer),
+            Err(e) => e.into_inner().push(timer),
+        }
+    }
+
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        match self.timers.lock() {
+            Ok(timers) => timers.iter().map(|timer| timer.due).min(),
+            Err(e) => e.into_inner().iter().map(|timer| timer.due).min(),
+        }
+    }
+
+    // Tasks run without the lock held, so they may add timers themselves.
+    pub(crate) fn run_due(&self, data: &TcpClientData, now: Instant) {
+        let mut timers = match self.timers.lock() {
+            Ok(mut timers) => std::mem::take(&mut *timers),
+            Err(e) => std::mem::take(&mut *e.into_inner()),
+        };
+
+        timers.retain_mut(|timer| {
+            if timer.due > now {
+                return true;
+            }
+
+            timer.due = now + timer.interval;
+            (timer.task)(data)
+        });
+
+        let mut current = match self.timers.lock() {
+            Ok(current) => current,
+            Err(e) => e.into_inner(),
+        };
+
+        timers.append(&mut current);
+        *current = timers;
+    }
+}
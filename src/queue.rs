@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::lifecycle::supervise;
+use crate::{BudgetAction, Clock, Error, IoThread, MemoryBudget, TcpClientData};
+
+// Waits recheck their deadline at least this often, since a mock clock can
+// pass it without signalling the queue.
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) struct QueuedMessage {
+    data: Vec<u8>,
+    completion: Sender<Result<(), Error>>,
+}
+
+// Control frames such as acks, auth or a goodbye are written ahead of
+// queued data, so they get out even when a shutdown or a reconnect leaves
+// time for little else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Lane {
+    Control,
+    Data,
+}
+
+// Messages still waiting in the write queue when the client disconnected,
+// in the order they would have been written, so the application can
+// persist or resend them. Their tickets fail with Error::QueueClosed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    pub control: Vec<Vec<u8>>,
+    pub data: Vec<Vec<u8>>,
+}
+
+impl QueueSnapshot {
+    pub fn len(&self) -> usize {
+        self.control.len() + self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct SendTicket {
+    receiver: Option<Receiver<Result<(), Error>>>,
+    result: Option<Result<(), Error>>,
+}
+
+impl SendTicket {
+    pub(crate) fn completed(result: Result<(), Error>) -> Self {
+        Self {
+            receiver: None,
+            result: Some(result),
+        }
+    }
+
+    pub(crate) fn queued(data: Vec<u8>) -> (Self, QueuedMessage) {
+        let (completion, receiver) = channel();
+
+        let ticket = Self {
+            receiver: Some(receiver),
+            result: None,
+        };
+
+        (ticket, QueuedMessage { data, completion })
+    }
+
+    // Returns the outcome of the write without blocking, or None while the
+    // message is still waiting in the queue.
+    pub fn poll(&mut self) -> Option<Result<(), Error>> {
+        if let (None, Some(receiver)) = (&self.result, &self.receiver) {
+            self.result = match receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => Some(Err(Error::QueueClosed)),
+            };
+        }
+
+        self.result.clone()
+    }
+
+    pub fn wait(self) -> Result<(), Error> {
+        match (self.result, self.receiver) {
+            (Some(result), _) => result,
+            (None, Some(receiver)) => receiver.recv().unwrap_or(Err(Error::QueueClosed)),
+            (None, None) => Err(Error::QueueClosed),
+        }
+    }
+
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<Result<(), Error>> {
+        if let (None, Some(receiver)) = (&self.result, &self.receiver) {
+            self.result = match receiver.recv_timeout(timeout) {
+                Ok(result) => Some(result),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => Some(Err(Error::QueueClosed)),
+            };
+        }
+
+        self.result.clone()
+    }
+}
+
+#[derive(Default)]
+struct Lanes {
+    control: VecDeque<QueuedMessage>,
+    data: VecDeque<QueuedMessage>,
+    // Bytes of the messages in both lanes.
+    bytes: usize,
+    // The writer is busy with a message taken off the queue.
+    writing: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct QueueState {
+    lanes: Mutex<Lanes>,
+    // Signalled when a message is queued, taken by the writer or written,
+    // or the queue closes.
+    changed: Condvar,
+}
+
+impl QueueState {
+    fn lanes(&self) -> MutexGuard<'_, Lanes> {
+        match self.lanes.lock() {
+            Ok(lanes) => lanes,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    // With a budget, room is how many bytes the queue may hold. A message
+    // that could never fit fails at once, whatever the budget's action.
+    // Waits for room are timed by the client's clock.
+    pub(crate) fn push(
+        &self,
+        lane: Lane,
+        message: QueuedMessage,
+        budget: Option<(MemoryBudget, usize)>,
+        clock: &dyn Clock,
+    ) -> Result<(), Error> {
+        let mut lanes = self.lanes();
+
+        if let Some((budget, room)) = budget {
+            let size = message.data.len();
+            let deadline = match budget.action {
+                BudgetAction::Reject => None,
+                BudgetAction::Wait(timeout) => Some(clock.now() + timeout),
+            };
+
+            while !lanes.closed && lanes.bytes.saturating_add(size) > room {
+                let remaining = match deadline {
+                    Some(deadline) if size <= room => {
+                        deadline.saturating_duration_since(clock.now())
+                    }
+                    _ => Duration::ZERO,
+                };
+
+                if remaining.is_zero() {
+                    return Err(Error::MemoryBudgetExceeded(budget.limit));
+                }
+
+                let wait = remaining.min(CLOCK_CHECK_INTERVAL);
+                lanes = match self.changed.wait_timeout(lanes, wait) {
+                    Ok((lanes, _)) => lanes,
+                    Err(e) => e.into_inner().0,
+                };
+            }
+        }
+
+        if lanes.closed {
+            return Err(Error::QueueClosed);
+        }
+
+        lanes.bytes += message.data.len();
+
+        match lane {
+            Lane::Control => lanes.control.push_back(message),
+            Lane::Data => lanes.data.push_back(message),
+        }
+
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // Blocks until there is a message to write, or returns None once the
+    // queue is closed and empty.
+    fn pop(&self) -> Option<QueuedMessage> {
+        let mut lanes = self.lanes();
+
+        loop {
+            lanes.writing = false;
+
+            let message = match lanes.control.pop_front() {
+                Some(message) => Some(message),
+                None => lanes.data.pop_front(),
+            };
+
+            if let Some(message) = message {
+                lanes.bytes -= message.data.len();
+                lanes.writing = true;
+                self.changed.notify_all();
+                return Some(message);
+            }
+
+            self.changed.notify_all();
+
+            if lanes.closed {
+                return None;
+            }
+
+            lanes = match self.changed.wait(lanes) {
+                Ok(lanes) => lanes,
+                Err(e) => e.into_inner(),
+            };
+        }
+    }
+
+    // Queued messages are still written, but no new ones are accepted.
+    pub(crate) fn close(&self) {
+        self.lanes().closed = true;
+        self.changed.notify_all();
+    }
+
+    // Waits until everything queued has been written, or the timeout has
+    // passed on the client's clock. Returns whether the queue drained.
+    pub(crate) fn wait_drained(&self, timeout: Duration, clock: &dyn Clock) -> bool {
+        let deadline = clock.now() + timeout;
+        let mut lanes = self.lanes();
+
+        loop {
+            if lanes.control.is_empty() && lanes.data.is_empty() && !lanes.writing {
+                return true;
+            }
+
+            let remaining = deadline.saturating_duration_since(clock.now());
+
+            if remaining.is_zero() {
+                return false;
+            }
+
+            let wait = remaining.min(CLOCK_CHECK_INTERVAL);
+            lanes = match self.changed.wait_timeout(lanes, wait) {
+                Ok((lanes, _)) => lanes,
+                Err(e) => e.into_inner().0,
+            };
+        }
+    }
+
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.lanes().bytes
+    }
+
+    // Removes every message not yet taken by the writer.
+    pub(crate) fn take_unsent(&self) -> QueueSnapshot {
+        let mut lanes = self.lanes();
+        lanes.bytes = 0;
+
+        QueueSnapshot {
+            control: lanes
+                .control
+                .drain(..)
+                .map(|message| message.data)
+                .collect(),
+            data: lanes.data.drain(..).map(|message| message.data).collect(),
+        }
+    }
+}
+
+// The client's end of the write queue. Dropping it, when the queue is
+// turned off or the client goes away, closes the queue and lets the writer
+// thread exit once it has written what is left.
+pub(crate) struct WriteQueue {
+    state: Arc<QueueState>,
+}
+
+impl WriteQueue {
+    pub(crate) fn state(&self) -> Arc<QueueState> {
+        self.state.clone()
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        self.state.close();
+    }
+}
+
+// The writer only holds a weak reference to the client data, whose write
+// queue closes when it is dropped.
+pub(crate) fn spawn_writer(data: Weak<TcpClientData>) -> WriteQueue {
+    let state = Arc::new(QueueState::default());
+    let state_ref = state.clone();
+
+    thread::spawn(move || {
+        let writer_state = state_ref.clone();
+
+        supervise(&data.clone(), IoThread::Writer, move || {
+            run_writer(data, &writer_state);
+            "Write queue closed".to_string()
+        });
+
+        // Should the writer have panicked, later sends fail instead of
+        // waiting forever, and so do those still queued.
+        state_ref.close();
+        state_ref.take_unsent();
+    });
+
+    WriteQueue { state }
+}
+
+fn run_writer(data: Weak<TcpClientData>, state: &QueueState) {
+    if let Some(data) = data.upgrade() {
+        data.apply_thread_hints();
+    }
+
+    while let Some(message) = state.pop() {
+        let result = match data.upgrade() {
+            Some(data) => {
+                // Polled in real time, like the receive loop's idle steps.
+                while data.is_suspended() && !data.is_closed() {
+                    thread::sleep(Duration::from_millis(50));
+                }
+
+                data.write_message(&message.data)
+            }
+            None => Err(Error::QueueClosed),
+        };
+
+        let _ = message.completion.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::within;
+    use crate::MockClock;
+
+    const BUDGET: MemoryBudget = MemoryBudget {
+        limit: 8,
+        action: BudgetAction::Wait(Duration::from_secs(30)),
+    };
+
+    fn message(size: usize) -> QueuedMessage {
+        SendTicket::queued(vec![0; size]).1
+    }
+
+    #[test]
+    fn a_push_waiting_for_room_times_out_by_the_clock() {
+        let state = Arc::new(QueueState::default());
+        let clock = MockClock::new();
+        state
+            .push(Lane::Data, message(8), Some((BUDGET, 8)), &clock)
+            .unwrap();
+
+        let state_ref = state.clone();
+        let clock_ref = clock.clone();
+        let pushing = thread::spawn(move || {
+            state_ref.push(Lane::Data, message(1), Some((BUDGET, 8)), &clock_ref)
+        });
+
+        // Real time passing doesn't expire the wait, only the clock does.
+        thread::sleep(CLOCK_CHECK_INTERVAL * 2);
+        assert!(!pushing.is_finished());
+
+        clock.advance(Duration::from_secs(30));
+        let result = within(move || pushing.join().unwrap());
+        assert!(matches!(result, Err(Error::MemoryBudgetExceeded(8))));
+    }
+
+    #[test]
+    fn a_push_waiting_for_room_goes_in_once_the_writer_takes_a_message() {
+        let state = Arc::new(QueueState::default());
+        let clock = MockClock::new();
+        state
+            .push(Lane::Data, message(8), Some((BUDGET, 8)), &clock)
+            .unwrap();
+
+        let state_ref = state.clone();
+        let pushing = thread::spawn(move || {
+            state_ref.push(Lane::Data, message(1), Some((BUDGET, 8)), &clock)
+        });
+
+        assert!(state.pop().is_some());
+        within(move || pushing.join().unwrap()).unwrap();
+        assert_eq!(state.queued_bytes(), 1);
+    }
+
+    #[test]
+    fn wait_drained_times_out_by_the_clock() {
+        let state = Arc::new(QueueState::default());
+        let clock = MockClock::new();
+        state.push(Lane::Data, message(1), None, &clock).unwrap();
+
+        let state_ref = state.clone();
+        let clock_ref = clock.clone();
+        let waiting =
+            thread::spawn(move || state_ref.wait_drained(Duration::from_secs(5), &clock_ref));
+
+        thread::sleep(CLOCK_CHECK_INTERVAL * 2);
+        assert!(!waiting.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(!within(move || waiting.join().unwrap()));
+        assert_eq!(state.queued_bytes(), 1);
+    }
+
+    #[test]
+    fn wait_drained_returns_once_the_writer_finishes() {
+        let state = Arc::new(QueueState::default());
+        let clock = MockClock::new();
+        state.push(Lane::Data, message(1), None, &clock).unwrap();
+
+        let state_ref = state.clone();
+        let waiting = thread::spawn(move || state_ref.wait_drained(Duration::from_secs(5), &clock));
+
+        // Taking the message marks the writer busy; the next pop finds the
+        // queue empty and clears it.
+        assert!(state.pop().is_some());
+        state.close();
+        assert!(state.pop().is_none());
+
+        assert!(within(move || waiting.join().unwrap()));
+        assert_eq!(state.queued_bytes(), 0);
+    }
+}
@@ -0,0 +1,264 @@
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::{Error, SendTicket, TcpClient, TcpClientData, Transport};
+
+// Every frame starts with a kind byte. Data frames carry the payload; a
+// redirect carries the UTF-8 address the server wants the client to move
+// to, for servers that spread load by sending clients elsewhere.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const REDIRECT: u8 = 1;
+
+type OnMessageReceivedCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+type OnRedirectedCallback = Arc<dyn Fn(&str) + Send + Sync>;
+type RedirectConnector = Arc<dyn Fn(&str) -> io::Result<Box<dyn Transport>> + Send + Sync>;
+
+struct RedirectState {
+    connector: Mutex<RedirectConnector>,
+    on_message_received: Mutex<OnMessageReceivedCallback>,
+    on_redirected: Mutex<OnRedirectedCallback>,
+}
+
+// Follows redirects from the server: the connection moves to the address
+// in the redirect without the client being rebuilt, so callbacks, queued
+// writes and the state of layers underneath carry over. Reconnects after a
+// later drop go back to the configured endpoints, which can redirect again.
+pub struct RedirectClient {
+    client: TcpClient,
+    state: Arc<RedirectState>,
+}
+
+// The frame a server sends, for servers and test peers built on this crate.
+pub fn redirect_frame(address: &str) -> Vec<u8> {
+    frame(REDIRECT, address.as_bytes())
+}
+
+impl RedirectState {
+    fn handle(&self, data: &TcpClientData, frame: &[u8]) -> Result<(), Error> {
+        match frame.first() {
+            Some(&DATA) => {
+                let on_message_received = match self.on_message_received.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_message_received(&frame[1..]);
+                Ok(())
+            }
+            Some(&REDIRECT) => {
+                let address = std::str::from_utf8(&frame[1..]).map_err(|_| {
+                    Error::Protocol("Redirect address is not valid UTF-8".to_string())
+                })?;
+
+                if address.is_empty() {
+                    return Err(Error::Protocol("Redirect carries no address".to_string()));
+                }
+
+                let connector = match self.connector.lock() {
+                    Ok(connector) => connector.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                data.redirect(address, || connector(address))?;
+
+                let on_redirected = match self.on_redirected.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_redirected(address);
+                Ok(())
+            }
+            _ => Err(Error::Protocol("Unrecognized redirect frame".to_string())),
+        }
+    }
+}
+
+impl RedirectClient {
+    // Redirects are followed over plain TCP unless set_connector says
+    // otherwise.
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(RedirectState {
+            connector: Mutex::new(Arc::new(|address| {
+                Ok(Box::new(TcpStream::connect(address)?) as Box<dyn Transport>)
+            })),
+            on_message_received: Mutex::new(Arc::new(|_| {})),
+            on_redirected: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.handle(&data, frame) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.client.send(frame(DATA, payload.as_ref()))
+    }
+
+    // Opens the connection to a redirect's address, for clients on TLS or
+    // another custom transport. A failure is reported through on_error and
+    // the client stays on its current connection.
+    pub fn set_connector<F, T>(&mut self, connect: F)
+    where
+        F: Fn(&str) -> io::Result<T> + Send + Sync + 'static,
+        T: Transport + 'static,
+    {
+        let connector: RedirectConnector =
+            Arc::new(move |address| Ok(Box::new(connect(address)?) as Box<dyn Transport>));
+
+        match self.state.connector.lock() {
+            Ok(mut current) => *current = connector,
+            Err(e) => *e.into_inner() = connector,
+        }
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+
+    // Called with the new address once the connection has moved, after
+    // on_reconnected.
+    pub fn set_on_redirected<F>(&mut self, callback: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        match self.state.on_redirected.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(1 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+    use crate::testing::{client_over, read_frame, write_frame, TIMEOUT};
+    use crate::MemoryTransport;
+
+    // The client, and the kinds of error it reports.
+    fn redirect_client(
+        first: MemoryTransport,
+        second: MemoryTransport,
+    ) -> (RedirectClient, Receiver<&'static str>) {
+        let mut client = client_over(vec![first]);
+
+        let (errors, failed) = channel();
+        let errors = Mutex::new(errors);
+        client.set_on_error(move |error| {
+            let kind = match error {
+                Error::Protocol(_) => "protocol",
+                Error::Connect(_) => "connect",
+                _ => "other",
+            };
+            let _ = errors.lock().unwrap().send(kind);
+        });
+
+        let mut client = RedirectClient::new(client);
+        let second = Mutex::new(Some(second));
+        client.set_connector(move |address| {
+            assert_eq!(address, "elsewhere:7000");
+            second
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+        });
+
+        (client, failed)
+    }
+
+    #[test]
+    fn a_redirect_moves_the_connection_and_keeps_the_callbacks() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let (mut client, _failed) = redirect_client(first, second);
+
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        client.set_on_message_received(move |message| {
+            let _ = messages.lock().unwrap().send(message.to_vec());
+        });
+
+        let (redirects, redirected) = channel();
+        let redirects = Mutex::new(redirects);
+        client.set_on_redirected(move |address| {
+            let _ = redirects.lock().unwrap().send(address.to_string());
+        });
+        client.client().receive().unwrap();
+
+        write_frame(&first_server, b"\x00before");
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"before");
+
+        write_frame(&first_server, &redirect_frame("elsewhere:7000"));
+        assert_eq!(redirected.recv_timeout(TIMEOUT).unwrap(), "elsewhere:7000");
+
+        // The old connection is closed for the server's benefit.
+        assert_eq!(read_frame(&first_server), None);
+
+        client.send("after").unwrap();
+        assert_eq!(read_frame(&second_server).unwrap(), b"\x00after");
+
+        write_frame(&second_server, b"\x00welcome");
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"welcome");
+    }
+
+    #[test]
+    fn an_unreachable_target_keeps_the_current_connection() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, _second_server) = MemoryTransport::pair();
+        let (mut client, failed) = redirect_client(first, second);
+        client.set_connector(|_| -> io::Result<MemoryTransport> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        });
+        client.client().receive().unwrap();
+
+        write_frame(&first_server, &redirect_frame("elsewhere:7000"));
+        assert_eq!(failed.recv_timeout(TIMEOUT).unwrap(), "connect");
+
+        client.send("still here").unwrap();
+        assert_eq!(read_frame(&first_server).unwrap(), b"\x00still here");
+    }
+
+    #[test]
+    fn a_redirect_without_an_address_is_a_protocol_error() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, _second_server) = MemoryTransport::pair();
+        let (client, failed) = redirect_client(first, second);
+        client.client().receive().unwrap();
+
+        write_frame(&first_server, &redirect_frame(""));
+        assert_eq!(failed.recv_timeout(TIMEOUT).unwrap(), "protocol");
+    }
+}
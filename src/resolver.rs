@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Clock, SystemClock};
+
+// Turns a "host:port" address into socket addresses, on connect and on every
+// reconnect.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, address: &str) -> io::Result<Vec<SocketAddr>>;
+}
+
+// The operating system's resolver, which clients use by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, address: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(address.to_socket_addrs()?.collect())
+    }
+}
+
+// Fixed answers, e.g. for tests that must run without DNS. Addresses that
+// are already an IP and port resolve to themselves; other unknown names
+// fail.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    entries: HashMap<String, Vec<SocketAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, address: &str, addresses: &[SocketAddr]) -> Self {
+        self.entries.insert(address.to_string(), addresses.to_vec());
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, address: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addresses) = self.entries.get(address) {
+            return Ok(addresses.clone());
+        }
+
+        match address.parse::<SocketAddr>() {
+            Ok(address) => Ok(vec![address]),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No static entry for \"{address}\""),
+            )),
+        }
+    }
+}
+
+// Remembers each successful lookup for ttl, so a reconnect storm doesn't
+// send a query per attempt. The system resolver doesn't report record
+// TTLs, so the same ttl applies to every name. Failures aren't cached.
+// Entries age by the system clock unless with_clock gives another, e.g. a
+// MockClock, or the one set on the client.
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    cache: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            clock: Arc::new(SystemClock),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn clear(&self) {
+        match self.cache.lock() {
+            Ok(mut cache) => cache.clear(),
+            Err(e) => e.into_inner().clear(),
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, address: &str) -> io::Result<Vec<SocketAddr>> {
+        let cached = match self.cache.lock() {
+            Ok(cache) => cache.get(address).cloned(),
+            Err(e) => e.into_inner().get(address).cloned(),
+        };
+
+        if let Some((resolved_at, addresses)) = cached {
+            if self.clock.now().saturating_duration_since(resolved_at) < self.ttl {
+                return Ok(addresses);
+            }
+        }
+
+        let addresses = self.inner.resolve(address)?;
+        let entry = (self.clock.now(), addresses.clone());
+
+        match self.cache.lock() {
+            Ok(mut cache) => cache.insert(address.to_string(), entry),
+            Err(e) => e.into_inner().insert(address.to_string(), entry),
+        };
+
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::MockClock;
+
+    // Answers with a different port on every lookup, so a cached answer
+    // can be told from a fresh one.
+    struct CountingResolver {
+        lookups: AtomicUsize,
+        fail: bool,
+    }
+
+    impl Resolver for CountingResolver {
+        fn resolve(&self, _address: &str) -> io::Result<Vec<SocketAddr>> {
+            let lookup = self.lookups.fetch_add(1, Ordering::SeqCst) + 1;
+
+            if self.fail {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+
+            Ok(vec![SocketAddr::from(([127, 0, 0, 1], lookup as u16))])
+        }
+    }
+
+    fn counting(fail: bool) -> CountingResolver {
+        CountingResolver {
+            lookups: AtomicUsize::new(0),
+            fail,
+        }
+    }
+
+    fn port(addresses: io::Result<Vec<SocketAddr>>) -> u16 {
+        addresses.unwrap()[0].port()
+    }
+
+    #[test]
+    fn lookups_are_cached_until_the_ttl_passes_on_its_clock() {
+        let clock = MockClock::new();
+        let resolver = CachingResolver::new(counting(false), Duration::from_secs(30))
+            .with_clock(clock.clone());
+
+        assert_eq!(port(resolver.resolve("example.com:1")), 1);
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(port(resolver.resolve("example.com:1")), 1);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(port(resolver.resolve("example.com:1")), 2);
+        assert_eq!(port(resolver.resolve("example.com:1")), 2);
+    }
+
+    #[test]
+    fn names_are_cached_separately_and_clear_forgets_them() {
+        let resolver = CachingResolver::new(counting(false), Duration::from_secs(30))
+            .with_clock(MockClock::new());
+
+        assert_eq!(port(resolver.resolve("first:1")), 1);
+        assert_eq!(port(resolver.resolve("second:1")), 2);
+        assert_eq!(port(resolver.resolve("first:1")), 1);
+
+        resolver.clear();
+        assert_eq!(port(resolver.resolve("first:1")), 3);
+    }
+
+    #[test]
+    fn failures_are_not_cached() {
+        let resolver = CachingResolver::new(counting(true), Duration::from_secs(30))
+            .with_clock(MockClock::new());
+
+        assert!(resolver.resolve("example.com:1").is_err());
+        assert!(resolver.resolve("example.com:1").is_err());
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::{Error, SendTicket, TcpClient, TcpClientData};
+
+// Schema frames start with the payload's type id (u32 LE) and schema version
+// (u16 LE), followed by the payload in that version's encoding.
+const SCHEMA_HEADER_SIZE: usize = 6;
+
+pub type SchemaHandler = Arc<dyn Fn(&[u8]) + Send + Sync>;
+type OnUnknownSchemaCallback = Arc<dyn Fn(&UnknownSchema) + Send + Sync>;
+
+// A frame whose (type id, version) has no registered decoder.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownSchema {
+    pub type_id: u32,
+    pub version: u16,
+    // The versions registered for this type id, so the application can tell
+    // a newer peer from a type it doesn't know at all.
+    pub known_versions: Vec<u16>,
+    pub payload: Vec<u8>,
+}
+
+// Which named handler each type and version goes to, so an application
+// with dozens of message types can keep its wiring in a file. As text it
+// is one route per line, "<type id> <version> <handler name>", where the
+// name has no spaces, with blank lines and lines starting with # ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoutingTable {
+    pub routes: Vec<Route>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub type_id: u32,
+    pub version: u16,
+    pub handler: String,
+}
+
+struct SchemaState {
+    decoders: Mutex<HashMap<(u32, u16), SchemaHandler>>,
+    on_unknown_schema: Mutex<OnUnknownSchemaCallback>,
+}
+
+pub struct SchemaClient {
+    client: TcpClient,
+    state: Arc<SchemaState>,
+}
+
+impl SchemaState {
+    fn handle(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() < SCHEMA_HEADER_SIZE {
+            return Err(Error::Protocol(
+                "Frame is too short to carry a schema header".to_string(),
+            ));
+        }
+
+        let type_id = u32::from_le_bytes(frame[..4].try_into().unwrap());
+        let version = u16::from_le_bytes(frame[4..SCHEMA_HEADER_SIZE].try_into().unwrap());
+        let payload = &frame[SCHEMA_HEADER_SIZE..];
+
+        let (decoder, known_versions) = match self.decoders.lock() {
+            Ok(decoders) => Self::lookup(&decoders, type_id, version),
+            Err(e) => Self::lookup(&e.into_inner(), type_id, version),
+        };
+
+        match decoder {
+            Some(decoder) => decoder(payload),
+            None => {
+                let on_unknown_schema = match self.on_unknown_schema.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_unknown_schema(&UnknownSchema {
+                    type_id,
+                    version,
+                    known_versions,
+                    payload: payload.to_vec(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lookup(
+        decoders: &HashMap<(u32, u16), SchemaHandler>,
+        type_id: u32,
+        version: u16,
+    ) -> (Option<SchemaHandler>, Vec<u16>) {
+        if let Some(decoder) = decoders.get(&(type_id, version)) {
+            return (Some(decoder.clone()), Vec::new());
+        }
+
+        let mut known_versions: Vec<u16> = decoders
+            .keys()
+            .filter(|(id, _)| *id == type_id)
+            .map(|(_, version)| *version)
+            .collect();
+        known_versions.sort_unstable();
+
+        (None, known_versions)
+    }
+}
+
+impl RoutingTable {
+    pub fn to_text(&self) -> String {
+        self.routes
+            .iter()
+            .map(|route| format!("{} {} {}\n", route.type_id, route.version, route.handler))
+            .collect()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut table = RoutingTable::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let type_id = fields.next().and_then(|field| field.parse().ok());
+            let version = fields.next().and_then(|field| field.parse().ok());
+
+            match (type_id, version, fields.next(), fields.next()) {
+                (Some(type_id), Some(version), Some(handler), None) => table.routes.push(Route {
+                    type_id,
+                    version,
+                    handler: handler.to_string(),
+                }),
+                _ => {
+                    return Err(Error::RoutingTable(format!(
+                        "Line {} is not a route",
+                        index + 1
+                    )))
+                }
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+impl SchemaClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(SchemaState {
+            decoders: Mutex::new(HashMap::new()),
+            on_unknown_schema: Mutex::new(Arc::new(|_| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Err(e) = state_ref.handle(frame) {
+                if let Some(data) = data.upgrade() {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        // Fails only when the receive loop is already running, which is fine
+        // since it picks up the handler set above.
+        let _ = client.receive();
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // Replaces any decoder already registered for the same type and version.
+    pub fn register<F>(&self, type_id: u32, version: u16, decoder: F)
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        match self.state.decoders.lock() {
+            Ok(mut decoders) => decoders.insert((type_id, version), Arc::new(decoder)),
+            Err(e) => e.into_inner().insert((type_id, version), Arc::new(decoder)),
+        };
+    }
+
+    pub fn register_all<I>(&self, decoders: I)
+    where
+        I: IntoIterator<Item = (u32, u16, SchemaHandler)>,
+    {
+        let mut current = match self.state.decoders.lock() {
+            Ok(decoders) => decoders,
+            Err(e) => e.into_inner(),
+        };
+
+        for (type_id, version, decoder) in decoders {
+            current.insert((type_id, version), decoder);
+        }
+    }
+
+    // Registers every route of the table with the handler of that name.
+    // Nothing is registered if any name is missing from handlers.
+    pub fn register_table(
+        &self,
+        table: &RoutingTable,
+        handlers: &HashMap<String, SchemaHandler>,
+    ) -> Result<(), Error> {
+        let decoders = table
+            .routes
+            .iter()
+            .map(|route| match handlers.get(&route.handler) {
+                Some(handler) => Ok((route.type_id, route.version, handler.clone())),
+                None => Err(Error::RoutingTable(format!(
+                    "No handler named {}",
+                    route.handler
+                ))),
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        self.register_all(decoders);
+        Ok(())
+    }
+
+    pub fn unregister(&self, type_id: u32, version: u16) -> bool {
+        match self.state.decoders.lock() {
+            Ok(mut decoders) => decoders.remove(&(type_id, version)).is_some(),
+            Err(e) => e.into_inner().remove(&(type_id, version)).is_some(),
+        }
+    }
+
+    pub fn send<T>(&self, type_id: u32, version: u16, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let payload = payload.as_ref();
+
+        let mut frame = Vec::with_capacity(SCHEMA_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&type_id.to_le_bytes());
+        frame.extend_from_slice(&version.to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        self.client.send(frame)
+    }
+
+    pub fn set_on_unknown_schema<F>(&mut self, callback: F)
+    where
+        F: Fn(&UnknownSchema) + Send + Sync + 'static,
+    {
+        match self.state.on_unknown_schema.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
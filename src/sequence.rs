@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::queue::Lane;
+use crate::{ClientState, Error, SendTicket, TcpClient, TcpClientData};
+
+// Every frame starts with a kind byte and a u64 LE number. Data frames carry
+// their sequence number followed by the payload; resume frames carry the
+// last sequence number received, so a buffering peer can replay what came
+// after it.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const RESUME: u8 = 1;
+const HEADER_SIZE: usize = 9;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceCounters {
+    // The number the next message sent gets.
+    pub next_sequence: u64,
+    // Zero if nothing arrived yet.
+    pub last_received: u64,
+}
+
+type OnMessageReceivedCallback = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+
+struct SequenceState {
+    next_sequence: AtomicU64,
+    // Zero until the first message arrives; sequence numbers start at one.
+    last_received: AtomicU64,
+    on_message_received: Mutex<OnMessageReceivedCallback>,
+}
+
+pub struct SequencedClient {
+    client: TcpClient,
+    state: Arc<SequenceState>,
+}
+
+impl SequenceState {
+    fn handle(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() < HEADER_SIZE {
+            return Err(Error::Protocol(
+                "Frame is too short to carry a sequence header".to_string(),
+            ));
+        }
+
+        let sequence = u64::from_le_bytes(frame[1..HEADER_SIZE].try_into().unwrap());
+
+        match frame[0] {
+            DATA => {
+                // Replays may overlap with what already arrived.
+                if sequence <= self.last_received.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+
+                self.last_received.store(sequence, Ordering::SeqCst);
+
+                let on_message_received = match self.on_message_received.lock() {
+                    Ok(callback) => callback.clone(),
+                    Err(e) => e.into_inner().clone(),
+                };
+
+                on_message_received(sequence, &frame[HEADER_SIZE..]);
+                Ok(())
+            }
+            // This side keeps no replay buffer, so there is nothing to resend.
+            RESUME => Ok(()),
+            kind => Err(Error::Protocol(format!(
+                "Unknown sequenced frame kind {kind}"
+            ))),
+        }
+    }
+
+    fn resume(&self, data: &TcpClientData) -> Result<(), Error> {
+        let last_received = self.last_received.load(Ordering::SeqCst);
+
+        data.send_on_reconnect(&frame(RESUME, last_received, &[]), Lane::Control)
+            .map(|_| ())
+    }
+}
+
+impl SequencedClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let state = Arc::new(SequenceState {
+            next_sequence: AtomicU64::new(1),
+            last_received: AtomicU64::new(0),
+            on_message_received: Mutex::new(Arc::new(|_, _| {})),
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_message_received(move |frame| {
+            if let Err(e) = state_ref.handle(frame) {
+                if let Some(data) = data.upgrade() {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        let data: Weak<TcpClientData> = client.downgrade();
+        let state_ref = state.clone();
+
+        client.set_on_reconnected(move || {
+            if let Some(data) = data.upgrade() {
+                if let Err(e) = state_ref.resume(&data) {
+                    data.report_error(&e);
+                }
+            }
+        });
+
+        Self { client, state }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    // For applications that track resumption themselves.
+    pub fn last_received_seq(&self) -> Option<u64> {
+        match self.state.last_received.load(Ordering::SeqCst) {
+            0 => None,
+            sequence => Some(sequence),
+        }
+    }
+
+    pub fn export_state(&self) -> ClientState {
+        ClientState {
+            sequence: Some(SequenceCounters {
+                next_sequence: self.state.next_sequence.load(Ordering::SeqCst),
+                last_received: self.state.last_received.load(Ordering::SeqCst),
+            }),
+            ..self.client.export_state()
+        }
+    }
+
+    // Picks up the counters and asks the peer to replay what came after the
+    // last message received, as after a reconnect, before sending the
+    // pending messages.
+    pub fn import_state(&self, state: &ClientState) -> Result<Vec<SendTicket>, Error> {
+        if let Some(counters) = state.sequence {
+            self.state
+                .next_sequence
+                .store(counters.next_sequence, Ordering::SeqCst);
+            self.state
+                .last_received
+                .store(counters.last_received, Ordering::SeqCst);
+
+            self.client
+                .send_control(frame(RESUME, counters.last_received, &[]))?;
+        }
+
+        self.client.import_state(state)
+    }
+
+    pub fn send<T>(&self, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        let sequence = self.state.next_sequence.fetch_add(1, Ordering::SeqCst);
+
+        self.client.send(frame(DATA, sequence, payload.as_ref()))
+    }
+
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        match self.state.on_message_received.lock() {
+            Ok(mut current) => *current = Arc::new(callback),
+            Err(e) => *e.into_inner() = Arc::new(callback),
+        }
+    }
+}
+
+fn frame(kind: u8, sequence: u64, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&sequence.to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{client_over, instant_reconnects, read_frame, write_frame, TIMEOUT};
+    use crate::{ErrorAction, ErrorPolicy, MemoryTransport, Transport};
+
+    #[test]
+    fn reconnect_asks_for_a_replay_after_the_last_received() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let (third, third_server) = MemoryTransport::pair();
+
+        // The resume fails on the second connection and is resent on the
+        // third.
+        second_server.shutdown(Shutdown::Both).unwrap();
+
+        let mut client = client_over(vec![first, second, third]);
+        client.set_reconnect_policy(instant_reconnects());
+        client.set_error_policy(ErrorPolicy {
+            peer_closed: ErrorAction::Reconnect,
+            ..ErrorPolicy::default()
+        });
+
+        let mut sequenced = SequencedClient::new(client);
+        let (sender, received) = channel();
+        let sender = Mutex::new(sender);
+        sequenced.set_on_message_received(move |sequence, _| {
+            let _ = sender.lock().unwrap().send(sequence);
+        });
+        sequenced.client().receive().unwrap();
+
+        write_frame(&first_server, &frame(DATA, 1, b"a"));
+        write_frame(&first_server, &frame(DATA, 2, b"b"));
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), 1);
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), 2);
+
+        first_server.shutdown(Shutdown::Both).unwrap();
+
+        assert_eq!(read_frame(&third_server).unwrap(), frame(RESUME, 2, &[]));
+        assert_eq!(sequenced.last_received_seq(), Some(2));
+    }
+}
@@ -0,0 +1,74 @@
+const BUCKET_COUNT: usize = usize::BITS as usize + 1;
+
+#[derive(Clone, Debug)]
+pub struct FrameSizeHistogram {
+    buckets: [u64; BUCKET_COUNT],
+    count: u64,
+    total_bytes: u64,
+    max: usize,
+}
+
+impl FrameSizeHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: [0; BUCKET_COUNT],
+            count: 0,
+            total_bytes: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, size: usize) {
+        let index = (usize::BITS - size.leading_zeros()) as usize;
+
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.total_bytes += size as u64;
+        self.max = self.max.max(size);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total_bytes as f64 / self.count as f64
+        }
+    }
+
+    // Returns (upper bound, count) for every non-empty bucket, where a bucket
+    // holds the frames whose size is at most its upper bound and greater than
+    // the previous bucket's.
+    pub fn buckets(&self) -> Vec<(usize, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| {
+                let upper_bound = match index {
+                    0 => 0,
+                    _ => usize::MAX >> (BUCKET_COUNT - 1 - index),
+                };
+
+                (upper_bound, *count)
+            })
+            .collect()
+    }
+}
+
+impl Default for FrameSizeHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
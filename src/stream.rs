@@ -0,0 +1,79 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryIter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::queue::Lane;
+use crate::{Error, SendTicket, TcpClientData};
+
+pub struct Messages {
+    receiver: Receiver<Vec<u8>>,
+}
+
+// Waits up to the timeout for each message, yielding the timeout as an
+// error and carrying on, so a batch consumer can stop when things go quiet.
+// Ends with the stream rather than yielding Disconnected.
+pub struct TimeoutIter<'a> {
+    messages: &'a Messages,
+    timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct MessageSink {
+    data: Arc<TcpClientData>,
+}
+
+impl Messages {
+    pub(crate) fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self { receiver }
+    }
+
+    // Disconnected means the stream has ended: the connection closed for
+    // good or the client was dropped.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    // The messages that have already arrived, without waiting.
+    pub fn try_iter(&self) -> TryIter<'_, Vec<u8>> {
+        self.receiver.try_iter()
+    }
+
+    pub fn timeout_iter(&self, timeout: Duration) -> TimeoutIter<'_> {
+        TimeoutIter {
+            messages: self,
+            timeout,
+        }
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Iterator for TimeoutIter<'_> {
+    type Item = Result<Vec<u8>, RecvTimeoutError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.messages.recv_timeout(self.timeout) {
+            Err(RecvTimeoutError::Disconnected) => None,
+            result => Some(result),
+        }
+    }
+}
+
+impl MessageSink {
+    pub(crate) fn new(data: Arc<TcpClientData>) -> Self {
+        Self { data }
+    }
+
+    pub fn send<T>(&self, data: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.data.send(data.as_ref(), Lane::Data)
+    }
+}
@@ -0,0 +1,170 @@
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::ffi::c_ulong;
+use std::io;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use std::mem::size_of;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+const IPPROTO_TCP: i32 = 6;
+const TCP_INFO: i32 = 11;
+
+// The socket-level constants and SIOCOUTQ are those of the generic Linux
+// ABI, which x86_64 and aarch64 use; architectures like MIPS, SPARC or
+// PowerPC number them differently, so there the send queue and buffer size
+// read as None.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SOL_SOCKET: i32 = 1;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SO_SNDBUF: i32 = 7;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SIOCOUTQ: c_ulong = 0x5411;
+
+// struct tcp_info starts with eight u8 fields, followed by u32 fields in
+// this order; only the ones exposed here are listed.
+const TCPI_FIXED_BYTES: usize = 8;
+const TCPI_RTO: usize = 0;
+const TCPI_SND_MSS: usize = 2;
+const TCPI_UNACKED: usize = 4;
+const TCPI_LOST: usize = 6;
+const TCPI_PMTU: usize = 13;
+const TCPI_RTT: usize = 15;
+const TCPI_RTTVAR: usize = 16;
+const TCPI_SND_SSTHRESH: usize = 17;
+const TCPI_SND_CWND: usize = 18;
+const TCPI_TOTAL_RETRANS: usize = 23;
+const TCPI_SIZE: usize = TCPI_FIXED_BYTES + (TCPI_TOTAL_RETRANS + 1) * 4;
+
+extern "C" {
+    fn getsockopt(fd: i32, level: i32, name: i32, value: *mut u8, length: *mut u32) -> i32;
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn ioctl(fd: i32, request: c_ulong, ...) -> i32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub state: u8,
+    pub rtt: Duration,
+    pub rtt_variance: Duration,
+    pub retransmission_timeout: Duration,
+    pub congestion_window: u32,
+    pub slow_start_threshold: u32,
+    pub send_mss: u32,
+    pub path_mtu: u32,
+    pub unacked: u32,
+    pub lost: u32,
+    // Current retransmissions count only this connection's outstanding
+    // segments; total_retransmits covers its whole lifetime.
+    pub retransmits: u8,
+    pub total_retransmits: u32,
+    // None where the socket-level constants aren't known, see above.
+    pub send_queue_bytes: Option<u32>,
+    pub send_buffer_size: Option<u32>,
+}
+
+pub(crate) fn tcp_info(socket: &TcpStream) -> io::Result<TcpInfo> {
+    let fd = socket.as_raw_fd();
+    let mut info = [0u8; TCPI_SIZE];
+    let mut length = TCPI_SIZE as u32;
+
+    if unsafe { getsockopt(fd, IPPROTO_TCP, TCP_INFO, info.as_mut_ptr(), &mut length) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Older kernels fill in less; missing fields read as zero.
+    info[length as usize..].fill(0);
+
+    let field = |index: usize| {
+        let offset = TCPI_FIXED_BYTES + index * 4;
+        u32::from_ne_bytes(info[offset..offset + 4].try_into().unwrap())
+    };
+
+    let (send_queue_bytes, send_buffer_size) = send_queue(fd)?;
+
+    Ok(TcpInfo {
+        state: info[0],
+        rtt: Duration::from_micros(field(TCPI_RTT) as u64),
+        rtt_variance: Duration::from_micros(field(TCPI_RTTVAR) as u64),
+        retransmission_timeout: Duration::from_micros(field(TCPI_RTO) as u64),
+        congestion_window: field(TCPI_SND_CWND),
+        slow_start_threshold: field(TCPI_SND_SSTHRESH),
+        send_mss: field(TCPI_SND_MSS),
+        path_mtu: field(TCPI_PMTU),
+        unacked: field(TCPI_UNACKED),
+        lost: field(TCPI_LOST),
+        retransmits: info[2],
+        total_retransmits: field(TCPI_TOTAL_RETRANS),
+        send_queue_bytes,
+        send_buffer_size,
+    })
+}
+
+// Bytes written but not yet acknowledged, and the send buffer's size.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn send_queue(fd: i32) -> io::Result<(Option<u32>, Option<u32>)> {
+    let mut send_buffer_size = [0u8; 4];
+    let mut length = size_of::<u32>() as u32;
+
+    if unsafe {
+        getsockopt(
+            fd,
+            SOL_SOCKET,
+            SO_SNDBUF,
+            send_buffer_size.as_mut_ptr(),
+            &mut length,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut send_queue_bytes: i32 = 0;
+
+    if unsafe { ioctl(fd, SIOCOUTQ, &mut send_queue_bytes as *mut i32) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((
+        Some(send_queue_bytes.max(0) as u32),
+        Some(u32::from_ne_bytes(send_buffer_size)),
+    ))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn send_queue(_fd: i32) -> io::Result<(Option<u32>, Option<u32>)> {
+    Ok((None, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    // TCP_ESTABLISHED in the kernel's tcp_states.h.
+    const ESTABLISHED: u8 = 1;
+
+    #[test]
+    fn reads_statistics_of_a_connected_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_accepted, _) = listener.accept().unwrap();
+        socket.write_all(b"hello").unwrap();
+
+        let info = tcp_info(&socket).unwrap();
+
+        assert_eq!(info.state, ESTABLISHED);
+        assert!(info.send_mss > 0);
+        assert!(info.congestion_window > 0);
+
+        if cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            assert!(info.send_buffer_size.unwrap() > 0);
+            assert!(info.send_queue_bytes.is_some());
+        } else {
+            assert_eq!(info.send_buffer_size, None);
+            assert_eq!(info.send_queue_bytes, None);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Helpers shared by the unit tests, which run clients over in-memory
+// transports instead of sockets.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::LENGTH_HEADER_SIZE;
+use crate::{MemoryTransport, ReconnectPolicy, TcpClient, Transport};
+
+// Long enough for a loaded CI machine, short enough that a deadlock fails
+// the test instead of hanging the run.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+// A client that connects to the given transports in order, for tests that
+// need to prepare a connection before the client uses it.
+pub(crate) fn client_over<T>(transports: Vec<T>) -> TcpClient
+where
+    T: Transport + 'static,
+{
+    let transports = Mutex::new(VecDeque::from(transports));
+
+    TcpClient::from_transport("memory", move || {
+        transports
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+    })
+    .unwrap()
+}
+
+pub(crate) fn instant_reconnects() -> ReconnectPolicy {
+    ReconnectPolicy {
+        max_attempts: 3,
+        initial_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+        multiplier: 1,
+    }
+}
+
+// Wraps a transport to misbehave like a real socket under stress: every
+// write takes at most max_write bytes, and scripted faults are returned by
+// the next reads and writes, one per call, before they pass through again.
+// A None in the write script lets that write through.
+pub(crate) struct FlakyTransport<T> {
+    inner: T,
+    max_write: usize,
+    write_faults: Mutex<VecDeque<Option<io::ErrorKind>>>,
+    read_faults: Mutex<VecDeque<io::ErrorKind>>,
+}
+
+impl<T: Transport> FlakyTransport<T> {
+    pub(crate) fn short_writes(inner: T, max_write: usize) -> Self {
+        Self {
+            inner,
+            max_write,
+            write_faults: Mutex::new(VecDeque::new()),
+            read_faults: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn with_write_faults<I>(self, faults: I) -> Self
+    where
+        I: IntoIterator<Item = Option<io::ErrorKind>>,
+    {
+        self.write_faults.lock().unwrap().extend(faults);
+        self
+    }
+
+    pub(crate) fn with_read_faults<I>(self, faults: I) -> Self
+    where
+        I: IntoIterator<Item = io::ErrorKind>,
+    {
+        self.read_faults.lock().unwrap().extend(faults);
+        self
+    }
+}
+
+impl<T: Transport> Transport for FlakyTransport<T> {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        if let Some(kind) = self.read_faults.lock().unwrap().pop_front() {
+            return Err(kind.into());
+        }
+
+        self.inner.read(buffer)
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        if let Some(Some(kind)) = self.write_faults.lock().unwrap().pop_front() {
+            return Err(kind.into());
+        }
+
+        self.inner.write(&data[..data.len().min(self.max_write)])
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+}
+
+pub(crate) fn write_frame(transport: &MemoryTransport, payload: &[u8]) {
+    Transport::write(transport, &(payload.len() as u64).to_le_bytes()).unwrap();
+    Transport::write(transport, payload).unwrap();
+}
+
+// Fails the test if the frame doesn't arrive in time; None once the client
+// end has shut down.
+pub(crate) fn read_frame(transport: &MemoryTransport) -> Option<Vec<u8>> {
+    let mut header = [0; LENGTH_HEADER_SIZE];
+
+    if !read_exact(transport, &mut header) {
+        return None;
+    }
+
+    let mut payload = vec![0; u64::from_le_bytes(header) as usize];
+    assert!(read_exact(transport, &mut payload), "frame was cut short");
+
+    Some(payload)
+}
+
+// Returns false if the client end shut down first.
+pub(crate) fn read_exact(transport: &MemoryTransport, buffer: &mut [u8]) -> bool {
+    transport.set_nonblocking(true).unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match Transport::read(transport, &mut buffer[filled..]) {
+            Ok(0) => return false,
+            Ok(size) => filled += size,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                assert!(Instant::now() < deadline, "timed out waiting for a frame");
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => panic!("server read failed: {e}"),
+        }
+    }
+
+    true
+}
+
+// Runs f on its own thread and fails the test if it doesn't finish in time,
+// so a deadlock shows up as a failure.
+pub(crate) fn within<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = channel();
+
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+
+    receiver
+        .recv_timeout(TIMEOUT)
+        .expect("did not finish in time, probably deadlocked")
+}
+
+// Polls until condition holds, failing the test once the timeout passes.
+pub(crate) fn wait_until<F>(condition: F)
+where
+    F: Fn() -> bool,
+{
+    let deadline = Instant::now() + TIMEOUT;
+
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// A fresh directory under the system's temporary directory, removed again
+// when dropped. Names are unique per process and test.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "tcp-client-{}-{}-{name}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Self { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,82 @@
+use std::io;
+
+// Scheduling hints for the receive and writer threads. Applied when each
+// thread starts; failures are reported through the error callback.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadHints {
+    pub core: Option<usize>,
+    // Lower values are scheduled sooner. Going below zero usually needs
+    // elevated privileges.
+    pub nice: Option<i32>,
+}
+
+impl ThreadHints {
+    pub(crate) fn apply(&self) -> io::Result<()> {
+        if let Some(core) = self.core {
+            platform::set_affinity(core)?;
+        }
+
+        if let Some(nice) = self.nice {
+            platform::set_nice(nice)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+
+    // Matches the size of glibc's cpu_set_t.
+    const CPU_SET_WORDS: usize = 16;
+    const PRIO_PROCESS: i32 = 0;
+
+    extern "C" {
+        fn sched_setaffinity(pid: i32, size: usize, mask: *const u64) -> i32;
+        fn setpriority(which: i32, who: u32, priority: i32) -> i32;
+    }
+
+    pub(super) fn set_affinity(core: usize) -> io::Result<()> {
+        if core >= CPU_SET_WORDS * 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "core index out of range",
+            ));
+        }
+
+        let mut mask = [0u64; CPU_SET_WORDS];
+        mask[core / 64] |= 1 << (core % 64);
+
+        // pid 0 is the calling thread.
+        let result = unsafe { sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn set_nice(nice: i32) -> io::Result<()> {
+        // On Linux the nice value is per thread, and who 0 is the caller.
+        let result = unsafe { setpriority(PRIO_PROCESS, 0, nice) };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::io;
+
+    pub(super) fn set_affinity(_core: usize) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub(super) fn set_nice(_nice: i32) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
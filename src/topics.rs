@@ -0,0 +1,264 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::mqtt::QoS;
+use crate::{ClientState, Error, SendTicket, TcpClient, TcpClientData, TopicSubscription};
+
+// Topic frames start with the topic name's length (u16 LE) and the name
+// itself, followed by the payload.
+const TOPIC_HEADER_SIZE: usize = 2;
+
+type TopicHandler = Arc<dyn Fn(&str, &[u8]) + Send + Sync>;
+
+pub struct TopicClient {
+    client: TcpClient,
+    subscriptions: Arc<Mutex<Vec<(String, TopicHandler)>>>,
+}
+
+impl TopicClient {
+    pub fn new(mut client: TcpClient) -> Self {
+        let subscriptions: Arc<Mutex<Vec<(String, TopicHandler)>>> = Arc::default();
+        let subscriptions_ref = subscriptions.clone();
+        let data: Weak<TcpClientData> = client.downgrade();
+
+        client.set_on_message_received(move |message| {
+            let (topic, payload) = match decode(message) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    if let Some(data) = data.upgrade() {
+                        data.report_error(&e);
+                    }
+                    return;
+                }
+            };
+
+            let handlers: Vec<TopicHandler> = match subscriptions_ref.lock() {
+                Ok(subscriptions) => subscriptions
+                    .iter()
+                    .filter(|(pattern, _)| matches(pattern, topic))
+                    .map(|(_, handler)| handler.clone())
+                    .collect(),
+                Err(_) => return,
+            };
+
+            for handler in handlers {
+                handler(topic, payload);
+            }
+        });
+
+        // Fails only when the receive loop is already running, which is fine
+        // since it picks up the handler set above.
+        let _ = client.receive();
+
+        Self {
+            client,
+            subscriptions,
+        }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn publish<T>(&self, topic: &str, payload: T) -> Result<SendTicket, Error>
+    where
+        T: AsRef<[u8]>,
+    {
+        if topic.is_empty() || topic.split('/').any(|level| level == "+" || level == "#") {
+            return Err(Error::InvalidTopic(topic.to_string()));
+        }
+
+        let length = match u16::try_from(topic.len()) {
+            Ok(length) => length.to_le_bytes(),
+            Err(_) => return Err(Error::InvalidTopic(topic.to_string())),
+        };
+
+        let mut frame = Vec::with_capacity(TOPIC_HEADER_SIZE + topic.len());
+        frame.extend_from_slice(&length);
+        frame.extend_from_slice(topic.as_bytes());
+        frame.extend_from_slice(payload.as_ref());
+
+        self.client.send(frame)
+    }
+
+    // Patterns are '/'-separated levels where "+" matches exactly one level
+    // and a trailing "#" matches any number of remaining levels.
+    pub fn subscribe<F>(&mut self, pattern: &str, handler: F)
+    where
+        F: Fn(&str, &[u8]) + Send + Sync + 'static,
+    {
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.push((pattern.to_string(), Arc::new(handler)));
+        }
+    }
+
+    pub fn unsubscribe(&mut self, pattern: &str) {
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            subscriptions.retain(|(subscribed, _)| subscribed != pattern);
+        }
+    }
+
+    // The transport's state with the subscribed patterns added.
+    pub fn export_state(&self) -> ClientState {
+        let subscriptions = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .map(|(pattern, _)| TopicSubscription {
+                    filter: pattern.clone(),
+                    qos: QoS::AtMostOnce,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        ClientState {
+            subscriptions,
+            ..self.client.export_state()
+        }
+    }
+
+    // Subscribes to every exported pattern, all delivered to the one
+    // handler, then sends the pending messages and returns their tickets
+    // like TcpClient::import_state.
+    pub fn import_state<F>(
+        &mut self,
+        state: &ClientState,
+        handler: F,
+    ) -> Result<Vec<SendTicket>, Error>
+    where
+        F: Fn(&str, &[u8]) + Send + Sync + 'static,
+    {
+        let handler: TopicHandler = Arc::new(handler);
+
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            for subscription in &state.subscriptions {
+                subscriptions.push((subscription.filter.clone(), handler.clone()));
+            }
+        }
+
+        self.client.import_state(state)
+    }
+}
+
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_levels = pattern.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return pattern_levels.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn decode(message: &[u8]) -> Result<(&str, &[u8]), Error> {
+    if message.len() < TOPIC_HEADER_SIZE {
+        return Err(Error::Protocol(
+            "Frame is too short to carry a topic header".to_string(),
+        ));
+    }
+
+    let length = u16::from_le_bytes([message[0], message[1]]) as usize;
+    let rest = &message[TOPIC_HEADER_SIZE..];
+
+    if rest.len() < length {
+        return Err(Error::Protocol(format!(
+            "Topic name of {length} bytes does not fit in a {} byte frame",
+            message.len()
+        )));
+    }
+
+    match std::str::from_utf8(&rest[..length]) {
+        Ok(topic) => Ok((topic, &rest[length..])),
+        Err(e) => Err(Error::InvalidUtf8(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::testing::{client_over, write_frame, TIMEOUT};
+    use crate::MemoryTransport;
+
+    fn topic_frame(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (topic.len() as u16).to_le_bytes().to_vec();
+        frame.extend_from_slice(topic.as_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn literal_levels_match_exactly() {
+        assert!(matches("sensors/kitchen", "sensors/kitchen"));
+        assert!(!matches("sensors/kitchen", "sensors/kitchen/temperature"));
+        assert!(!matches("sensors/kitchen/temperature", "sensors/kitchen"));
+        assert!(!matches("sensors/kitchen", "sensors/hall"));
+    }
+
+    #[test]
+    fn a_trailing_hash_also_matches_its_parent_level() {
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/b"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(!matches("a/#", "b"));
+        assert!(matches("#", "anything/at/all"));
+    }
+
+    #[test]
+    fn a_hash_that_is_not_last_matches_nothing() {
+        assert!(!matches("a/#/c", "a/b/c"));
+        assert!(!matches("a/#/c", "a/c"));
+        assert!(!matches("#/a", "a"));
+    }
+
+    #[test]
+    fn a_plus_matches_exactly_one_level_even_an_empty_one() {
+        assert!(matches("a/+", "a/b"));
+        assert!(matches("a/+", "a/"));
+        assert!(matches("+/b", "/b"));
+        assert!(matches("+", ""));
+        assert!(!matches("a/+", "a"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(matches("a/+/+", "a//"));
+    }
+
+    #[test]
+    fn imported_patterns_route_to_the_new_handler() {
+        let (transport, _server) = MemoryTransport::pair();
+        let mut old = TopicClient::new(client_over(vec![transport]));
+        old.subscribe("sensors/+", |_, _| {});
+        old.subscribe("alerts/#", |_, _| {});
+        let state = old.export_state();
+
+        assert_eq!(
+            state
+                .subscriptions
+                .iter()
+                .map(|subscription| subscription.filter.as_str())
+                .collect::<Vec<_>>(),
+            ["sensors/+", "alerts/#"]
+        );
+
+        let (transport, server) = MemoryTransport::pair();
+        let mut new = TopicClient::new(client_over(vec![transport]));
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        new.import_state(
+            &ClientState::decode(&state.encode()).unwrap(),
+            move |topic, _| {
+                let _ = messages.lock().unwrap().send(topic.to_string());
+            },
+        )
+        .unwrap();
+
+        write_frame(&server, &topic_frame("sensors/kitchen", b"21"));
+        write_frame(&server, &topic_frame("alerts/fire/now", b"!"));
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), "sensors/kitchen");
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), "alerts/fire/now");
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// The byte stream underneath a client, which TcpClient::from_transport takes
+// from a connector so anything that moves bytes, like a serial bridge or an
+// SSH channel, gets framing, queueing and reconnects for free. The client
+// switches transports to nonblocking mode and expects WouldBlock from read
+// and write when they can't make progress. TcpStream and, on unix,
+// UnixStream implement it here; TLS is left to the caller, who wraps
+// whichever TLS library they use in a Transport, since this crate has no
+// dependencies to build one on.
+pub trait Transport: Send + Sync {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, data: &[u8]) -> io::Result<usize>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    // Called after every whole message, for transports that buffer writes.
+    // WouldBlock is retried like a write.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // How long the client waits before reading again after WouldBlock, and
+    // before writing again. Transports that know how soon they'll be ready
+    // can shorten the wait; None keeps the client's default.
+    fn read_retry_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    fn write_retry_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    // Socket options are only available on TCP transports.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buffer)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        platform::write_nosignal(self, data)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, data)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buffer)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        platform::write_nosignal(self, data)
+    }
+
+    // Elsewhere SO_NOSIGPIPE is only set on TCP sockets, so a host that
+    // doesn't ignore SIGPIPE itself shouldn't write to a closed Unix
+    // socket.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, data)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, how)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// Rust binaries ignore SIGPIPE, but a host loading this crate through the
+// C bindings may not, and a write to a closed socket would then kill the
+// process instead of failing with EPIPE. Linux and Android pass
+// MSG_NOSIGNAL on every send. Apple platforms lack that flag, so they, and
+// FreeBSD, NetBSD and DragonFly, which share their socket option, get
+// SO_NOSIGPIPE set on each socket the client installs. Windows has no
+// SIGPIPE. Elsewhere, e.g. on OpenBSD or illumos, the host has to ignore
+// the signal itself.
+pub(crate) fn suppress_sigpipe(socket: &dyn Transport) {
+    if let Some(socket) = socket.as_tcp() {
+        // Best effort: a socket that refuses the option still works, and
+        // the worst case is the signal the host would have had anyway.
+        let _ = platform::suppress_sigpipe(socket);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod platform {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    const MSG_NOSIGNAL: i32 = 0x4000;
+
+    extern "C" {
+        fn send(fd: i32, data: *const u8, length: usize, flags: i32) -> isize;
+    }
+
+    pub(super) fn write_nosignal(socket: &impl AsRawFd, data: &[u8]) -> io::Result<usize> {
+        let sent = unsafe { send(socket.as_raw_fd(), data.as_ptr(), data.len(), MSG_NOSIGNAL) };
+
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    pub(super) fn suppress_sigpipe(_socket: &TcpStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+mod platform {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    const SOL_SOCKET: i32 = 0xffff;
+
+    #[cfg(target_vendor = "apple")]
+    const SO_NOSIGPIPE: i32 = 0x1022;
+    #[cfg(not(target_vendor = "apple"))]
+    const SO_NOSIGPIPE: i32 = 0x0800;
+
+    extern "C" {
+        fn setsockopt(
+            fd: i32,
+            level: i32,
+            name: i32,
+            value: *const std::ffi::c_void,
+            length: u32,
+        ) -> i32;
+    }
+
+    pub(super) fn suppress_sigpipe(socket: &TcpStream) -> io::Result<()> {
+        let enabled: i32 = 1;
+        let result = unsafe {
+            setsockopt(
+                socket.as_raw_fd(),
+                SOL_SOCKET,
+                SO_NOSIGPIPE,
+                &enabled as *const i32 as *const std::ffi::c_void,
+                std::mem::size_of::<i32>() as u32,
+            )
+        };
+
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+)))]
+mod platform {
+    use std::io;
+    use std::net::TcpStream;
+
+    pub(super) fn suppress_sigpipe(_socket: &TcpStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    bytes: VecDeque<u8>,
+    // Set when the writing end shuts down; readers see end of stream once
+    // the buffered bytes are drained.
+    write_closed: bool,
+    read_closed: bool,
+}
+
+#[derive(Default)]
+struct PipeState {
+    pipe: Mutex<Pipe>,
+    readable: Condvar,
+}
+
+// One end of an in-memory duplex stream, for exercising a client without
+// real sockets. Writes never block; reads block unless nonblocking is set.
+pub struct MemoryTransport {
+    inbound: Arc<PipeState>,
+    outbound: Arc<PipeState>,
+    nonblocking: Mutex<bool>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let first = Arc::new(PipeState::default());
+        let second = Arc::new(PipeState::default());
+
+        (
+            MemoryTransport {
+                inbound: first.clone(),
+                outbound: second.clone(),
+                nonblocking: Mutex::new(false),
+            },
+            MemoryTransport {
+                inbound: second,
+                outbound: first,
+                nonblocking: Mutex::new(false),
+            },
+        )
+    }
+
+    fn is_nonblocking(&self) -> bool {
+        match self.nonblocking.lock() {
+            Ok(nonblocking) => *nonblocking,
+            Err(e) => *e.into_inner(),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = match self.inbound.pipe.lock() {
+            Ok(pipe) => pipe,
+            Err(e) => e.into_inner(),
+        };
+
+        loop {
+            if pipe.read_closed {
+                return Ok(0);
+            }
+
+            if !pipe.bytes.is_empty() || buffer.is_empty() {
+                let size = buffer.len().min(pipe.bytes.len());
+
+                for (target, byte) in buffer.iter_mut().zip(pipe.bytes.drain(..size)) {
+                    *target = byte;
+                }
+
+                return Ok(size);
+            }
+
+            if pipe.write_closed {
+                return Ok(0);
+            }
+
+            if self.is_nonblocking() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            pipe = match self.inbound.readable.wait(pipe) {
+                Ok(pipe) => pipe,
+                Err(e) => e.into_inner(),
+            };
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut pipe = match self.outbound.pipe.lock() {
+            Ok(pipe) => pipe,
+            Err(e) => e.into_inner(),
+        };
+
+        if pipe.write_closed || pipe.read_closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        pipe.bytes.extend(data);
+        self.outbound.readable.notify_all();
+
+        Ok(data.len())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            if let Ok(mut pipe) = self.outbound.pipe.lock() {
+                pipe.write_closed = true;
+            }
+
+            self.outbound.readable.notify_all();
+        }
+
+        if how != Shutdown::Write {
+            if let Ok(mut pipe) = self.inbound.pipe.lock() {
+                pipe.read_closed = true;
+                pipe.bytes.clear();
+            }
+
+            self.inbound.readable.notify_all();
+        }
+
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self.nonblocking.lock() {
+            Ok(mut current) => *current = nonblocking,
+            Err(e) => *e.into_inner() = nonblocking,
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for &MemoryTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Transport::read(*self, buffer)
+    }
+}
+
+impl Write for &MemoryTransport {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        Transport::write(*self, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{read_frame, TIMEOUT};
+    use crate::{DisconnectReason, TcpClient};
+
+    fn read_all(transport: &MemoryTransport) -> io::Result<Vec<u8>> {
+        let mut buffer = [0; 64];
+        let size = Transport::read(transport, &mut buffer)?;
+        Ok(buffer[..size].to_vec())
+    }
+
+    #[test]
+    fn bytes_written_on_one_end_are_read_from_the_other() {
+        let (first, second) = MemoryTransport::pair();
+
+        assert_eq!(Transport::write(&first, b"hello").unwrap(), 5);
+        assert_eq!(Transport::write(&second, b"world").unwrap(), 5);
+
+        assert_eq!(read_all(&second).unwrap(), b"hello");
+        assert_eq!(read_all(&first).unwrap(), b"world");
+    }
+
+    #[test]
+    fn a_read_takes_only_what_fits_and_leaves_the_rest() {
+        let (first, second) = MemoryTransport::pair();
+        Transport::write(&first, b"hello").unwrap();
+
+        let mut buffer = [0; 3];
+        assert_eq!(Transport::read(&second, &mut buffer).unwrap(), 3);
+        assert_eq!(&buffer, b"hel");
+        assert_eq!(read_all(&second).unwrap(), b"lo");
+    }
+
+    #[test]
+    fn an_empty_nonblocking_read_would_block() {
+        let (_first, second) = MemoryTransport::pair();
+        second.set_nonblocking(true).unwrap();
+
+        let error = read_all(&second).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn a_blocking_read_waits_for_the_next_write() {
+        let (first, second) = MemoryTransport::pair();
+        let reader = thread::spawn(move || read_all(&second).unwrap());
+
+        thread::sleep(Duration::from_millis(20));
+        Transport::write(&first, b"hello").unwrap();
+
+        assert_eq!(reader.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn a_half_close_drains_buffered_bytes_before_end_of_stream() {
+        let (first, second) = MemoryTransport::pair();
+        Transport::write(&first, b"hello").unwrap();
+        first.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!(read_all(&second).unwrap(), b"hello");
+        assert_eq!(read_all(&second).unwrap(), b"");
+
+        // The other direction stays open.
+        Transport::write(&second, b"reply").unwrap();
+        assert_eq!(read_all(&first).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn a_half_close_wakes_a_blocked_reader_with_end_of_stream() {
+        let (first, second) = MemoryTransport::pair();
+        let reader = thread::spawn(move || read_all(&second).unwrap());
+
+        thread::sleep(Duration::from_millis(20));
+        first.shutdown(Shutdown::Write).unwrap();
+
+        assert_eq!(reader.join().unwrap(), b"");
+    }
+
+    #[test]
+    fn writing_after_either_end_closed_the_direction_is_a_broken_pipe() {
+        let (first, second) = MemoryTransport::pair();
+
+        first.shutdown(Shutdown::Write).unwrap();
+        let error = Transport::write(&first, b"hello").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+
+        // Shutting down reads drops what was buffered and refuses more.
+        Transport::write(&second, b"unread").unwrap();
+        first.shutdown(Shutdown::Read).unwrap();
+        assert_eq!(read_all(&first).unwrap(), b"");
+        let error = Transport::write(&second, b"more").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn writing_to_a_socket_the_peer_closed_fails_instead_of_signalling() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        suppress_sigpipe(&socket);
+        drop(listener.accept().unwrap());
+
+        // The first write may still be accepted; the reset it draws fails
+        // a later one.
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        let error = loop {
+            match Transport::write(&socket, b"hello") {
+                Ok(_) => assert!(
+                    std::time::Instant::now() < deadline,
+                    "writes kept succeeding"
+                ),
+                Err(e) => break e,
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_client_sends_and_receives_over_a_unix_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let (transport, mut server) = UnixStream::pair().unwrap();
+        let mut client = crate::testing::client_over(vec![transport]);
+
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        client.set_on_message_received(move |message| {
+            let _ = messages.lock().unwrap().send(message.to_vec());
+        });
+        client.receive().unwrap();
+
+        server.write_all(&5u64.to_le_bytes()).unwrap();
+        server.write_all(b"hello").unwrap();
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"hello");
+
+        client.send("reply").unwrap();
+        server.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut header = [0; 8];
+        server.read_exact(&mut header).unwrap();
+        let mut payload = vec![0; u64::from_le_bytes(header) as usize];
+        server.read_exact(&mut payload).unwrap();
+        assert_eq!(payload, b"reply");
+    }
+
+    #[test]
+    fn a_client_frames_sends_and_receives_over_a_pair() {
+        let (transport, server) = MemoryTransport::pair();
+        let transport = Mutex::new(Some(transport));
+
+        let mut client = TcpClient::from_transport("memory", move || {
+            transport
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .unwrap();
+
+        let (messages, received) = channel();
+        let messages = Mutex::new(messages);
+        client.set_on_message_received(move |message| {
+            let _ = messages.lock().unwrap().send(message.to_vec());
+        });
+
+        let (closes, closed) = channel();
+        let closes = Mutex::new(closes);
+        client.set_on_connection_closed(move |_, mid_frame, reason| {
+            let _ = closes.lock().unwrap().send((mid_frame, reason));
+        });
+
+        client.receive().unwrap();
+
+        // Two frames in one write and a third split across writes arrive
+        // one message each, in order.
+        let mut bytes = Vec::new();
+        for payload in [&b"first"[..], b"second", b"third"] {
+            bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(payload);
+        }
+        let (head, tail) = bytes.split_at(bytes.len() - 3);
+        Transport::write(&server, head).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        Transport::write(&server, tail).unwrap();
+
+        for expected in [&b"first"[..], b"second", b"third"] {
+            assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), expected);
+        }
+
+        client.send("reply").unwrap();
+        assert_eq!(read_frame(&server).unwrap(), b"reply");
+
+        server.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(
+            closed.recv_timeout(TIMEOUT).unwrap(),
+            (false, DisconnectReason::PeerClosed)
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::marker::PhantomData;
+
+use crate::msgpack_rpc::Value;
+use crate::{Error, SendTicket, TcpClient};
+
+// Converts application messages to and from frame payloads. Implement it
+// with whatever serialization the application already uses.
+pub trait Codec: Sized {
+    fn encode(&self) -> Result<Vec<u8>, String>;
+    fn decode(bytes: &[u8]) -> Result<Self, String>;
+}
+
+pub struct TypedClient<T> {
+    client: TcpClient,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedClient<T>
+where
+    T: Codec + 'static,
+{
+    pub fn new(client: TcpClient) -> Self {
+        Self {
+            client,
+            message: PhantomData,
+        }
+    }
+
+    pub fn client(&self) -> &TcpClient {
+        &self.client
+    }
+
+    pub fn send(&self, message: &T) -> Result<SendTicket, Error> {
+        match message.encode() {
+            Ok(bytes) => self.client.send(bytes),
+            Err(e) => Err(Error::Protocol(e)),
+        }
+    }
+
+    // Payloads that fail to decode are reported through the error callback.
+    pub fn set_on_message_received<F>(&mut self, callback: F)
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let data = self.client.downgrade();
+
+        self.client
+            .set_on_message_received(move |bytes| match T::decode(bytes) {
+                Ok(message) => callback(message),
+                Err(e) => {
+                    if let Some(data) = data.upgrade() {
+                        data.report_error(&Error::Protocol(e));
+                    }
+                }
+            });
+    }
+}
+
+impl Codec for Vec<u8> {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        Ok(self.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        Ok(self.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl Codec for Value {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        Ok(Value::encode(self))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        Value::decode(bytes)
+    }
+}